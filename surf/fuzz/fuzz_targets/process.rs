@@ -0,0 +1,37 @@
+//! Feeds arbitrary datagrams to a server as if they'd come off the network, checking that it
+//! neither panics nor loses track of who it probes along the way.
+
+#![no_main]
+
+use std::net::SocketAddr;
+
+use libfuzzer_sys::fuzz_target;
+use rand::{rngs::SmallRng, SeedableRng};
+use surf::{Message, PeerId, Rumor, RumorKind, Server};
+
+fn addr(id: u64) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8000 + id as u16))
+}
+
+fuzz_target!(|datagrams: Vec<&[u8]>| {
+    // A fixed seed so crashes reproduce
+    let mut server = Server::builder(0.into(), addr(0))
+        .rng(SmallRng::seed_from_u64(0))
+        .build()
+        .unwrap();
+    // Some peers to ack, suspect and refute
+    for id in 1..=3 {
+        server.process_rumor(Rumor {
+            peer_id: PeerId::from(id),
+            incarnation: 1.into(),
+            kind: RumorKind::alive(addr(id)),
+        });
+    }
+    for datagram in datagrams {
+        if let Ok(msg) = Message::from_bytes(datagram) {
+            let _ = server.process(msg);
+            server.check_invariants();
+        }
+        server.outbox();
+    }
+});
@@ -0,0 +1,87 @@
+//! Keyed HMACs over [`Message`]s and the gossip sent alongside them, so only servers sharing
+//! a secret can change each other's view of the cluster.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::Message;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Compute the HMAC of everything in `msg` but its `mac`, then `gossip`
+fn digest(key: &[u8], msg: &mut Message, gossip: &[u8]) -> Option<HmacSha256> {
+    let theirs = core::mem::take(&mut msg.mac);
+    let bytes = msg.to_bytes();
+    msg.mac = theirs;
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&bytes.ok()?);
+    mac.update(gossip);
+    Some(mac)
+}
+
+/// Stamp `msg` with the HMAC of it and `gossip` under the first of `keys`, if there are any
+pub(crate) fn sign(keys: &[Vec<u8>], msg: &mut Message, gossip: &[u8]) {
+    let key = match keys.first() {
+        Some(key) => key,
+        None => return,
+    };
+    msg.mac = match digest(key, msg, gossip) {
+        Some(mac) => mac.finalize().into_bytes().to_vec(),
+        None => Vec::new(),
+    };
+}
+
+/// Whether `msg` and `gossip` were signed with any of `keys`, or trivially so when we have none. Accepting
+/// every key lets a new one be rolled out before anyone signs with it, and an old one
+/// retired once nobody does.
+pub(crate) fn verify(keys: &[Vec<u8>], msg: &mut Message, gossip: &[u8]) -> bool {
+    if keys.is_empty() {
+        return true;
+    }
+    keys.iter()
+        .any(|key| digest(key, msg, gossip).is_some_and(|mac| mac.verify_slice(&msg.mac).is_ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MsgKind, PROTOCOL_VERSION};
+
+    fn ping() -> Message {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        Message {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_label: 0,
+            dest_id: 1.into(),
+            dest_addr: addr,
+            src_id: 0.into(),
+            src_addr: addr,
+            seq_no: 7,
+            kind: MsgKind::Ping,
+            mac: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn signatures_cover_the_message() {
+        let keys = vec![b"old".to_vec(), b"new".to_vec()];
+        let mut msg = ping();
+        sign(&keys[1..], &mut msg, &[]);
+        assert!(verify(&keys, &mut msg, &[]));
+        assert!(!verify(&keys[..1], &mut msg, &[]));
+        msg.seq_no += 1;
+        assert!(!verify(&keys, &mut msg, &[]));
+        assert!(!verify(&keys, &mut ping(), &[]));
+        assert!(verify(&[], &mut ping(), &[]));
+    }
+
+    #[test]
+    fn signatures_cover_the_gossip() {
+        let keys = vec![b"key".to_vec()];
+        let mut msg = ping();
+        sign(&keys, &mut msg, &[1, 0, 5]);
+        assert!(verify(&keys, &mut msg, &[1, 0, 5]));
+        assert!(!verify(&keys, &mut msg, &[1, 0, 6]));
+        assert!(!verify(&keys, &mut msg, &[]));
+    }
+}
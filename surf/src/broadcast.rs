@@ -1,45 +1,84 @@
-use std::cmp::{Ordering, Reverse};
-use std::collections::{BinaryHeap, HashMap};
+use alloc::collections::BinaryHeap;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::mem::take;
 
+use crate::collections::HashMap;
 use crate::rumor::*;
-use crate::PeerId;
+use crate::{Incarnation, Instant, PeerId};
+
+/// How urgently a broadcast needs to spread
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Priority {
+    /// Application data, which never crowds out membership news
+    User,
+    /// Alive refreshes
+    Normal,
+    /// Suspicions, failures, and departures
+    Membership,
+}
+
+impl From<&RumorKind> for Priority {
+    fn from(kind: &RumorKind) -> Self {
+        match kind {
+            RumorKind::Suspect { .. } | RumorKind::Failed | RumorKind::Depart => {
+                Priority::Membership
+            }
+            RumorKind::Alive { .. } => Priority::Normal,
+            RumorKind::User { .. } => Priority::User,
+        }
+    }
+}
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct Broadcast {
+    /// The order the rumor was queued in, unique within its store. Breaks every other tie.
     pub id: usize,
-    pub peer_id: PeerId,
+    pub subject: Subject,
+    pub priority: Priority,
     pub sends: usize,
     pub message: Vec<u8>,
+    /// When the rumor was first queued
+    pub created_at: Instant,
+    /// When a user broadcast with a TTL goes stale and is dropped, however few times it's
+    /// been sent
+    pub expires_at: Option<Instant>,
 }
 
 impl PartialOrd for Broadcast {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.sends.cmp(&other.sends) {
-            Ordering::Equal => {}
-            ord => return Some(ord),
-        }
-        match self.message.len().cmp(&other.message.len()) {
-            Ordering::Equal => {}
-            ord => return Some(ord),
-        }
-        Some(Reverse(self.id).cmp(&Reverse(other.id)))
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
+/// Greater broadcasts are sent first: highest priority, then fewest sends, then largest
+/// message, then oldest.
+///
+/// Ids are unique within a store, so two broadcasts only compare equal if they're the same
+/// one, and the order never depends on how the heap happens to be laid out. The same rumors
+/// queued in the same order always come out in the same order, which keeps simulations and
+/// seeded tests reproducible.
 impl Ord for Broadcast {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // We reverse this here because we want a min heap
-        other.partial_cmp(self).unwrap()
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        // sends and id are reversed because the queue is a max heap
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sends.cmp(&self.sends))
+            .then_with(|| self.message.len().cmp(&other.message.len()))
+            .then_with(|| other.id.cmp(&self.id))
     }
 }
 
+#[derive(Default)]
 pub struct BroadcastStore {
     queue: BinaryHeap<Broadcast>,
     // Current messages we're broadcasting. Used to dedupe
     // on replay
     // Rumors are small so I don't care that we're storing them twice
-    broadcasting: HashMap<PeerId, (usize, Rumor)>,
+    broadcasting: HashMap<Subject, (usize, Rumor)>,
     next_broadcast: usize,
+    /// Most broadcasts to queue at once, if bounded
+    capacity: Option<usize>,
 }
 
 impl BroadcastStore {
@@ -48,7 +87,68 @@ impl BroadcastStore {
             queue: BinaryHeap::new(),
             broadcasting: HashMap::new(),
             next_broadcast: 0,
+            capacity: None,
+        }
+    }
+
+    /// Bound the queue, evicting whichever broadcasts would be sent last once it's full
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = Some(capacity);
+        self.evict();
+    }
+
+    /// Drop the lowest-priority, most-sent, oldest broadcasts until we're within capacity.
+    /// Whether any were dropped.
+    fn evict(&mut self) -> bool {
+        let capacity = match self.capacity {
+            Some(capacity) if self.queue.len() > capacity => capacity,
+            _ => return false,
+        };
+        let mut queue = take(&mut self.queue).into_vec();
+        queue.sort_by_key(|bc| (bc.priority, Reverse(bc.sends), bc.id));
+        let evicted: Vec<_> = queue.drain(..queue.len() - capacity).collect();
+        self.queue = queue.into();
+        for bc in evicted {
+            self.forget(&bc);
         }
+        true
+    }
+
+    /// Drop queued broadcasts that `stale` picks out, forgetting their subjects
+    fn drop_where(&mut self, stale: impl Fn(&Broadcast) -> bool) {
+        let (dropped, kept): (Vec<_>, Vec<_>) = take(&mut self.queue).into_iter().partition(stale);
+        self.queue = kept.into();
+        for bc in dropped {
+            self.forget(&bc);
+        }
+    }
+
+    /// Stop tracking the subject of `broadcast` once it's left the queue, unless newer news
+    /// about it has been queued since
+    fn forget(&mut self, broadcast: &Broadcast) {
+        if self
+            .broadcasting
+            .get(&broadcast.subject)
+            .is_some_and(|(id, _)| *id == broadcast.id)
+        {
+            self.broadcasting.remove(&broadcast.subject);
+        }
+    }
+
+    /// Drop every broadcast and forget what we've heard, keeping the capacity
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.broadcasting.clear();
+    }
+
+    /// Drop broadcasts first queued before `cutoff`, however few times they've been sent
+    pub fn retire(&mut self, cutoff: Instant) {
+        self.drop_where(|bc| bc.created_at < cutoff);
+    }
+
+    /// Drop broadcasts whose TTL has run out by `now`
+    pub fn expire(&mut self, now: Instant) {
+        self.drop_where(|bc| bc.expires_at.is_some_and(|expires_at| expires_at <= now));
     }
 
     pub fn replay(&mut self, mut broadcast: Broadcast) {
@@ -56,41 +156,85 @@ impl BroadcastStore {
         self.queue.push(broadcast)
     }
 
-    pub fn push(&mut self, rumor: Rumor) {
-        if let Some((rumor_id, cur_rumor)) = self.broadcasting.get_mut(&rumor.peer_id) {
+    /// Queue a rumor for broadcast at `now`, returning false if it's old news or the queue is
+    /// full of more pressing broadcasts.
+    pub fn push(&mut self, rumor: Rumor, now: Instant) -> bool {
+        self.push_until(rumor, now, None)
+    }
+
+    /// Queue a rumor like [`BroadcastStore::push`], dropping it at `expires_at` if given
+    pub fn push_until(&mut self, rumor: Rumor, now: Instant, expires_at: Option<Instant>) -> bool {
+        let subject = rumor.subject();
+        if let Some((_, cur_rumor)) = self.broadcasting.get(&subject) {
             assert_eq!(cur_rumor.peer_id, rumor.peer_id);
-            if let Some(Ordering::Greater) = rumor.partial_cmp(cur_rumor) {
-                *rumor_id = self.next_broadcast;
-                *cur_rumor = rumor;
-            } else {
+            if !rumor.supersedes(cur_rumor) {
                 // Old news
-                return;
+                return false;
             }
-        } else {
-            self.broadcasting
-                .insert(rumor.peer_id, (self.next_broadcast, rumor));
+            // Replace the stale broadcast so the queue holds at most one per subject
+            self.queue.retain(|bc| bc.subject != subject);
         }
+        // Serialize once up front; every send and replay reuses these bytes
+        let message = rumor.serialize();
+        let priority = Priority::from(&rumor.kind);
+        let id = self.next_broadcast;
+        self.broadcasting.insert(subject, (id, rumor));
         self.queue.push(Broadcast {
-            peer_id: rumor.peer_id,
-            message: rumor.serialize(),
+            subject,
+            priority,
+            message,
             sends: 0,
-            id: self.next_broadcast,
+            id,
+            created_at: now,
+            expires_at,
         });
         self.next_broadcast = self.next_broadcast.wrapping_add(1);
+        !self.evict() || self.queue.iter().any(|bc| bc.id == id)
+    }
+
+    /// Number of broadcasts waiting to be sent
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Whether the latest membership news about `peer_id` is at `incarnation` and still
+    /// waiting to be sent
+    pub fn is_queued(&self, peer_id: PeerId, incarnation: Incarnation) -> bool {
+        match self.broadcasting.get(&Subject::Peer(peer_id)) {
+            Some((id, rumor)) if rumor.incarnation == incarnation => {
+                self.queue.iter().any(|bc| bc.id == *id)
+            }
+            _ => false,
+        }
+    }
+
+    /// Queued broadcasts in the order they'll be sent
+    pub fn iter(&self) -> impl Iterator<Item = &Broadcast> {
+        let mut queued: Vec<_> = self.queue.iter().collect();
+        queued.sort_by(|a, b| b.cmp(a));
+        queued.into_iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
     }
 
     pub fn push_broadcast(&mut self, broadcast: Broadcast) {
         self.queue.push(broadcast);
     }
 
+    /// Drop a popped broadcast that's been sent enough times
+    pub fn finish(&mut self, broadcast: Broadcast) {
+        self.forget(&broadcast);
+    }
+
     pub fn pop(&mut self) -> Option<Broadcast> {
         while let Some(bc) = self.queue.pop() {
-            let (latest_id, _) = self.broadcasting.get(&bc.peer_id).unwrap();
+            let (latest_id, _) = self.broadcasting.get(&bc.subject).unwrap();
             if bc.id >= *latest_id {
                 return Some(bc);
-            } else {
-                return None;
             }
+            // Superseded by newer news while it was out being sent
         }
         None
     }
@@ -99,29 +243,37 @@ impl BroadcastStore {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
 
     #[test]
     fn disregards_lesser_news() {
         // We only keep the latest news for a given peer
         let mut bs = BroadcastStore::new();
-        bs.push(Rumor {
-            peer_id: 1.into(),
-            incarnation: 1.into(),
-            kind: RumorKind::Suspect,
-        });
+        let now = Instant::now();
+        bs.push(
+            Rumor {
+                peer_id: 1.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::Suspect { from: 7.into() },
+            },
+            now,
+        );
         let alive = Rumor {
             peer_id: 1.into(),
             incarnation: 2.into(),
-            kind: RumorKind::Alive("127.0.0.1:8080".parse().unwrap()),
+            kind: RumorKind::alive("127.0.0.1:8080".parse().unwrap()),
         };
-        bs.push(alive);
+        bs.push(alive.clone(), now);
         assert_eq!(
             bs.pop(),
             Some(Broadcast {
-                peer_id: 1.into(),
+                subject: Subject::Peer(1.into()),
+                priority: Priority::Normal,
                 message: alive.serialize(),
                 sends: 0,
                 id: 1,
+                created_at: now,
+                expires_at: None,
             })
         );
         // The suspect rumor is ignored as new news arrived
@@ -130,7 +282,254 @@ mod tests {
 
     #[test]
     fn test_broadcast_ordering() {
-        // Fewest sends, then largest size, then newest message
-        todo!()
+        // Fewest sends, then largest size, then oldest message
+        let now = Instant::now();
+        let broadcast = |id: usize, sends: usize, size: usize| Broadcast {
+            id,
+            subject: Subject::Peer((id as u64).into()),
+            priority: Priority::Normal,
+            sends,
+            message: vec![0; size],
+            created_at: now,
+            expires_at: None,
+        };
+        let mut queue: BinaryHeap<Broadcast> = [
+            broadcast(0, 2, 30),
+            broadcast(1, 0, 9),
+            broadcast(2, 0, 9),
+            broadcast(3, 1, 30),
+            broadcast(4, 0, 30),
+            broadcast(5, 1, 9),
+        ]
+        .into_iter()
+        .collect();
+        let mut order = Vec::new();
+        while let Some(bc) = queue.pop() {
+            order.push(bc.id);
+        }
+        assert_eq!(order, vec![4, 1, 2, 3, 5, 0]);
+    }
+
+    #[test]
+    fn membership_news_jumps_the_queue() {
+        let mut bs = BroadcastStore::new();
+        bs.push(
+            Rumor {
+                peer_id: 1.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::alive("127.0.0.1:8080".parse().unwrap()),
+            },
+            Instant::now(),
+        );
+        bs.push(
+            Rumor {
+                peer_id: 2.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::Failed,
+            },
+            Instant::now(),
+        );
+        let first = bs.pop().unwrap();
+        assert_eq!(first.subject, Subject::Peer(2.into()));
+        assert_eq!(first.priority, Priority::Membership);
+        assert_eq!(bs.pop().unwrap().subject, Subject::Peer(1.into()));
+    }
+
+    #[test]
+    fn user_data_goes_last() {
+        let mut bs = BroadcastStore::new();
+        bs.push(
+            Rumor {
+                peer_id: 1.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::User {
+                    seq_no: 1,
+                    tag: 1,
+                    data: vec![0; 200],
+                },
+            },
+            Instant::now(),
+        );
+        bs.push(
+            Rumor {
+                peer_id: 2.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::alive("127.0.0.1:8080".parse().unwrap()),
+            },
+            Instant::now(),
+        );
+        // Bigger and queued first, but still behind an Alive refresh
+        assert_eq!(bs.pop().unwrap().subject, Subject::Peer(2.into()));
+        assert_eq!(bs.pop().unwrap().priority, Priority::User);
+    }
+
+    #[test]
+    fn newer_news_replaces_queued_broadcasts() {
+        let mut bs = BroadcastStore::new();
+        for incarnation in 1..=5 {
+            bs.push(
+                Rumor {
+                    peer_id: 1.into(),
+                    incarnation: incarnation.into(),
+                    kind: RumorKind::alive("127.0.0.1:8080".parse().unwrap()),
+                },
+                Instant::now(),
+            );
+            bs.push(
+                Rumor {
+                    peer_id: 2.into(),
+                    incarnation: 3.into(),
+                    kind: RumorKind::Suspect { from: 7.into() },
+                },
+                Instant::now(),
+            );
+        }
+        assert_eq!(bs.len(), 2);
+        // Higher precedence at the same incarnation also replaces
+        bs.push(
+            Rumor {
+                peer_id: 2.into(),
+                incarnation: 3.into(),
+                kind: RumorKind::Failed,
+            },
+            Instant::now(),
+        );
+        assert_eq!(bs.len(), 2);
+        let failed = bs.pop().unwrap();
+        assert_eq!(failed.subject, Subject::Peer(2.into()));
+        assert_eq!(
+            Rumor::deserialize(&failed.message).unwrap().0.kind,
+            RumorKind::Failed
+        );
+        let alive = bs.pop().unwrap();
+        assert_eq!(
+            Rumor::deserialize(&alive.message).unwrap().0.incarnation,
+            5.into()
+        );
+        assert!(bs.is_empty());
+    }
+
+    fn alive(peer_id: u64) -> Rumor {
+        Rumor {
+            peer_id: peer_id.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::alive("127.0.0.1:8080".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn full_queues_evict_the_least_useful_broadcasts() {
+        let mut bs = BroadcastStore::new();
+        bs.set_capacity(3);
+        let now = Instant::now();
+        bs.push(alive(1), now);
+        bs.push(alive(2), now);
+        let sent = bs.pop().unwrap();
+        assert_eq!(sent.subject, Subject::Peer(1.into()));
+        bs.replay(sent);
+        bs.push(
+            Rumor {
+                peer_id: 3.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::Failed,
+            },
+            now,
+        );
+        // Peer 1 is the only normal-priority broadcast that's been sent already
+        bs.push(alive(4), now);
+        assert_eq!(bs.len(), 3);
+        let mut order = Vec::new();
+        while let Some(bc) = bs.pop() {
+            order.push(bc.subject);
+        }
+        assert_eq!(
+            order,
+            vec![
+                Subject::Peer(3.into()),
+                Subject::Peer(2.into()),
+                Subject::Peer(4.into())
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_inputs_pop_identically() {
+        let now = Instant::now();
+        let store = || {
+            let mut bs = BroadcastStore::new();
+            // Ties on priority, sends and size, with replays mixed in
+            for id in [5, 3, 8, 1] {
+                bs.push(alive(id), now);
+            }
+            let first = bs.pop().unwrap();
+            bs.replay(first);
+            bs.push(
+                Rumor {
+                    peer_id: 9.into(),
+                    incarnation: 1.into(),
+                    kind: RumorKind::Failed,
+                },
+                now,
+            );
+            for id in [2, 7] {
+                bs.push(alive(id), now);
+            }
+            let mut order = Vec::new();
+            while let Some(bc) = bs.pop() {
+                order.push((bc.id, bc.subject));
+            }
+            order
+        };
+        let order = store();
+        assert_eq!(order, store());
+        let subjects: Vec<_> = order.into_iter().map(|(_, subject)| subject).collect();
+        assert_eq!(
+            subjects,
+            [9, 3, 8, 1, 2, 7, 5].map(|id| Subject::Peer(id.into()))
+        );
+    }
+
+    #[test]
+    fn old_broadcasts_retire() {
+        let mut bs = BroadcastStore::new();
+        let start = Instant::now();
+        bs.push(alive(1), start);
+        bs.push(alive(2), start + Duration::from_secs(1));
+        bs.retire(start + Duration::from_millis(500));
+        assert_eq!(bs.len(), 1);
+        assert_eq!(bs.pop().unwrap().subject, Subject::Peer(2.into()));
+    }
+
+    #[test]
+    fn subjects_are_forgotten_once_their_broadcasts_leave() {
+        let mut bs = BroadcastStore::new();
+        bs.set_capacity(2);
+        let now = Instant::now();
+        bs.push(alive(1), now);
+        bs.push(alive(2), now + Duration::from_secs(1));
+        bs.push_until(alive(3), now, Some(now + Duration::from_secs(1)));
+        // Evicted to make room
+        assert!(!bs.broadcasting.contains_key(&Subject::Peer(1.into())));
+        bs.expire(now + Duration::from_secs(1));
+        assert!(!bs.broadcasting.contains_key(&Subject::Peer(3.into())));
+        bs.push(alive(4), now);
+        bs.retire(now + Duration::from_millis(500));
+        assert!(!bs.broadcasting.contains_key(&Subject::Peer(4.into())));
+        let sent = bs.pop().unwrap();
+        bs.finish(sent);
+        assert!(bs.broadcasting.is_empty());
+        // Newer news queued while the old was out keeps its subject tracked
+        bs.push(alive(5), now);
+        let sent = bs.pop().unwrap();
+        bs.push(
+            Rumor {
+                peer_id: 5.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::Failed,
+            },
+            now,
+        );
+        bs.finish(sent);
+        assert!(bs.is_queued(5.into(), 1.into()));
     }
 }
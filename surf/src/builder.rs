@@ -0,0 +1,659 @@
+use alloc::boxed::Box;
+use core::net::SocketAddr;
+use core::time::Duration;
+
+use rand::RngCore;
+
+use crate::clock::DefaultClock;
+#[cfg(feature = "compression")]
+use crate::DEFAULT_COMPRESSION_THRESHOLD;
+use crate::{
+    AdmitAll, Clock, HelperPreference, Incarnation, MembershipDelegate, MembershipSnapshot,
+    Metrics, NoopDelegate, NoopMetrics, Peer, PeerFilter, PeerId, ProbeSelection, Server,
+    Transport, VecTransport, DEFAULT_CLUSTER_LOSS_PERIODS, DEFAULT_GOSSIP_FANOUT,
+    DEFAULT_LEAVE_TIMEOUT, DEFAULT_RETRANSMIT_MULTIPLIER, DEFAULT_TOMBSTONE_TTL,
+};
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum ConfigError {
+    #[error("pingreq subgroup size must be at least 1")]
+    ZeroSubgroupSize,
+    #[error("must probe at least 1 peer per period")]
+    ZeroProbesPerPeriod,
+    #[error("must send at least 1 gossip message per peer per round")]
+    ZeroGossipMessages,
+    #[error("cluster loss window must be at least 1 protocol period")]
+    ZeroClusterLossPeriods,
+    #[error("protocol period must be non-zero")]
+    ZeroProtocolPeriod,
+    #[error("phi threshold {0} must be positive")]
+    NonPositivePhiThreshold(f64),
+    #[error("probe jitter {0} must be at least 0 and less than 1")]
+    ProbeJitterOutOfRange(f64),
+    #[error("user gossip fraction {0} must be between 0 and 1")]
+    UserGossipFractionOutOfRange(f64),
+    #[error("minimum suspicion period {min:?} exceeds the maximum {max:?}")]
+    SuspicionBoundsInverted { min: Duration, max: Duration },
+    #[error("ping interval {ping_interval:?} must be shorter than the protocol period {protocol_period:?}")]
+    PingIntervalTooLong {
+        ping_interval: Duration,
+        protocol_period: Duration,
+    },
+}
+
+/// Configures a [`Server`] by name rather than position, checking the settings make sense
+/// before building it.
+pub struct ServerBuilder<T = VecTransport> {
+    id: PeerId,
+    addr: SocketAddr,
+    ping_interval: Duration,
+    pingreq_subgroup_sz: usize,
+    protocol_period: Duration,
+    suspicion_period: Option<Duration>,
+    min_suspicion: Duration,
+    max_suspicion: Duration,
+    suspicion_timeout: Option<(Duration, Duration)>,
+    tombstone_ttl: Duration,
+    broadcast_capacity: Option<usize>,
+    broadcast_max_age: Option<u32>,
+    gossip_fanout: usize,
+    max_gossip_messages: usize,
+    max_piggyback: usize,
+    leave_timeout: Duration,
+    cluster_loss_periods: u32,
+    user_gossip_fraction: f64,
+    disseminate_membership: bool,
+    push_sample_size: usize,
+    cluster_label: u64,
+    retransmit_multiplier: u32,
+    transport: T,
+    clock: Box<dyn Clock>,
+    delegate: Box<dyn MembershipDelegate>,
+    peer_filter: Box<dyn PeerFilter>,
+    metrics: Box<dyn Metrics>,
+    helper_preference: Option<HelperPreference>,
+    indirect_probes: bool,
+    deterministic_insert: bool,
+    rng: Option<Box<dyn RngCore>>,
+    snapshot: Option<MembershipSnapshot>,
+    incarnation: Option<Incarnation>,
+    phi_threshold: Option<f64>,
+    probe_jitter: f64,
+    probes_per_period: usize,
+    probe_selection: ProbeSelection,
+    #[cfg(feature = "auth")]
+    secret_keys: Vec<Vec<u8>>,
+    #[cfg(feature = "encryption")]
+    encryption_keys: Vec<[u8; 32]>,
+    #[cfg(feature = "compression")]
+    compression_threshold: usize,
+}
+
+impl ServerBuilder<VecTransport> {
+    /// Start from the defaults: 500ms pings within a 1s protocol period, a pingreq subgroup
+    /// of 3, a suspicion period of 3 protocol periods that grows with `log10` of the cluster's
+    /// size, and messages buffered in a [`VecTransport`].
+    pub fn new(id: PeerId, addr: SocketAddr) -> Self {
+        ServerBuilder {
+            id,
+            addr,
+            ping_interval: Duration::from_millis(500),
+            pingreq_subgroup_sz: 3,
+            protocol_period: Duration::from_secs(1),
+            suspicion_period: None,
+            min_suspicion: Duration::ZERO,
+            max_suspicion: Duration::MAX,
+            suspicion_timeout: None,
+            tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+            broadcast_capacity: None,
+            broadcast_max_age: None,
+            gossip_fanout: DEFAULT_GOSSIP_FANOUT,
+            max_gossip_messages: 1,
+            leave_timeout: DEFAULT_LEAVE_TIMEOUT,
+            cluster_loss_periods: DEFAULT_CLUSTER_LOSS_PERIODS,
+            max_piggyback: usize::MAX,
+            user_gossip_fraction: 1.0,
+            disseminate_membership: true,
+            push_sample_size: usize::MAX,
+            cluster_label: 0,
+            retransmit_multiplier: DEFAULT_RETRANSMIT_MULTIPLIER,
+            transport: VecTransport::new(),
+            clock: Box::new(DefaultClock::default()),
+            delegate: Box::new(NoopDelegate),
+            peer_filter: Box::new(AdmitAll),
+            metrics: Box::new(NoopMetrics),
+            helper_preference: None,
+            indirect_probes: true,
+            deterministic_insert: false,
+            rng: None,
+            snapshot: None,
+            incarnation: None,
+            phi_threshold: None,
+            probe_jitter: 0.0,
+            probes_per_period: 1,
+            probe_selection: ProbeSelection::Shuffled,
+            #[cfg(feature = "auth")]
+            secret_keys: Vec::new(),
+            #[cfg(feature = "encryption")]
+            encryption_keys: Vec::new(),
+            #[cfg(feature = "compression")]
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    /// See [`Server::with_max_outbox`]
+    pub fn max_outbox(mut self, max: usize) -> Self {
+        self.transport = VecTransport::bounded(max);
+        self
+    }
+}
+
+impl<T> ServerBuilder<T> {
+    /// How long to wait for a direct ack before asking others to ping on our behalf
+    pub fn ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    /// How many peers to ask to ping an unresponsive peer for us
+    pub fn pingreq_subgroup_sz(mut self, pingreq_subgroup_sz: usize) -> Self {
+        self.pingreq_subgroup_sz = pingreq_subgroup_sz;
+        self
+    }
+
+    /// How often we probe a peer
+    pub fn protocol_period(mut self, protocol_period: Duration) -> Self {
+        self.protocol_period = protocol_period;
+        self
+    }
+
+    /// How long a suspected peer has to refute the suspicion before it's declared failed,
+    /// however big the cluster grows, see [`Server::set_suspicion_period`]. Overrides
+    /// [`ServerBuilder::min_suspicion`] and [`ServerBuilder::max_suspicion`].
+    pub fn suspicion_period(mut self, suspicion_period: Duration) -> Self {
+        self.suspicion_period = Some(suspicion_period);
+        self
+    }
+
+    /// See [`Server::with_gossip_fanout`]
+    pub fn gossip_fanout(mut self, gossip_fanout: usize) -> Self {
+        self.gossip_fanout = gossip_fanout;
+        self
+    }
+
+    /// See [`Server::with_max_gossip_messages`]
+    pub fn max_gossip_messages(mut self, max: usize) -> Self {
+        self.max_gossip_messages = max;
+        self
+    }
+
+    /// See [`Server::with_cluster_loss_periods`]
+    pub fn cluster_loss_periods(mut self, periods: u32) -> Self {
+        self.cluster_loss_periods = periods;
+        self
+    }
+
+    /// See [`Server::with_leave_timeout`]
+    pub fn leave_timeout(mut self, timeout: Duration) -> Self {
+        self.leave_timeout = timeout;
+        self
+    }
+
+    /// See [`Server::with_max_piggyback`]
+    pub fn max_piggyback(mut self, max_piggyback: usize) -> Self {
+        self.max_piggyback = max_piggyback;
+        self
+    }
+
+    /// See [`Server::with_user_gossip_fraction`]
+    pub fn user_gossip_fraction(mut self, fraction: f64) -> Self {
+        self.user_gossip_fraction = fraction;
+        self
+    }
+
+    /// See [`Server::with_disseminate_membership`]
+    pub fn disseminate_membership(mut self, enabled: bool) -> Self {
+        self.disseminate_membership = enabled;
+        self
+    }
+
+    /// See [`Server::with_push_sample_size`]
+    pub fn push_sample_size(mut self, size: usize) -> Self {
+        self.push_sample_size = size;
+        self
+    }
+
+    /// See [`Server::with_retransmit_multiplier`]
+    pub fn retransmit_multiplier(mut self, multiplier: u32) -> Self {
+        self.retransmit_multiplier = multiplier;
+        self
+    }
+
+    /// See [`Server::with_cluster_label`]
+    pub fn cluster_label(mut self, label: u64) -> Self {
+        self.cluster_label = label;
+        self
+    }
+
+    /// See [`Server::with_clock`]
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// See [`Server::with_delegate`]
+    pub fn delegate(mut self, delegate: impl MembershipDelegate + 'static) -> Self {
+        self.delegate = Box::new(delegate);
+        self
+    }
+
+    /// See [`Server::with_metrics`]
+    pub fn metrics(mut self, metrics: impl Metrics + 'static) -> Self {
+        self.metrics = Box::new(metrics);
+        self
+    }
+
+    /// See [`Server::with_peer_filter`]
+    pub fn peer_filter(mut self, filter: impl PeerFilter + 'static) -> Self {
+        self.peer_filter = Box::new(filter);
+        self
+    }
+
+    /// See [`Server::with_helper_preference`]
+    pub fn helper_preference(mut self, prefer: impl Fn(&Peer, &Peer) -> bool + 'static) -> Self {
+        self.helper_preference = Some(Box::new(prefer));
+        self
+    }
+
+    /// See [`Server::with_indirect_probes`]
+    pub fn indirect_probes(mut self, enabled: bool) -> Self {
+        self.indirect_probes = enabled;
+        self
+    }
+
+    /// See [`Server::with_rng`]
+    pub fn rng(mut self, rng: impl RngCore + 'static) -> Self {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    /// See [`Server::with_phi_threshold`]
+    pub fn phi_threshold(mut self, threshold: f64) -> Self {
+        self.phi_threshold = Some(threshold);
+        self
+    }
+
+    /// See [`Server::with_probes_per_period`]
+    pub fn probes_per_period(mut self, count: usize) -> Self {
+        self.probes_per_period = count;
+        self
+    }
+
+    /// See [`Server::with_deterministic_insert`]
+    pub fn deterministic_insert(mut self, enabled: bool) -> Self {
+        self.deterministic_insert = enabled;
+        self
+    }
+
+    /// See [`Server::with_probe_selection`]
+    pub fn probe_selection(mut self, selection: ProbeSelection) -> Self {
+        self.probe_selection = selection;
+        self
+    }
+
+    /// See [`Server::with_probe_jitter`]
+    pub fn probe_jitter(mut self, fraction: f64) -> Self {
+        self.probe_jitter = fraction;
+        self
+    }
+
+    /// See [`Server::with_min_suspicion`]. Only bounds the size-scaled SWIM suspicion period,
+    /// not the Lifeguard range from [`ServerBuilder::suspicion_timeout`].
+    pub fn min_suspicion(mut self, min: Duration) -> Self {
+        self.min_suspicion = min;
+        self
+    }
+
+    /// See [`Server::with_max_suspicion`]. Only bounds the size-scaled SWIM suspicion period,
+    /// not the Lifeguard range from [`ServerBuilder::suspicion_timeout`].
+    pub fn max_suspicion(mut self, max: Duration) -> Self {
+        self.max_suspicion = max;
+        self
+    }
+
+    /// See [`Server::with_suspicion_timeout`]. Replaces the SWIM suspicion period, and so
+    /// [`ServerBuilder::suspicion_period`], [`ServerBuilder::min_suspicion`] and
+    /// [`ServerBuilder::max_suspicion`], outright.
+    pub fn suspicion_timeout(mut self, min: Duration, max: Duration) -> Self {
+        self.suspicion_timeout = Some((min, max));
+        self
+    }
+
+    /// See [`Server::with_tombstone_ttl`]
+    pub fn tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    /// See [`Server::with_broadcast_capacity`]
+    pub fn broadcast_capacity(mut self, capacity: usize) -> Self {
+        self.broadcast_capacity = Some(capacity);
+        self
+    }
+
+    /// See [`Server::with_broadcast_max_age`]
+    pub fn broadcast_max_age(mut self, periods: u32) -> Self {
+        self.broadcast_max_age = Some(periods);
+        self
+    }
+
+    /// See [`Server::with_incarnation`]
+    pub fn incarnation(mut self, incarnation: Incarnation) -> Self {
+        self.incarnation = Some(incarnation);
+        self
+    }
+
+    /// See [`Server::with_secret_key`]. Call again to add more keys for rotation.
+    #[cfg(feature = "auth")]
+    pub fn secret_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.secret_keys.push(key.into());
+        self
+    }
+
+    /// See [`Server::with_encryption_key`]. Call again to add more keys for rotation.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption_keys.push(key);
+        self
+    }
+
+    /// See [`Server::with_compression_threshold`]
+    #[cfg(feature = "compression")]
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Start from what a previous run knew, see [`Server::restore`]
+    pub fn snapshot(mut self, snapshot: MembershipSnapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Send messages through `transport` instead
+    pub fn transport<U>(self, transport: U) -> ServerBuilder<U> {
+        ServerBuilder {
+            id: self.id,
+            addr: self.addr,
+            ping_interval: self.ping_interval,
+            pingreq_subgroup_sz: self.pingreq_subgroup_sz,
+            protocol_period: self.protocol_period,
+            suspicion_period: self.suspicion_period,
+            min_suspicion: self.min_suspicion,
+            max_suspicion: self.max_suspicion,
+            suspicion_timeout: self.suspicion_timeout,
+            tombstone_ttl: self.tombstone_ttl,
+            broadcast_capacity: self.broadcast_capacity,
+            broadcast_max_age: self.broadcast_max_age,
+            gossip_fanout: self.gossip_fanout,
+            max_gossip_messages: self.max_gossip_messages,
+            leave_timeout: self.leave_timeout,
+            cluster_loss_periods: self.cluster_loss_periods,
+            max_piggyback: self.max_piggyback,
+            user_gossip_fraction: self.user_gossip_fraction,
+            disseminate_membership: self.disseminate_membership,
+            push_sample_size: self.push_sample_size,
+            cluster_label: self.cluster_label,
+            retransmit_multiplier: self.retransmit_multiplier,
+            transport,
+            clock: self.clock,
+            delegate: self.delegate,
+            peer_filter: self.peer_filter,
+            metrics: self.metrics,
+            helper_preference: self.helper_preference,
+            indirect_probes: self.indirect_probes,
+            deterministic_insert: self.deterministic_insert,
+            rng: self.rng,
+            snapshot: self.snapshot,
+            incarnation: self.incarnation,
+            phi_threshold: self.phi_threshold,
+            probe_jitter: self.probe_jitter,
+            probes_per_period: self.probes_per_period,
+            probe_selection: self.probe_selection,
+            #[cfg(feature = "auth")]
+            secret_keys: self.secret_keys,
+            #[cfg(feature = "encryption")]
+            encryption_keys: self.encryption_keys,
+            #[cfg(feature = "compression")]
+            compression_threshold: self.compression_threshold,
+        }
+    }
+}
+
+impl<T: Transport> ServerBuilder<T> {
+    pub fn build(self) -> Result<Server<T>, ConfigError> {
+        if self.pingreq_subgroup_sz == 0 {
+            return Err(ConfigError::ZeroSubgroupSize);
+        }
+        if self.probes_per_period == 0 {
+            return Err(ConfigError::ZeroProbesPerPeriod);
+        }
+        if self.max_gossip_messages == 0 {
+            return Err(ConfigError::ZeroGossipMessages);
+        }
+        if self.cluster_loss_periods == 0 {
+            return Err(ConfigError::ZeroClusterLossPeriods);
+        }
+        if self.protocol_period.is_zero() {
+            return Err(ConfigError::ZeroProtocolPeriod);
+        }
+        if self.ping_interval >= self.protocol_period {
+            return Err(ConfigError::PingIntervalTooLong {
+                ping_interval: self.ping_interval,
+                protocol_period: self.protocol_period,
+            });
+        }
+        if let Some(threshold) = self.phi_threshold.filter(|t| t.is_nan() || *t <= 0.0) {
+            return Err(ConfigError::NonPositivePhiThreshold(threshold));
+        }
+        if !(0.0..1.0).contains(&self.probe_jitter) {
+            return Err(ConfigError::ProbeJitterOutOfRange(self.probe_jitter));
+        }
+        if !(0.0..=1.0).contains(&self.user_gossip_fraction) {
+            return Err(ConfigError::UserGossipFractionOutOfRange(
+                self.user_gossip_fraction,
+            ));
+        }
+        let (min_suspicion, max_suspicion) = match self.suspicion_period {
+            Some(period) => (period, period),
+            None => (self.min_suspicion, self.max_suspicion),
+        };
+        if min_suspicion > max_suspicion {
+            return Err(ConfigError::SuspicionBoundsInverted {
+                min: min_suspicion,
+                max: max_suspicion,
+            });
+        }
+        if let Some((min, max)) = self.suspicion_timeout.filter(|(min, max)| min > max) {
+            return Err(ConfigError::SuspicionBoundsInverted { min, max });
+        }
+        let mut server = Server::with_transport(
+            self.id,
+            self.addr,
+            self.ping_interval,
+            self.pingreq_subgroup_sz,
+            self.protocol_period,
+            self.protocol_period * 3,
+            self.transport,
+        )
+        .with_gossip_fanout(self.gossip_fanout)
+        .with_max_gossip_messages(self.max_gossip_messages)
+        .with_leave_timeout(self.leave_timeout)
+        .with_cluster_loss_periods(self.cluster_loss_periods)
+        .with_max_piggyback(self.max_piggyback)
+        .with_user_gossip_fraction(self.user_gossip_fraction)
+        .with_disseminate_membership(self.disseminate_membership)
+        .with_indirect_probes(self.indirect_probes)
+        .with_deterministic_insert(self.deterministic_insert)
+        .with_push_sample_size(self.push_sample_size)
+        .with_cluster_label(self.cluster_label)
+        .with_retransmit_multiplier(self.retransmit_multiplier)
+        .with_min_suspicion(min_suspicion)
+        .with_max_suspicion(max_suspicion)
+        .with_tombstone_ttl(self.tombstone_ttl)
+        .with_probe_jitter(self.probe_jitter)
+        .with_probes_per_period(self.probes_per_period)
+        .with_probe_selection(self.probe_selection);
+        server.clock = self.clock;
+        server.delegate = self.delegate;
+        server.peer_filter = self.peer_filter;
+        server.metrics = self.metrics;
+        server.helper_preference = self.helper_preference;
+        server.phi_threshold = self.phi_threshold;
+        server.suspicion_bounds = self.suspicion_timeout;
+        server.broadcast_max_age = self.broadcast_max_age;
+        if let Some(capacity) = self.broadcast_capacity {
+            server.broadcasts.set_capacity(capacity);
+        }
+        #[cfg(feature = "auth")]
+        server.set_secret_keys(self.secret_keys);
+        #[cfg(feature = "encryption")]
+        server.set_encryption_keys(self.encryption_keys);
+        #[cfg(feature = "compression")]
+        {
+            server.compression_threshold = self.compression_threshold;
+        }
+        if let Some(rng) = self.rng {
+            server.rng = rng;
+        }
+        // Now that the delegate is in place to hear about it
+        if let Some(incarnation) = self.incarnation {
+            server = server.with_incarnation(incarnation);
+        }
+        if let Some(snapshot) = self.snapshot {
+            server.restore(snapshot);
+        }
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder() -> ServerBuilder {
+        ServerBuilder::new(1.into(), "127.0.0.1:8080".parse().unwrap())
+    }
+
+    #[test]
+    fn builds_with_defaults() {
+        let s = builder().build().unwrap();
+        assert_eq!(s.id, 1.into());
+        assert_eq!(s.pingreq_subgroup_sz, 3);
+        assert!(s.ping_interval < s.protocol_period);
+    }
+
+    #[test]
+    fn honours_the_suspicion_period() {
+        let s = builder().build().unwrap();
+        assert_eq!(s.suspicion_period(), Duration::from_secs(3));
+        let mut s = builder()
+            .suspicion_period(Duration::from_millis(150))
+            .build()
+            .unwrap();
+        assert_eq!(s.suspicion_period(), Duration::from_millis(150));
+        // Pinned, so it no longer follows the protocol period
+        s.set_protocol_period(Duration::from_secs(2)).unwrap();
+        assert_eq!(s.suspicion_period(), Duration::from_millis(150));
+    }
+
+    #[test]
+    fn passes_everything_on() {
+        let s = builder()
+            .suspicion_timeout(Duration::from_secs(1), Duration::from_secs(4))
+            .tombstone_ttl(Duration::from_secs(60))
+            .broadcast_capacity(16)
+            .broadcast_max_age(10)
+            .build()
+            .unwrap();
+        assert_eq!(
+            s.suspicion_bounds,
+            Some((Duration::from_secs(1), Duration::from_secs(4)))
+        );
+        assert_eq!(s.tombstone_ttl, Duration::from_secs(60));
+        assert_eq!(s.broadcast_max_age, Some(10));
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        assert_eq!(
+            builder().pingreq_subgroup_sz(0).build().err(),
+            Some(ConfigError::ZeroSubgroupSize)
+        );
+        assert_eq!(
+            builder()
+                .ping_interval(Duration::ZERO)
+                .protocol_period(Duration::ZERO)
+                .build()
+                .err(),
+            Some(ConfigError::ZeroProtocolPeriod)
+        );
+        // Easy to do by swapping the two intervals
+        assert_eq!(
+            builder()
+                .ping_interval(Duration::from_secs(1))
+                .protocol_period(Duration::from_millis(200))
+                .build()
+                .err(),
+            Some(ConfigError::PingIntervalTooLong {
+                ping_interval: Duration::from_secs(1),
+                protocol_period: Duration::from_millis(200),
+            })
+        );
+        assert_eq!(
+            builder().phi_threshold(0.0).build().err(),
+            Some(ConfigError::NonPositivePhiThreshold(0.0))
+        );
+        assert_eq!(
+            builder().probes_per_period(0).build().err(),
+            Some(ConfigError::ZeroProbesPerPeriod)
+        );
+        assert_eq!(
+            builder().max_gossip_messages(0).build().err(),
+            Some(ConfigError::ZeroGossipMessages)
+        );
+        assert_eq!(
+            builder().cluster_loss_periods(0).build().err(),
+            Some(ConfigError::ZeroClusterLossPeriods)
+        );
+        assert_eq!(
+            builder().probe_jitter(1.0).build().err(),
+            Some(ConfigError::ProbeJitterOutOfRange(1.0))
+        );
+        assert_eq!(
+            builder().user_gossip_fraction(1.5).build().err(),
+            Some(ConfigError::UserGossipFractionOutOfRange(1.5))
+        );
+        assert_eq!(
+            builder()
+                .min_suspicion(Duration::from_secs(2))
+                .max_suspicion(Duration::from_secs(1))
+                .build()
+                .err(),
+            Some(ConfigError::SuspicionBoundsInverted {
+                min: Duration::from_secs(2),
+                max: Duration::from_secs(1),
+            })
+        );
+        assert_eq!(
+            builder()
+                .suspicion_timeout(Duration::from_secs(2), Duration::from_secs(1))
+                .build()
+                .err(),
+            Some(ConfigError::SuspicionBoundsInverted {
+                min: Duration::from_secs(2),
+                max: Duration::from_secs(1),
+            })
+        );
+    }
+}
@@ -0,0 +1,134 @@
+#[cfg(not(feature = "std"))]
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+#[cfg(not(feature = "std"))]
+use core::time::Duration;
+
+#[cfg(feature = "std")]
+pub use std::time::Instant;
+
+/// Source of time for a [`Server`](crate::Server). Everything time-sensitive, from stamping
+/// pings to expiring them, reads from the same clock.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Wall-clock time via [`Instant::now`]
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The clock servers start with
+#[cfg(feature = "std")]
+pub(crate) type DefaultClock = SystemClock;
+
+/// The clock servers start with
+#[cfg(not(feature = "std"))]
+pub(crate) type DefaultClock = StoppedClock;
+
+/// A moment as a [`Duration`] since some arbitrary start, such as when the device booted.
+/// Without `std` there's no system clock, so applications count time themselves and report
+/// it through a [`Clock`].
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    /// `elapsed` after the start
+    pub const fn from_elapsed(elapsed: Duration) -> Self {
+        Instant(elapsed)
+    }
+
+    /// How long after the start this is
+    pub const fn elapsed_since_start(&self) -> Duration {
+        self.0
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Instant)
+    }
+}
+
+/// Wall-clock time for the tests, counted from an hour before the first reading so that
+/// they can look back
+#[cfg(all(test, not(feature = "std")))]
+impl Instant {
+    pub fn now() -> Instant {
+        static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+        let start = START.get_or_init(std::time::Instant::now);
+        Instant(Duration::from_secs(3600) + start.elapsed())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.0 += rhs;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0 - rhs)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        self.0 -= rhs;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.0 - rhs.0
+    }
+}
+
+/// A clock stuck at the start, for servers built without `std` until the application
+/// supplies a real one through [`Server::with_clock`](crate::Server::with_clock)
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StoppedClock;
+
+#[cfg(not(feature = "std"))]
+impl Clock for StoppedClock {
+    fn now(&self) -> Instant {
+        Instant::default()
+    }
+}
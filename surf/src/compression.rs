@@ -0,0 +1,73 @@
+//! Compression of serialized messages, so membership lists and gossip take less of the
+//! network. Each payload starts with a flag saying whether the rest is compressed, so small
+//! ones can skip it.
+
+/// How many bytes [`Server::compress`](crate::Server::compress) adds in front of what it
+/// produces: the flag saying whether the rest is compressed
+pub const COMPRESSION_OVERHEAD: usize = 1;
+
+/// Payloads smaller than this go out as they are unless
+/// [`Server::with_compression_threshold`](crate::Server::with_compression_threshold) says
+/// otherwise, since compressing tiny messages costs more than it saves
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Largest payload we'll decompress to, so a hostile frame can't make us allocate wildly
+const MAX_DECOMPRESSED: usize = 16 << 20;
+
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+
+/// zstd's default trades a little ratio for speed, which suits a per-message codec
+const LEVEL: i32 = 3;
+
+/// Flag `bytes` and compress them if there are at least `threshold` of them and compressing
+/// actually makes them smaller
+pub(crate) fn compress(threshold: usize, bytes: &[u8]) -> Vec<u8> {
+    if bytes.len() >= threshold {
+        if let Ok(compressed) = zstd::bulk::compress(bytes, LEVEL) {
+            if compressed.len() < bytes.len() {
+                let mut flagged = Vec::with_capacity(COMPRESSION_OVERHEAD + compressed.len());
+                flagged.push(ZSTD);
+                flagged.extend_from_slice(&compressed);
+                return flagged;
+            }
+        }
+    }
+    let mut flagged = Vec::with_capacity(COMPRESSION_OVERHEAD + bytes.len());
+    flagged.push(RAW);
+    flagged.extend_from_slice(bytes);
+    flagged
+}
+
+/// Undo [`compress`], or `None` if the flag or the compressed bytes make no sense
+pub(crate) fn decompress(flagged: &[u8]) -> Option<Vec<u8>> {
+    match flagged.split_first()? {
+        (&RAW, bytes) => Some(bytes.to_vec()),
+        (&ZSTD, compressed) => zstd::bulk::decompress(compressed, MAX_DECOMPRESSED).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_large_payloads_are_compressed() {
+        let small = b"ping";
+        let flagged = compress(DEFAULT_COMPRESSION_THRESHOLD, small);
+        assert_eq!(flagged.len(), small.len() + COMPRESSION_OVERHEAD);
+        assert_eq!(flagged[0], RAW);
+        assert_eq!(decompress(&flagged).as_deref(), Some(&small[..]));
+
+        let large = vec![7; 4096];
+        let flagged = compress(DEFAULT_COMPRESSION_THRESHOLD, &large);
+        assert_eq!(flagged[0], ZSTD);
+        assert!(flagged.len() < large.len());
+        assert_eq!(decompress(&flagged), Some(large));
+
+        assert_eq!(decompress(&[]), None);
+        assert_eq!(decompress(&[ZSTD, 1, 2, 3]), None);
+        assert_eq!(decompress(&[9, 1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,44 @@
+use crate::{Incarnation, Peer, PeerState};
+
+/// Notified by a [`Server`](crate::Server) whenever one of its peers changes state
+pub trait MembershipDelegate {
+    /// `peer` is as it is now; `old` is the state it left, or `None` if we just met it
+    fn on_change(&mut self, peer: Peer, old: Option<PeerState>);
+
+    /// Our own incarnation rose to `incarnation`. Persist it to restart with
+    /// [`Server::with_incarnation`](crate::Server::with_incarnation).
+    fn on_incarnation(&mut self, _incarnation: Incarnation) {}
+}
+
+/// Ignores every change
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopDelegate;
+
+impl MembershipDelegate for NoopDelegate {
+    fn on_change(&mut self, _peer: Peer, _old: Option<PeerState>) {}
+}
+
+/// Decides which peers a [`Server`](crate::Server) lets in and where it reaches them, see
+/// [`Server::with_peer_filter`](crate::Server::with_peer_filter)
+pub trait PeerFilter {
+    /// `None` keeps `peer` out; otherwise the peer to let in in its place, e.g.
+    /// [at another address](Peer::with_addr) behind a NAT. Only the address of what's
+    /// returned is used.
+    fn admit(&self, peer: &Peer) -> Option<Peer>;
+}
+
+impl<F: Fn(&Peer) -> Option<Peer>> PeerFilter for F {
+    fn admit(&self, peer: &Peer) -> Option<Peer> {
+        self(peer)
+    }
+}
+
+/// Lets every peer in as it is
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AdmitAll;
+
+impl PeerFilter for AdmitAll {
+    fn admit(&self, peer: &Peer) -> Option<Peer> {
+        Some(peer.clone())
+    }
+}
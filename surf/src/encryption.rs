@@ -0,0 +1,70 @@
+//! Symmetric encryption of serialized messages, so that gossip on a shared network doesn't
+//! give away the cluster's topology.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// How many bytes [`Server::seal`](crate::Server::seal) adds: a random nonce up front and an
+/// authentication tag at the end
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// Encrypt `plaintext` under the first of `keys` with a fresh nonce from `rng`, or copy it
+/// as is when there are no keys
+pub(crate) fn seal(keys: &[[u8; 32]], rng: &mut dyn RngCore, plaintext: &[u8]) -> Vec<u8> {
+    let key = match keys.first() {
+        Some(key) => key,
+        None => return plaintext.to_vec(),
+    };
+    let mut nonce = [0; NONCE_SIZE];
+    rng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .expect("ChaCha20-Poly1305 only fails on absurdly large messages");
+    let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed
+}
+
+/// Decrypt what [`seal`] produced under whichever of `keys` authenticates it, or copy it as
+/// is when there are no keys
+pub(crate) fn open(keys: &[[u8; 32]], sealed: &[u8]) -> Option<Vec<u8>> {
+    if keys.is_empty() {
+        return Some(sealed.to_vec());
+    }
+    if sealed.len() < ENCRYPTION_OVERHEAD {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    keys.iter().find_map(|key| {
+        ChaCha20Poly1305::new(Key::from_slice(key))
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use super::*;
+
+    #[test]
+    fn sealed_bytes_open_under_any_key() {
+        let keys = [[1; 32], [2; 32]];
+        let sealed = seal(&keys[1..], &mut thread_rng(), b"ping");
+        assert_eq!(sealed.len(), 4 + ENCRYPTION_OVERHEAD);
+        assert_eq!(open(&keys, &sealed).as_deref(), Some(&b"ping"[..]));
+        assert_eq!(open(&keys[..1], &sealed), None);
+
+        let mut tampered = sealed.clone();
+        tampered[NONCE_SIZE] ^= 1;
+        assert_eq!(open(&keys, &tampered), None);
+        assert_eq!(open(&keys, b"ping"), None);
+        assert_eq!(open(&[], b"ping").as_deref(), Some(&b"ping"[..]));
+    }
+}
@@ -1,28 +1,152 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(any(feature = "std", feature = "alloc")))]
+compile_error!("surf needs either the `std` feature or, on no_std targets, `alloc`");
+
+extern crate alloc;
+// The tests lean on std even where the crate itself can't
+#[cfg(all(test, not(feature = "std")))]
+#[macro_use]
+extern crate std;
+#[cfg(not(feature = "tracing"))]
 #[macro_use]
 extern crate log;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
 
+#[cfg(feature = "auth")]
+mod auth;
 mod broadcast;
+mod builder;
+mod clock;
+#[cfg(feature = "compression")]
+mod compression;
+mod delegate;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod math;
+mod metrics;
+mod phi;
+mod replay;
 mod rumor;
+#[cfg(feature = "sim")]
+pub mod sim;
+#[cfg(feature = "serde")]
+mod socket_addr;
+mod transport;
+#[cfg(feature = "udp")]
+pub mod udp;
 
 pub use broadcast::*;
+pub use builder::*;
+pub use clock::*;
+#[cfg(feature = "compression")]
+pub use compression::{COMPRESSION_OVERHEAD, DEFAULT_COMPRESSION_THRESHOLD};
+pub use delegate::*;
+#[cfg(feature = "encryption")]
+pub use encryption::ENCRYPTION_OVERHEAD;
+pub use metrics::*;
 pub use rumor::*;
+pub use transport::*;
 
-use core::fmt;
-use rand::prelude::*;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
-use std::{
-    collections::{HashMap, HashSet},
-    fmt::Display,
+use alloc::{boxed::Box, collections::VecDeque, format, string::String, vec, vec::Vec};
+use collections::{HashMap, HashSet};
+use core::{
+    fmt::{self, Display},
     mem::take,
     net::SocketAddr,
-    time::{Duration, Instant},
+    time::Duration,
 };
+use phi::ArrivalWindow;
+use rand::prelude::*;
+use rand::seq::SliceRandom;
+use replay::ReplayWindow;
+
+/// Where a server gets its randomness until [`Server::with_rng`] replaces it
+#[cfg(feature = "std")]
+fn default_rng(_id: PeerId) -> Box<dyn RngCore> {
+    Box::new(rand::thread_rng())
+}
+
+/// Without an OS to seed from, a generator seeded with our id. It's predictable, so
+/// applications should supply a properly seeded one through [`Server::with_rng`].
+#[cfg(not(feature = "std"))]
+fn default_rng(id: PeerId) -> Box<dyn RngCore> {
+    Box::new(rand::rngs::SmallRng::seed_from_u64(id.0))
+}
+
+/// `std`'s hash maps where we have them, `hashbrown`'s otherwise
+mod collections {
+    #[cfg(not(feature = "std"))]
+    pub(crate) use hashbrown::{HashMap, HashSet};
+    #[cfg(feature = "std")]
+    pub(crate) use std::collections::{HashMap, HashSet};
+}
+
+/// Wire protocol version stamped on every [`Message`]. The high byte is the major version and
+/// the low byte the minor: peers accept any minor version of their own major version, so minor
+/// bumps must stay readable by older nodes.
+pub const PROTOCOL_VERSION: u16 = 0x0700;
+
+/// Whether we can handle a message stamped with `version`
+fn compatible_version(version: u16) -> bool {
+    version >> 8 == PROTOCOL_VERSION >> 8
+}
+
+/// How far sequence number `b` is ahead of `a`, negative if it's behind. Sequence numbers
+/// wrap, so whichever way round is shorter wins: anything up to half the number space ahead
+/// counts as newer.
+fn seq_distance(a: u64, b: u64) -> i64 {
+    b.wrapping_sub(a) as i64
+}
+
+/// Nanoseconds since the epoch, which grows faster than any server sends messages
+#[cfg(feature = "std")]
+fn initial_seq_no() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map_or(1, |d| d.as_nanos() as u64)
+}
 
-const PROTOCOL_VERSION: u16 = 1;
+/// Without a wall clock there's nothing to stay ahead of a previous run with, so peers that
+/// still remember us from before a restart drop our messages until they hear us at a higher
+/// incarnation, see [`Server::with_incarnation`]
+#[cfg(not(feature = "std"))]
+fn initial_seq_no() -> u64 {
+    1
+}
+/// Size of the rumor buffer in each message sent by [`Server::disseminate`]
+const GOSSIP_BUFFER_SIZE: usize = 1024;
+/// How many peers [`Server::disseminate`] gossips to by default
+const DEFAULT_GOSSIP_FANOUT: usize = 3;
+/// Broadcasts are sent `DEFAULT_RETRANSMIT_MULTIPLIER * log10(n)` times by default
+const DEFAULT_RETRANSMIT_MULTIPLIER: u32 = 3;
+/// How long a failed peer is remembered before it's forgotten entirely
+const DEFAULT_TOMBSTONE_TTL: Duration = Duration::from_secs(30);
+/// How long after leaving we wait for our departure to spread by default
+const DEFAULT_LEAVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Protocol periods within which a departure means losing every peer isn't losing the
+/// cluster, by default
+const DEFAULT_CLUSTER_LOSS_PERIODS: u32 = 10;
+/// Suspicion periods a departed peer is quarantined before it's forgotten entirely
+const QUARANTINE_SUSPICION_PERIODS: u32 = 3;
+/// Clusters up to this size push-pull their full state; bigger ones exchange digests
+const FULL_SYNC_MAX_MEMBERS: usize = 16;
+/// Protocol periods to wait for a seed to answer before pulling from them all again
+const SEED_RETRY_PERIODS: u32 = 5;
+/// Consecutive unanswered probes before we consider ourselves cut off from the cluster
+const ISOLATION_PROBES: usize = 3;
+/// Cap on how many times the seed retry interval doubles
+const MAX_SEED_BACKOFF: u32 = 4;
+/// How many of the latest failures [`Server::recent_failures`] remembers
+const FAILURE_HISTORY_LEN: usize = 32;
+/// Upper bound on [`Server::health_multiplier`]
+const MAX_HEALTH_MULTIPLIER: usize = 8;
 
-#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash)]
-pub struct PeerId(u32);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(u64);
 
 impl Display for PeerId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -35,19 +159,33 @@ impl PeerId {
         buf.extend_from_slice(&self.0.to_le_bytes());
     }
 
-    pub fn deserialize(bytes: [u8; 4]) -> Self {
-        PeerId(u32::from_le_bytes(bytes))
+    pub fn deserialize(bytes: [u8; 8]) -> Self {
+        PeerId(u64::from_le_bytes(bytes))
+    }
+
+    /// A fresh id, wide enough that a random one won't collide with anyone else's. Generate
+    /// it once and persist it so the node keeps its identity across restarts.
+    #[cfg(feature = "std")]
+    pub fn random() -> Self {
+        PeerId(rand::random())
     }
 }
 
-impl From<u32> for PeerId {
-    fn from(u: u32) -> Self {
+impl From<u64> for PeerId {
+    fn from(u: u64) -> Self {
         Self(u)
     }
 }
 
+impl From<PeerId> for u64 {
+    fn from(id: PeerId) -> Self {
+        id.0
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Clone, Copy, Eq, PartialOrd, Ord)]
-pub struct Incarnation(u32);
+pub struct Incarnation(u64);
 
 impl Display for Incarnation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -64,56 +202,103 @@ impl Incarnation {
         buf.extend_from_slice(&self.0.to_le_bytes());
     }
 
-    pub fn deserialize(bytes: [u8; 4]) -> Self {
-        Incarnation(u32::from_le_bytes(bytes))
+    pub fn deserialize(bytes: [u8; 8]) -> Self {
+        Incarnation(u64::from_le_bytes(bytes))
     }
 }
 
-impl From<u32> for Incarnation {
-    fn from(u: u32) -> Self {
+impl From<u64> for Incarnation {
+    fn from(u: u64) -> Self {
         Self(u)
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum PingState {
+/// Where a pending ping stands, see [`PendingPingState`]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PingState {
+    /// One of our own probes, awaiting a direct ack
     Normal,
+    /// One of our own probes that went unanswered, so we've asked others to ping on our behalf
     Forwarded,
+    /// Sent on behalf of the requester of an indirect probe
     FromElsewhere,
 }
 
 #[derive(Debug)]
 struct PendingPing {
     addr: SocketAddr,
-    seq_no: usize,
+    seq_no: u64,
     requester: PeerId,
+    /// Sequence number the requester expects echoed back in our ack
+    requester_seq_no: u64,
     state: PingState,
     sent_at: Instant,
+    /// How many peers we asked to ping on our behalf
+    helpers: usize,
+    /// How many of those couldn't reach the target either
+    nacks: usize,
+    /// Set once the probe has gone a whole protocol period unanswered
+    missed: bool,
+}
+
+impl PendingPing {
+    /// When every helper confirms the target is unreachable there's no point waiting out
+    /// the full suspicion period
+    fn shorten_suspicion(&self, timeout: Duration) -> Duration {
+        if self.helpers > 0 && self.nacks >= self.helpers {
+            timeout / 2
+        } else {
+            timeout
+        }
+    }
 }
 
+/// A running suspicion timer: unless refuted, the suspect is declared failed when it expires
+#[derive(Debug)]
+struct Suspicion {
+    started: Instant,
+    /// Peers known to independently suspect the same peer
+    suspectors: HashSet<PeerId>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum PeerState {
     Alive,
     Suspect,
     Failed,
+    Departed,
+}
+
+impl PeerState {
+    /// Whether peers in this state are still part of our probe rotation
+    fn is_probed(self) -> bool {
+        matches!(self, PeerState::Alive | PeerState::Suspect)
+    }
 }
 
 impl From<RumorKind> for PeerState {
     fn from(rk: RumorKind) -> Self {
         match rk {
-            RumorKind::Alive(_) => PeerState::Alive,
-            RumorKind::Suspect => PeerState::Suspect,
+            RumorKind::Alive { .. } => PeerState::Alive,
+            RumorKind::Suspect { .. } => PeerState::Suspect,
             RumorKind::Failed => PeerState::Failed,
+            RumorKind::Depart => PeerState::Departed,
+            RumorKind::User { .. } => unreachable!("user rumors don't describe peer state"),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Peer {
     id: PeerId,
+    #[cfg_attr(feature = "serde", serde(with = "crate::socket_addr"))]
     addr: SocketAddr,
     state: PeerState,
     incarnation: Incarnation,
+    meta: Vec<u8>,
 }
 
 impl Peer {
@@ -123,27 +308,159 @@ impl Peer {
             addr,
             state,
             incarnation,
+            meta: Vec::new(),
         }
     }
 
-    fn rumor_kind(&self) -> RumorKind {
+    /// The same peer somewhere else, for a [`PeerFilter`] remapping addresses
+    pub fn with_addr(self, addr: SocketAddr) -> Peer {
+        Peer { addr, ..self }
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn state(&self) -> PeerState {
+        self.state
+    }
+
+    pub fn incarnation(&self) -> Incarnation {
+        self.incarnation
+    }
+
+    /// Whatever the peer attached to its Alive rumors, see [`Server::set_meta`]
+    pub fn meta(&self) -> &[u8] {
+        &self.meta
+    }
+
+    /// `suspector` is who we credit if this peer is suspect
+    fn rumor_kind(&self, suspector: PeerId) -> RumorKind {
         match self.state {
-            PeerState::Alive => RumorKind::Alive(self.addr),
+            PeerState::Alive => RumorKind::Alive {
+                addr: self.addr,
+                meta: self.meta.clone(),
+            },
             PeerState::Failed => RumorKind::Failed,
-            PeerState::Suspect => RumorKind::Suspect,
+            PeerState::Suspect => RumorKind::Suspect { from: suspector },
+            PeerState::Departed => RumorKind::Depart,
         }
     }
 
     /// Create a rumor about this peer's current state
-    fn rumor(&self) -> Rumor {
+    fn rumor(&self, suspector: PeerId) -> Rumor {
         Rumor {
             peer_id: self.id,
             incarnation: self.incarnation,
-            kind: self.rumor_kind(),
+            kind: self.rumor_kind(suspector),
+        }
+    }
+}
+
+/// Whether we'd rather ask `helper` to ping `target` for us, see
+/// [`Server::with_helper_preference`]
+pub type HelperPreference = Box<dyn Fn(&Peer, &Peer) -> bool>;
+
+/// How [`Server::probe`] picks whom to ping, see [`Server::with_probe_selection`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProbeSelection {
+    /// Work through the membership in a random order, reshuffling after each pass. Every
+    /// peer is probed once per pass, but one that joins may wait nearly a pass for its first.
+    #[default]
+    Shuffled,
+    /// Ping whoever we've gone longest without probing, peers we never have first
+    LeastRecentlyProbed,
+}
+
+/// What a peer's state is without where it lives, for cheaply comparing memberships
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct PeerDigest {
+    pub id: PeerId,
+    pub incarnation: Incarnation,
+    pub state: PeerState,
+}
+
+impl From<&Peer> for PeerDigest {
+    fn from(peer: &Peer) -> Self {
+        PeerDigest {
+            id: peer.id,
+            incarnation: peer.incarnation,
+            state: peer.state,
         }
     }
 }
 
+/// What a server knew of the cluster at some point, for restoring after a restart with
+/// [`Server::restore`] instead of bootstrapping from seeds again
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
+pub struct MembershipSnapshot {
+    /// The snapshotted server's own incarnation
+    pub incarnation: Incarnation,
+    /// Every other peer it knew of
+    pub peers: Vec<Peer>,
+}
+
+/// Everything a server knows, from [`Server::debug_state`], for post-mortems and for diffing
+/// two servers' views of the cluster
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
+pub struct DebugState {
+    pub id: PeerId,
+    pub incarnation: Incarnation,
+    /// The sequence number our next message will use
+    pub seq_no: u64,
+    /// Every peer we know of but ourselves, by id
+    pub membership: Vec<Peer>,
+    /// The probe rotation, which is reshuffled each time we reach its end
+    pub probe_order: Vec<PeerId>,
+    /// Index of the next peer to probe in `probe_order`
+    pub next_probe: usize,
+    /// Pings awaiting an ack, by target
+    pub pings: Vec<PendingPingState>,
+    /// Rumors waiting to be gossiped, in the order they'll go out
+    pub broadcasts: Vec<QueuedBroadcast>,
+}
+
+/// A ping awaiting an ack, see [`DebugState`]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
+pub struct PendingPingState {
+    pub target: PeerId,
+    pub seq_no: u64,
+    pub state: PingState,
+    pub requester: PeerId,
+    /// How long ago the ping was sent
+    pub age: Duration,
+}
+
+/// A rumor waiting to be gossiped, see [`DebugState`]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
+pub struct QueuedBroadcast {
+    pub rumor: Rumor,
+    /// How many times it's gone out so far
+    pub sends: usize,
+}
+
+#[cfg(feature = "bincode")]
+impl MembershipSnapshot {
+    /// Encode this snapshot for storage
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(self)
+    }
+
+    /// Decode a snapshot produced by [`MembershipSnapshot::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+}
+
 impl fmt::Display for Peer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -155,54 +472,360 @@ impl fmt::Display for Peer {
 }
 
 /// Failure Detector messages. These piggy-back higher level data
-#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
 pub enum MsgKind {
     Ping,
     Ack(PeerId, Incarnation),
+    /// Ping the target for the sender. We do so even if we've never heard of the target,
+    /// since the sender gave us its address, but answering us doesn't make it a member;
+    /// that's left to its own gossip.
     PingReq {
         target_id: PeerId,
+        #[cfg_attr(feature = "serde", serde(with = "crate::socket_addr"))]
         target: SocketAddr,
     },
+    /// Sent back to a PingReq's requester when we couldn't reach the target either
+    Nack {
+        target_id: PeerId,
+    },
     Push(Vec<Peer>),
     Pull(Vec<Peer>),
+    /// Like a Pull, but only answered with the peers the digest is missing or stale about
+    PullDigest(Vec<PeerDigest>),
+    /// Rumors on their own, in the format [`Server::process_gossip`] reads
+    Gossip(Vec<u8>),
 }
 
-#[derive(Debug)]
+impl MsgKind {
+    /// Whether this should go over a reliable, stream-oriented transport when there is one.
+    /// Anti-entropy carries whole membership lists that can outgrow a datagram, while
+    /// probes are small and are better off lost than late.
+    pub fn prefers_reliable(&self) -> bool {
+        matches!(
+            self,
+            MsgKind::Push(_) | MsgKind::Pull(_) | MsgKind::PullDigest(_)
+        )
+    }
+
+    /// The variant's name, for tracing without the payload
+    #[cfg(feature = "tracing")]
+    fn name(&self) -> &'static str {
+        match self {
+            MsgKind::Ping => "Ping",
+            MsgKind::Ack(..) => "Ack",
+            MsgKind::PingReq { .. } => "PingReq",
+            MsgKind::Nack { .. } => "Nack",
+            MsgKind::Push(_) => "Push",
+            MsgKind::Pull(_) => "Pull",
+            MsgKind::PullDigest(_) => "PullDigest",
+            MsgKind::Gossip(_) => "Gossip",
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Message {
     pub protocol_version: u16,
+    /// Which cluster sent this, see [`Server::with_cluster_label`]
+    pub cluster_label: u64,
     pub dest_id: PeerId,
+    #[cfg_attr(feature = "serde", serde(with = "crate::socket_addr"))]
     pub dest_addr: SocketAddr,
     pub src_id: PeerId,
+    #[cfg_attr(feature = "serde", serde(with = "crate::socket_addr"))]
     pub src_addr: SocketAddr,
-    pub seq_no: usize,
+    pub seq_no: u64,
     pub kind: MsgKind,
+    /// HMAC of the rest of the message and its gossip, or empty when the sender has no secret
+    /// key. See `Server::with_secret_key` under the `auth` feature.
+    pub mac: Vec<u8>,
+}
+
+#[cfg(feature = "bincode")]
+impl Message {
+    /// Encode this message for the wire.
+    ///
+    /// Gossip isn't part of the message; it's already serialized by [`Server::piggyback`] and
+    /// should be sent alongside these bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(self)
+    }
+
+    /// Decode a message produced by [`Message::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+
+    /// How many bytes [`Message::to_bytes`] produces, worked out without encoding anything,
+    /// so a transport can tell what fits in a datagram before building it
+    pub fn estimated_size(&self) -> usize {
+        use core::mem::size_of;
+        // bincode writes fixed-width integers, a u32 for each enum variant and a u64 for
+        // each length
+        const VARIANT: usize = 4;
+        const LEN: usize = 8;
+        let id = size_of::<PeerId>();
+        let incarnation = size_of::<Incarnation>();
+        let addr = |addr: &SocketAddr| {
+            VARIANT
+                + match addr {
+                    // ip, port
+                    SocketAddr::V4(_) => 4 + 2,
+                    // ip, port, flowinfo, scope id
+                    SocketAddr::V6(_) => 16 + 2 + 4 + 4,
+                }
+        };
+        let peers = |peers: &[Peer]| {
+            LEN + peers
+                .iter()
+                .map(|p| id + addr(&p.addr) + VARIANT + incarnation + LEN + p.meta.len())
+                .sum::<usize>()
+        };
+        let kind = VARIANT
+            + match &self.kind {
+                MsgKind::Ping => 0,
+                MsgKind::Ack(..) => id + incarnation,
+                MsgKind::PingReq { target, .. } => id + addr(target),
+                MsgKind::Nack { .. } => id,
+                MsgKind::Push(p) | MsgKind::Pull(p) => peers(p),
+                MsgKind::PullDigest(digests) => LEN + digests.len() * (id + incarnation + VARIANT),
+                MsgKind::Gossip(rumors) => LEN + rumors.len(),
+            };
+        size_of::<u16>()
+            + size_of::<u64>()
+            + 2 * id
+            + addr(&self.dest_addr)
+            + addr(&self.src_addr)
+            + size_of::<u64>()
+            + kind
+            + LEN
+            + self.mac.len()
+    }
+}
+
+/// Application data received via [`Server::broadcast_user`]
+#[derive(Debug, PartialEq, Clone)]
+pub struct UserMessage {
+    pub from: PeerId,
+    pub tag: u8,
+    pub data: Vec<u8>,
+}
+
+/// Something the application may want to react to, returned from [`Server::process`],
+/// [`Server::probe`], [`Server::expire`] and [`Server::tick`]
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    /// We met a new peer
+    Joined(Peer),
+    StateChanged {
+        id: PeerId,
+        from: PeerState,
+        to: PeerState,
+    },
+    UserMessage(UserMessage),
+    /// A peer left the cluster
+    Departed(PeerId),
+    /// Every peer we could reach has failed, and none left gracefully lately, so we're more
+    /// likely cut off by a partition than the last one standing. Applications that mustn't
+    /// act alone should stop serving until peers come back. Lists the peers that failed
+    /// within [`Server::with_cluster_loss_periods`], newest first.
+    ClusterLost(Vec<PeerId>),
+    /// Someone suspected us or declared us failed, and we refuted it at this incarnation
+    SelfSuspected(Incarnation),
 }
 
-pub struct Server {
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum BroadcastError {
+    #[error("user data of {0} bytes exceeds the {MAX_USER_DATA} byte limit")]
+    TooLarge(usize),
+    #[error("metadata of {0} bytes exceeds the {MAX_META} byte limit")]
+    MetaTooLarge(usize),
+    #[error("the broadcast queue is full of more pressing news")]
+    QueueFull,
+}
+
+/// Why [`Server::process`] dropped a message
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum ProcessError {
+    #[error("message for {0} delivered to the wrong server")]
+    WrongRecipient(PeerId),
+    #[error("message from {0} failed authentication")]
+    Unauthenticated(PeerId),
+    #[error("message from foreign cluster {0}")]
+    ForeignCluster(u64),
+    #[error("incompatible protocol version {0:#06x}")]
+    IncompatibleVersion(u16),
+    #[error("message from {0} replayed sequence number {1}")]
+    Replayed(PeerId, u64),
+}
+
+pub struct Server<T = VecTransport> {
     pub id: PeerId,
     addr: SocketAddr,
-    seq_no: usize,
+    /// Stamped on everything but acks and nacks, which echo the ping's. Starts from the wall
+    /// clock so that after a restart we're still ahead of what peers last saw from us.
+    seq_no: u64,
+    /// Sequence numbers each member has recently sent us, so replays can be dropped
+    replay_windows: HashMap<PeerId, ReplayWindow>,
     incarnation: Incarnation,
+    /// How many times we've refuted a peer suspecting us or declaring us failed
+    self_refutations: usize,
+    /// Set once we've left the cluster
+    departed: bool,
+    /// When we left, if we have since the last reset
+    left_at: Option<Instant>,
+    /// How long [`Server::is_departed_acknowledged`] waits for our departure to spread
+    leave_timeout: Duration,
+    /// Attached to our Alive rumors
+    meta: Vec<u8>,
+    /// How we'd describe ourselves to a peer, rebuilt whenever our incarnation, metadata or
+    /// departure changes
+    self_peer: Peer,
+    /// Stamped on everything we send; messages with any other label are dropped
+    cluster_label: u64,
     pingreq_subgroup_sz: usize,
+    /// How many random peers each round of dissemination reaches
+    gossip_fanout: usize,
+    /// Most gossip messages each of those peers is sent per round
+    max_gossip_messages: usize,
+    /// Most rumors piggybacked into one gossip buffer
+    max_piggyback: usize,
+    /// Most of each gossip buffer user broadcasts may fill, as a fraction
+    user_gossip_fraction: f64,
+    /// Whether membership changes are gossiped, or left for the application to spread
+    disseminate_membership: bool,
+    /// Most peers sent in one Push
+    push_sample_size: usize,
+    /// Scales how many times each broadcast is sent
+    retransmit_multiplier: u32,
+    /// How many times each broadcast is sent, recomputed whenever the membership changes size
+    max_sends: usize,
     ping_interval: Duration,
     protocol_period: Duration,
+    /// The suspicion period in a cluster of up to 8, scaled up with the cluster's size
+    base_suspicion: Duration,
     suspicion_period: Duration,
+    /// The size-scaled suspicion period is clamped to these
+    min_suspicion: Duration,
+    max_suspicion: Duration,
     broadcasts: BroadcastStore,
-    pings: HashMap<PeerId, PendingPing>,
+    /// Rumors are retired after this many protocol periods, if set
+    broadcast_max_age: Option<u32>,
+    /// (target, seq_no) -> ping awaiting an ack. A peer can have several in flight.
+    pings: HashMap<(PeerId, u64), PendingPing>,
     // Index into memberlist
     last_pinged: usize,
+    /// How many distinct peers each probe pings
+    probes_per_period: usize,
+    probe_selection: ProbeSelection,
+    /// When we last pinged each peer as part of our own probes
+    last_probed: HashMap<PeerId, Instant>,
+    /// When `probe` or `expire` last ran
+    last_tick: Option<Instant>,
+    /// When the next probe is due, a protocol period after the last give or take jitter
+    next_probe: Option<Instant>,
+    /// How far each probe may stray from the protocol period, as a fraction of it
+    probe_jitter: f64,
     memberlist: Vec<PeerId>,
     /// Node id -> (State, timestamp the state was updated)
     membership: HashMap<PeerId, Peer>,
+    /// Failed peers -> when we learned they failed. While tombstoned, a peer can only come
+    /// back with a higher incarnation; once the TTL passes it's forgotten.
+    tombstones: HashMap<PeerId, Instant>,
+    /// The latest peers to fail and when we learned of it, newest first. Unlike tombstones
+    /// these outlive the peer rejoining or being forgotten.
+    failures: VecDeque<(PeerId, Instant)>,
+    /// When a peer we could reach last left gracefully or was evicted
+    last_departure: Option<Instant>,
+    /// See [`Server::with_cluster_loss_periods`]
+    cluster_loss_periods: u32,
+    /// Peers we heard had failed before we ever met them -> (incarnation, when we heard).
+    /// They're kept out until they come back with a higher incarnation or the TTL passes.
+    unknown_failed: HashMap<PeerId, (Incarnation, Instant)>,
+    tombstone_ttl: Duration,
+    /// Departed peers -> (incarnation they left at, when we heard). Stale gossip can't bring
+    /// them back; only an Alive rumor at a higher incarnation, from a genuine rejoin, can.
+    /// Once a few suspicion periods pass they're forgotten.
+    quarantine: HashMap<PeerId, (Incarnation, Instant)>,
+    /// Seeds we're bootstrapping from, see [`Server::join_seeds`]
+    seeds: Vec<(PeerId, SocketAddr)>,
+    /// When we last pulled from the seeds
+    seeds_pulled_at: Option<Instant>,
+    /// Seeds that have answered
+    seeds_responded: HashSet<PeerId>,
+    /// How many times in a row we've pulled from the seeds without rejoining
+    seed_backoff: u32,
+    /// Our own probes that went unanswered since the last one that was acked
+    consecutive_failed_probes: usize,
+    /// When each peer last changed state
+    last_changed: HashMap<PeerId, Instant>,
+    /// Peer -> (latest round-trip time, when they last acked one of our pings)
+    responsiveness: HashMap<PeerId, (Option<Duration>, Instant)>,
+    suspicions: HashMap<PeerId, Suspicion>,
+    /// (min, max) for Lifeguard's adaptive suspicion timeout
+    suspicion_bounds: Option<(Duration, Duration)>,
+    /// Lifeguard's local health multiplier, see [`Server::health_multiplier`]
+    health_multiplier: usize,
+    /// Suspect peers once phi passes this, see [`Server::with_phi_threshold`]
+    phi_threshold: Option<f64>,
+    /// When each peer has acked our pings, for the phi-accrual detector
+    arrivals: HashMap<PeerId, ArrivalWindow>,
+    transport: T,
+    clock: Box<dyn Clock>,
+    delegate: Box<dyn MembershipDelegate>,
+    /// Vets peers before we let them in, see [`Server::with_peer_filter`]
+    peer_filter: Box<dyn PeerFilter>,
+    metrics: Box<dyn Metrics>,
+    /// Traffic so far, see [`Server::stats`]. The queue lengths are filled in on demand.
+    stats: Stats,
+    /// Picks out peers we'd rather ask to ping a target for us, see
+    /// [`Server::with_helper_preference`]
+    helper_preference: Option<HelperPreference>,
+    /// Whether peers are asked to ping a target that didn't answer us directly
+    indirect_probes: bool,
+    /// Whether new peers join the end of the probe rotation rather than a random spot
+    deterministic_insert: bool,
+    /// Source of all randomness, from where new peers land in the probe order to which
+    /// peers we gossip with
+    rng: Box<dyn RngCore>,
+    /// User broadcasts waiting to be handed to the application
+    user_messages: Vec<UserMessage>,
+    /// User broadcasts we've sent or handed over -> when we last heard them, so one that keeps
+    /// being gossiped back to us is only delivered once. Forgotten once they've gone quiet for
+    /// a quarantine period; unlike the broadcast queue they survive a reset.
+    delivered: HashMap<Subject, Instant>,
+    /// Messages are signed with the first and accepted under any, see
+    /// [`Server::with_secret_key`]
+    #[cfg(feature = "auth")]
+    secret_keys: Vec<Vec<u8>>,
+    /// Bytes are encrypted with the first and decrypted with any, see
+    /// [`Server::with_encryption_key`]
+    #[cfg(feature = "encryption")]
+    encryption_keys: Vec<[u8; 32]>,
+    /// Payloads at least this large are compressed, see [`Server::with_compression_threshold`]
+    #[cfg(feature = "compression")]
+    compression_threshold: usize,
+    /// Waiting to be returned from the next `process` or `probe`
+    events: Vec<Event>,
 }
 
-impl Display for Server {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl<T> Display for Server<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Server({}, {})", self.id, self.incarnation)
     }
 }
 
-impl Server {
+impl Server<VecTransport> {
+    /// Configure a server by name, see [`ServerBuilder`]
+    pub fn builder(id: PeerId, addr: SocketAddr) -> ServerBuilder {
+        ServerBuilder::new(id, addr)
+    }
+
+    /// Suspicions last `suspicion_period` in a cluster of up to 8 and grow with `log10` of
+    /// the cluster's size beyond that, see [`Server::suspicion_period`]. The SWIM paper
+    /// suggests 3 protocol periods.
     pub fn new(
         id: PeerId,
         addr: SocketAddr,
@@ -211,476 +834,5948 @@ impl Server {
         protocol_period: Duration,
         suspicion_period: Duration,
     ) -> Self {
-        Server {
+        Server::with_transport(
+            id,
+            addr,
+            ping_interval,
+            pingreq_subgroup_sz,
+            protocol_period,
+            suspicion_period,
+            VecTransport::new(),
+        )
+    }
+
+    /// Take every message sent since the last call
+    pub fn outbox(&mut self) -> Vec<Message> {
+        self.transport.drain()
+    }
+
+    /// Hold at most `max` messages between calls to [`Server::outbox`], dropping the least
+    /// important once full, see [`VecTransport::bounded`]. Unbounded by default.
+    pub fn with_max_outbox(mut self, max: usize) -> Self {
+        self.transport = VecTransport::bounded(max);
+        self
+    }
+}
+
+impl<T: Transport> Server<T> {
+    pub fn with_transport(
+        id: PeerId,
+        addr: SocketAddr,
+        ping_interval: Duration,
+        pingreq_subgroup_sz: usize,
+        protocol_period: Duration,
+        suspicion_period: Duration,
+        transport: T,
+    ) -> Self {
+        let mut server = Server {
             id,
             addr,
             pingreq_subgroup_sz,
+            gossip_fanout: DEFAULT_GOSSIP_FANOUT,
+            max_gossip_messages: 1,
+            max_piggyback: usize::MAX,
+            user_gossip_fraction: 1.0,
+            disseminate_membership: true,
+            push_sample_size: usize::MAX,
+            retransmit_multiplier: DEFAULT_RETRANSMIT_MULTIPLIER,
+            max_sends: DEFAULT_RETRANSMIT_MULTIPLIER as usize,
             ping_interval,
             protocol_period,
+            base_suspicion: suspicion_period,
             suspicion_period,
-            seq_no: 1,
+            min_suspicion: Duration::ZERO,
+            max_suspicion: Duration::MAX,
+            seq_no: initial_seq_no(),
+            replay_windows: HashMap::new(),
             incarnation: Incarnation(1),
+            self_refutations: 0,
+            departed: false,
+            left_at: None,
+            leave_timeout: DEFAULT_LEAVE_TIMEOUT,
+            meta: Vec::new(),
+            self_peer: Peer::new(id, addr, Incarnation(1), PeerState::Alive),
+            cluster_label: 0,
             broadcasts: BroadcastStore::new(),
+            broadcast_max_age: None,
             pings: HashMap::new(),
             last_pinged: 0,
+            probes_per_period: 1,
+            probe_selection: ProbeSelection::Shuffled,
+            last_probed: HashMap::new(),
+            last_tick: None,
+            next_probe: None,
+            probe_jitter: 0.0,
             memberlist: Vec::new(),
             membership: HashMap::new(),
+            tombstones: HashMap::new(),
+            failures: VecDeque::new(),
+            last_departure: None,
+            cluster_loss_periods: DEFAULT_CLUSTER_LOSS_PERIODS,
+            unknown_failed: HashMap::new(),
+            quarantine: HashMap::new(),
+            tombstone_ttl: DEFAULT_TOMBSTONE_TTL,
+            seeds: Vec::new(),
+            seeds_pulled_at: None,
+            seeds_responded: HashSet::new(),
+            seed_backoff: 0,
+            consecutive_failed_probes: 0,
+            last_changed: HashMap::new(),
+            responsiveness: HashMap::new(),
+            suspicions: HashMap::new(),
+            suspicion_bounds: None,
+            health_multiplier: 0,
+            phi_threshold: None,
+            arrivals: HashMap::new(),
+            transport,
+            clock: Box::new(DefaultClock::default()),
+            delegate: Box::new(NoopDelegate),
+            peer_filter: Box::new(AdmitAll),
+            metrics: Box::new(NoopMetrics),
+            stats: Stats::default(),
+            helper_preference: None,
+            indirect_probes: true,
+            deterministic_insert: false,
+            rng: default_rng(id),
+            user_messages: Vec::new(),
+            delivered: HashMap::new(),
+            #[cfg(feature = "auth")]
+            secret_keys: Vec::new(),
+            #[cfg(feature = "encryption")]
+            encryption_keys: Vec::new(),
+            #[cfg(feature = "compression")]
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            events: Vec::new(),
+        };
+        // Announce ourselves with the first gossip, before anyone who half-remembers us from
+        // a previous run gets around to suspecting us
+        server.spread_alive();
+        server
+    }
+
+    /// Resume from the incarnation we had before restarting, announcing ourselves alive at the
+    /// next one so that whatever the cluster last heard about us, suspicions included, is
+    /// refuted straight away. Starting over at 1 instead would leave us unable to outrank
+    /// rumors from before the restart.
+    ///
+    /// To restart safely, persist the incarnation every time it increases, which
+    /// [`MembershipDelegate::on_incarnation`] is told about, and pass the last one persisted
+    /// here.
+    pub fn with_incarnation(mut self, incarnation: Incarnation) -> Self {
+        self.incarnation = incarnation;
+        self.announce();
+        self
+    }
+
+    /// Replace the clock used to time pings
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Replace the source of randomness, e.g. with a seeded RNG so that probe order and peer
+    /// selection are reproducible
+    pub fn with_rng(mut self, rng: impl RngCore + 'static) -> Self {
+        self.rng = Box::new(rng);
+        self
+    }
+
+    /// Set how many random peers [`Server::disseminate`] gossips to each round (3 by default).
+    /// Zero leaves rumors to ride along on other messages.
+    pub fn with_gossip_fanout(mut self, fanout: usize) -> Self {
+        self.gossip_fanout = fanout;
+        self
+    }
+
+    /// Let [`Server::disseminate`] send each peer up to `max` gossip messages a round rather
+    /// than one, so a backlog of broadcasts after a big membership change drains in fewer
+    /// periods. Each message carries different rumors, and no more are sent than the queue
+    /// fills, but every extra message is bandwidth: this caps how much a round may use.
+    pub fn with_max_gossip_messages(mut self, max: usize) -> Self {
+        self.max_gossip_messages = max;
+        self
+    }
+
+    /// Sign everything we send with a keyed HMAC and drop whatever arrives without a valid
+    /// one, so that only servers sharing a key can change our view of the cluster. Called
+    /// more than once, messages are still signed with the first key but accepted under any
+    /// of them, which lets keys be rotated: add the new key everywhere, then make it the
+    /// first with [`Server::set_secret_keys`], then drop the old one.
+    #[cfg(feature = "auth")]
+    pub fn with_secret_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.secret_keys.push(key.into());
+        self
+    }
+
+    /// Replace our keys, signing with the first, see [`Server::with_secret_key`]. With none,
+    /// we neither sign nor check anything.
+    #[cfg(feature = "auth")]
+    pub fn set_secret_keys(&mut self, keys: Vec<Vec<u8>>) {
+        self.secret_keys = keys;
+    }
+
+    /// Encrypt what we send with ChaCha20-Poly1305 under a 256-bit key. Encryption applies to
+    /// serialized messages, so it's up to the transport to [`Server::seal`] what it sends and
+    /// [`Server::open`] what it receives. Keys rotate like `Server::with_secret_key`'s: we
+    /// encrypt with the first and decrypt with whichever works.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption_keys.push(key);
+        self
+    }
+
+    /// Replace our encryption keys, encrypting with the first, see
+    /// [`Server::with_encryption_key`]. With none, bytes pass through as they are.
+    #[cfg(feature = "encryption")]
+    pub fn set_encryption_keys(&mut self, keys: Vec<[u8; 32]>) {
+        self.encryption_keys = keys;
+    }
+
+    /// Encrypt serialized bytes for the wire with a fresh nonce, adding
+    /// [`ENCRYPTION_OVERHEAD`] bytes, unless we have no encryption key
+    #[cfg(feature = "encryption")]
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        encryption::seal(&self.encryption_keys, &mut self.rng, plaintext)
+    }
+
+    /// Decrypt bytes from the wire with whichever of our keys authenticates them. Bytes that
+    /// none do are counted and give `None`. Without keys, bytes pass through as they are.
+    #[cfg(feature = "encryption")]
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = encryption::open(&self.encryption_keys, sealed);
+        if plaintext.is_none() {
+            warn!("{:03} couldn't decrypt {} bytes", self.id, sealed.len());
+            self.metrics.incr_undecryptable_messages();
+        }
+        plaintext
+    }
+
+    /// Have [`Server::compress`] leave payloads smaller than `threshold` bytes as they are,
+    /// rather than [`DEFAULT_COMPRESSION_THRESHOLD`]. Compression is per message, so peers
+    /// with different thresholds still understand each other.
+    #[cfg(feature = "compression")]
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Flag serialized bytes for the wire, compressing them if they're at least our
+    /// compression threshold and it makes them smaller. Adds [`COMPRESSION_OVERHEAD`] bytes
+    /// at most.
+    #[cfg(feature = "compression")]
+    pub fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        compression::compress(self.compression_threshold, bytes)
+    }
+
+    /// Undo [`Server::compress`] on bytes from the wire. Bytes that don't decompress are
+    /// counted and give `None`.
+    #[cfg(feature = "compression")]
+    pub fn decompress(&self, flagged: &[u8]) -> Option<Vec<u8>> {
+        let bytes = compression::decompress(flagged);
+        if bytes.is_none() {
+            warn!("{:03} couldn't decompress {} bytes", self.id, flagged.len());
+            self.metrics.incr_undecompressable_messages();
+        }
+        bytes
+    }
+
+    /// Only talk to servers with the same label (0 by default), so clusters sharing a network
+    /// can't pollute each other's membership
+    pub fn with_cluster_label(mut self, label: u64) -> Self {
+        self.cluster_label = label;
+        self
+    }
+
+    /// Prefer some peers over others when asking for help probing a target, e.g. those in the
+    /// target's zone according to their metadata. `prefer(target, helper)` is asked about each
+    /// candidate; preferred helpers are chosen first and the rest of the subgroup is filled at
+    /// random. Helpers are chosen uniformly at random by default.
+    pub fn with_helper_preference(
+        mut self,
+        prefer: impl Fn(&Peer, &Peer) -> bool + 'static,
+    ) -> Self {
+        self.helper_preference = Some(Box::new(prefer));
+        self
+    }
+
+    /// Suspect peers as soon as a direct ping times out, without asking others to ping them
+    /// for us. Useful in tiny clusters, or to tell whether indirect probes are hiding a real
+    /// connectivity problem. We still answer other peers' ping requests. On by default.
+    pub fn with_indirect_probes(mut self, enabled: bool) -> Self {
+        self.indirect_probes = enabled;
+        self
+    }
+
+    /// Replace the sink for metrics
+    pub fn with_metrics(mut self, metrics: impl Metrics + 'static) -> Self {
+        self.metrics = Box::new(metrics);
+        self
+    }
+
+    /// Limit how many rumors [`Server::gossip`] packs into one buffer. Only the buffer's size
+    /// limits it by default.
+    pub fn with_max_piggyback(mut self, max_piggyback: usize) -> Self {
+        self.max_piggyback = max_piggyback;
+        self
+    }
+
+    /// Let user broadcasts fill at most `fraction` of each buffer [`Server::gossip`] packs,
+    /// keeping the rest for membership news even when none is queued yet. User data already
+    /// goes after membership rumors; this also stops it from crowding them out of later
+    /// buffers. No limit by default.
+    pub fn with_user_gossip_fraction(mut self, fraction: f64) -> Self {
+        self.user_gossip_fraction = fraction;
+        self
+    }
+
+    /// Keep membership changes to ourselves, using SWIM purely as a failure detector while
+    /// the application spreads membership some other way. Local state and events still
+    /// follow what we detect, but [`Server::gossip`] only carries user broadcasts. On by
+    /// default.
+    pub fn with_disseminate_membership(mut self, enabled: bool) -> Self {
+        self.disseminate_membership = enabled;
+        if !enabled {
+            // Our own announcement from startup included
+            self.broadcasts.clear();
         }
+        self
+    }
+
+    /// Answer pulls with at most `size` peers: the most recently changed half, then a random
+    /// sample of the rest. Anti-entropy then takes a few rounds to converge in big clusters
+    /// instead of one oversized message. Unbounded by default.
+    pub fn with_push_sample_size(mut self, size: usize) -> Self {
+        self.push_sample_size = size;
+        self
+    }
+
+    /// Send each broadcast `multiplier * ceil(log10(n))` times in a cluster of `n` (3 by
+    /// default)
+    pub fn with_retransmit_multiplier(mut self, multiplier: u32) -> Self {
+        self.retransmit_multiplier = multiplier;
+        self.resized();
+        self
+    }
+
+    /// Never let the suspicion period, which grows with `log10(n)` protocol periods in a
+    /// cluster of `n`, drop below `min`, so tiny clusters don't declare peers failed before
+    /// they've had a fair chance to refute
+    pub fn with_min_suspicion(mut self, min: Duration) -> Self {
+        self.min_suspicion = min;
+        self.resized();
+        self
+    }
+
+    /// Never let the suspicion period grow beyond `max`, so huge clusters still notice
+    /// failures promptly
+    pub fn with_max_suspicion(mut self, max: Duration) -> Self {
+        self.max_suspicion = max;
+        self.resized();
+        self
+    }
+
+    /// Replace the delegate told about membership changes
+    pub fn with_delegate(mut self, delegate: impl MembershipDelegate + 'static) -> Self {
+        self.delegate = Box::new(delegate);
+        self
+    }
+
+    /// Vet every peer we hear is alive before letting it in or updating where it is, whether
+    /// we heard through gossip, a Push or Pull, or from the peer itself. The filter can keep
+    /// peers out, e.g. those from an unexpected subnet, or rewrite their address, e.g. to
+    /// reach them through a NAT; the rewritten address is also what we gossip. Peers we
+    /// already know that it turns away can't refute suspicion, so they eventually fail.
+    /// Everyone is let in as they are by default.
+    pub fn with_peer_filter(mut self, filter: impl PeerFilter + 'static) -> Self {
+        self.peer_filter = Box::new(filter);
+        self
+    }
+
+    /// Adapt the suspicion timeout Lifeguard-style: start at `max` and shrink toward `min` as
+    /// other peers independently confirm a suspicion. Without this the timeout is the SWIM
+    /// suspicion period, which is all [`Server::with_min_suspicion`] and
+    /// [`Server::with_max_suspicion`] bound; they don't touch this range.
+    pub fn with_suspicion_timeout(mut self, min: Duration, max: Duration) -> Self {
+        self.suspicion_bounds = Some((min, max));
+        self
     }
 
-    fn ack(&mut self, node: PeerId, dest_id: PeerId, dest_addr: SocketAddr) -> Message {
+    /// Suspect peers with the phi-accrual failure detector instead of a fixed timeout: once a
+    /// peer has acked a few of our pings, it's suspected when the silence since its last ack
+    /// is so unusual, given the gaps between its earlier acks, that the odds of an ack still
+    /// arriving fall below `10^-threshold`. Peers we haven't heard from enough yet are still
+    /// suspected when a probe times out. 8 is a reasonable threshold; lower is quicker to
+    /// suspect.
+    pub fn with_phi_threshold(mut self, threshold: f64) -> Self {
+        self.phi_threshold = Some(threshold);
+        self
+    }
+
+    /// Ping `count` distinct peers each protocol period instead of one, so failures are
+    /// noticed sooner in large clusters at the cost of more probe traffic.
+    pub fn with_probes_per_period(mut self, count: usize) -> Self {
+        self.probes_per_period = count;
+        self
+    }
+
+    /// Add new peers to the end of the probe rotation instead of at a random spot in it, so
+    /// that which peer we probe when depends only on the order we learned of them and the
+    /// reshuffles between rotations. Handy for simulations and tests. It's slightly less fair:
+    /// a peer that joins early in a rotation waits for everyone else before its first probe,
+    /// and peers that join together are probed in that order. Off by default.
+    pub fn with_deterministic_insert(mut self, enabled: bool) -> Self {
+        self.deterministic_insert = enabled;
+        self
+    }
+
+    /// Choose how probe targets are picked. The default shuffled rotation probes everyone
+    /// evenly, while [`ProbeSelection::LeastRecentlyProbed`] gets to new peers sooner.
+    pub fn with_probe_selection(mut self, selection: ProbeSelection) -> Self {
+        self.probe_selection = selection;
+        self
+    }
+
+    /// Space probes a protocol period apart give or take up to `fraction` of it, drawn from
+    /// our RNG, so that servers started together don't keep probing in lockstep. No jitter by
+    /// default.
+    ///
+    /// A whole period or more of jitter could schedule probes in the past, so `fraction` is
+    /// clamped to `[0, 1)`, with NaN meaning none. [`ServerBuilder::probe_jitter`] rejects
+    /// such fractions instead.
+    pub fn with_probe_jitter(mut self, fraction: f64) -> Self {
+        self.probe_jitter = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0 - f64::EPSILON)
+        };
+        self
+    }
+
+    /// Set how long failed peers are remembered (30 seconds by default)
+    pub fn with_tombstone_ttl(mut self, ttl: Duration) -> Self {
+        self.tombstone_ttl = ttl;
+        self
+    }
+
+    /// Set how long after [`Server::leave`] we stop waiting for the departure to spread and
+    /// call it acknowledged anyway (5 seconds by default)
+    pub fn with_leave_timeout(mut self, timeout: Duration) -> Self {
+        self.leave_timeout = timeout;
+        self
+    }
+
+    /// Losing our last reachable peer to failure raises [`Event::ClusterLost`] unless a peer
+    /// departed or was evicted within this many protocol periods (10 by default), in which case
+    /// the cluster is more likely winding down on purpose.
+    pub fn with_cluster_loss_periods(mut self, periods: u32) -> Self {
+        self.cluster_loss_periods = periods;
+        self
+    }
+
+    /// Queue at most `capacity` broadcasts, dropping the most-sent (then oldest) once full.
+    /// Unbounded by default.
+    pub fn with_broadcast_capacity(mut self, capacity: usize) -> Self {
+        self.broadcasts.set_capacity(capacity);
+        self
+    }
+
+    /// Retire rumors that have been queued for longer than `periods` protocol periods, even if
+    /// they haven't been sent enough times yet. Kept until fully sent by default.
+    pub fn with_broadcast_max_age(mut self, periods: u32) -> Self {
+        self.broadcast_max_age = Some(periods);
+        self
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Take the next sequence number for a message or ping of our own
+    fn next_seq_no(&mut self) -> u64 {
         self.seq_no = self.seq_no.wrapping_add(1);
-        Message {
+        self.seq_no
+    }
+
+    /// Whether `msg` is new from its sender. Acks and nacks echo the sequence number of the
+    /// ping they answer and are matched against our pending pings instead. Strangers have no
+    /// window until they're members, see `track_seq_no`.
+    fn fresh(&mut self, msg: &Message) -> bool {
+        if matches!(msg.kind, MsgKind::Ack(..) | MsgKind::Nack { .. }) {
+            return true;
+        }
+        let accepted = match self.replay_windows.get_mut(&msg.src_id) {
+            Some(window) => window.accept(msg.seq_no),
+            None => return true,
+        };
+        // Its sequence numbers may have gone backwards with the restart. Once its new
+        // incarnation sinks in, a window starts over from here.
+        accepted || self.announces_restart(msg)
+    }
+
+    /// Whether `msg` is a Push or Pull describing its sender at a higher incarnation than we
+    /// know, as one rejoining after a restart sends
+    fn announces_restart(&self, msg: &Message) -> bool {
+        let known = match self.membership.get(&msg.src_id) {
+            Some(peer) => peer.incarnation,
+            None => return false,
+        };
+        match &msg.kind {
+            MsgKind::Push(peers) | MsgKind::Pull(peers) => peers
+                .iter()
+                .any(|p| p.id == msg.src_id && p.incarnation > known),
+            _ => false,
+        }
+    }
+
+    /// Start a replay window at `seq_no` for `peer_id` once it's a member. Only members get
+    /// one, so strangers and peers we turn away can't grow the map.
+    fn track_seq_no(&mut self, peer_id: PeerId, seq_no: u64) {
+        if self.membership.contains_key(&peer_id) {
+            self.replay_windows
+                .entry(peer_id)
+                .or_insert_with(|| ReplayWindow::new(seq_no));
+        }
+    }
+
+    fn send(&mut self, mut msg: Message) {
+        self.sign(&mut msg, &[]);
+        self.metrics.incr_messages_sent(&msg.kind);
+        self.stats.sent.incr(&msg.kind);
+        self.transport.send(&msg);
+        for _ in 0..self.transport.dropped() {
+            self.metrics.incr_dropped_messages();
+        }
+    }
+
+    /// Stamp `msg` with the HMAC of it and the `gossip` going with it, if we have a key
+    #[cfg(feature = "auth")]
+    fn sign(&self, msg: &mut Message, gossip: &[u8]) {
+        auth::sign(&self.secret_keys, msg, gossip);
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn sign(&self, _msg: &mut Message, _gossip: &[u8]) {}
+
+    /// Whether `msg` and its `gossip` were signed with one of our keys, if we have any
+    #[cfg(feature = "auth")]
+    fn authentic(&self, msg: &mut Message, gossip: &[u8]) -> bool {
+        auth::verify(&self.secret_keys, msg, gossip)
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn authentic(&self, _msg: &mut Message, _gossip: &[u8]) -> bool {
+        true
+    }
+
+    /// Update the gauges in our [`Metrics`]
+    fn report(&self) {
+        let suspects = self
+            .membership
+            .values()
+            .filter(|p| p.state == PeerState::Suspect)
+            .count();
+        self.metrics.set_alive_count(self.num_alive());
+        self.metrics.set_suspect_count(suspects);
+        self.metrics.set_broadcast_queue_len(self.broadcasts.len());
+    }
+
+    /// Acks echo the sequence number of the ping they answer and carry the acked node's
+    /// incarnation, which differs from ours when relaying an indirect ack.
+    fn ack(
+        &mut self,
+        node: PeerId,
+        incarnation: Incarnation,
+        dest_id: PeerId,
+        dest_addr: SocketAddr,
+        seq_no: u64,
+    ) {
+        self.send(Message {
             protocol_version: PROTOCOL_VERSION,
+            cluster_label: self.cluster_label,
             dest_id,
             dest_addr,
             src_id: self.id,
             src_addr: self.addr,
-            seq_no: self.seq_no,
-            kind: MsgKind::Ack(node, self.incarnation),
-        }
+            seq_no,
+            kind: MsgKind::Ack(node, incarnation),
+            mac: Vec::new(),
+        });
     }
 
-    fn ping(&mut self, target_id: PeerId, target_addr: SocketAddr, recipient: PeerId) -> Message {
+    /// Ping a peer at `now`, either as our own probe or on behalf of a `(requester, seq_no)`
+    /// that sent us a PingReq.
+    fn ping(
+        &mut self,
+        target_id: PeerId,
+        target_addr: SocketAddr,
+        requester: Option<(PeerId, u64)>,
+        now: Instant,
+    ) {
         assert_ne!(target_id, self.id, "Attempted to ping ourselves");
-        self.seq_no = self.seq_no.wrapping_add(1);
-        let state = if recipient != self.id {
-            PingState::FromElsewhere
-        } else {
-            PingState::Normal
+        self.next_seq_no();
+        let (recipient, requester_seq_no, state) = match requester {
+            Some((id, seq_no)) => (id, seq_no, PingState::FromElsewhere),
+            None => (self.id, self.seq_no, PingState::Normal),
         };
         debug!(
             "{:03} pinging {:03} on behalf of {:03}",
             self.id, target_id, recipient
         );
         self.pings.insert(
-            target_id,
+            (target_id, self.seq_no),
             PendingPing {
                 addr: target_addr,
                 seq_no: self.seq_no,
                 requester: recipient,
+                requester_seq_no,
                 state,
-                sent_at: Instant::now(),
+                sent_at: now,
+                helpers: 0,
+                nacks: 0,
+                missed: false,
             },
         );
-        Message {
+        self.send(Message {
             protocol_version: PROTOCOL_VERSION,
+            cluster_label: self.cluster_label,
             dest_id: target_id,
             dest_addr: target_addr,
             src_id: self.id,
             src_addr: self.addr,
             seq_no: self.seq_no,
             kind: MsgKind::Ping,
-        }
+            mac: Vec::new(),
+        });
     }
 
-    pub fn live_members(&self) -> Vec<Peer> {
-        let peer_self = Peer::new(self.id, self.addr, self.incarnation, PeerState::Alive);
-        let mut peers = Vec::with_capacity(1 + self.membership.len());
-        peers.push(peer_self);
-        for peer in self.membership.values() {
-            peers.push(peer.clone());
-        }
-        peers
-    }
+    /// Catch [`Server::self_peer`] up with our incarnation, metadata and departure
+    fn describe_self(&mut self) {
+        let state = if self.departed {
+            PeerState::Departed
+        } else {
+            PeerState::Alive
+        };
+        self.self_peer = Peer {
+            meta: self.meta.clone(),
+            ..Peer::new(self.id, self.addr, self.incarnation, state)
+        };
+    }
+
+    /// Everyone we know of, ourselves first, as owned copies. See [`Server::members`] to look
+    /// without cloning.
+    pub fn live_members(&self) -> Vec<Peer> {
+        self.members().cloned().collect()
+    }
+
+    /// Everyone we know of, whatever their state: ourselves first, then everyone else in no
+    /// particular order
+    pub fn members(&self) -> impl Iterator<Item = &Peer> + Clone {
+        core::iter::once(&self.self_peer).chain(self.membership.values())
+    }
+
+    /// The order we probe peers in. It's reshuffled each time we reach the end, and new
+    /// peers are slotted in at random.
+    pub fn probe_order(&self) -> &[PeerId] {
+        &self.memberlist
+    }
+
+    /// Who we'll probe next, unless we've reached the end of [`Server::probe_order`] and
+    /// will reshuffle first
+    pub fn next_probe_target(&self) -> Option<PeerId> {
+        match self.probe_selection {
+            ProbeSelection::Shuffled => self.memberlist.get(self.last_pinged).copied(),
+            ProbeSelection::LeastRecentlyProbed => self.least_recently_probed(&[]),
+        }
+    }
+
+    /// Whoever in the rotation but `skip` we've gone longest without probing. Ties, such as
+    /// peers we've never probed, go to whoever comes first in the rotation.
+    fn least_recently_probed(&self, skip: &[PeerId]) -> Option<PeerId> {
+        self.memberlist
+            .iter()
+            .filter(|id| !skip.contains(id))
+            .min_by_key(|id| self.last_probed.get(*id))
+            .copied()
+    }
+
+    /// Peers we currently suspect, in no particular order
+    pub fn suspects(&self) -> Vec<&Peer> {
+        self.membership
+            .values()
+            .filter(|p| p.state == PeerState::Suspect)
+            .collect()
+    }
+
+    /// The latest peers to fail and when we learned of it, newest first. Only the last 32
+    /// are kept, but they're kept even after the peer rejoins or is forgotten, so a
+    /// dashboard polling now and then doesn't miss a failure in between.
+    pub fn recent_failures(&self) -> impl Iterator<Item = (PeerId, Instant)> + '_ {
+        self.failures.iter().copied()
+    }
+
+    /// What we know about another peer
+    pub fn peer(&self, id: PeerId) -> Option<&Peer> {
+        self.membership.get(&id)
+    }
+
+    /// Whether we know of another peer, whatever its state
+    pub fn contains(&self, id: PeerId) -> bool {
+        self.membership.contains_key(&id)
+    }
+
+    /// Our own incarnation, which should be persisted whenever it changes, see
+    /// [`Server::with_incarnation`]
+    pub fn incarnation(&self) -> Incarnation {
+        self.incarnation
+    }
+
+    /// Every peer we know of, in any state, plus ourselves
+    pub fn num_members(&self) -> usize {
+        1 + self.membership.len()
+    }
+
+    /// Peers we believe are alive, counting ourselves unless we've left
+    pub fn num_alive(&self) -> usize {
+        let alive = self
+            .membership
+            .values()
+            .filter(|p| p.state == PeerState::Alive)
+            .count();
+        alive + usize::from(!self.departed)
+    }
+
+    /// Round-trip time of the latest ping the peer answered directly
+    pub fn peer_rtt(&self, id: PeerId) -> Option<Duration> {
+        self.responsiveness.get(&id).and_then(|(rtt, _)| *rtt)
+    }
+
+    /// When the peer last answered one of our pings, directly or through a helper
+    pub fn last_seen(&self, id: PeerId) -> Option<Instant> {
+        self.responsiveness.get(&id).map(|(_, at)| *at)
+    }
+
+    /// How suspicious a peer's silence is according to the phi-accrual detector, if it's
+    /// enabled and has heard from them enough to say. See [`Server::with_phi_threshold`].
+    pub fn phi(&self, id: PeerId) -> Option<f64> {
+        self.phi_threshold?;
+        let window = self.arrivals.get(&id).filter(|w| w.ready())?;
+        Some(window.phi(self.clock.now(), self.phi_min_std_dev()))
+    }
+
+    /// Ack intervals are assumed to vary by at least this much, so a perfectly regular peer
+    /// isn't suspected the instant it's late
+    fn phi_min_std_dev(&self) -> Duration {
+        self.protocol_period / 10
+    }
+
+    /// Whether the phi-accrual detector, rather than a probe timing out, decides when to
+    /// suspect `peer_id`
+    fn phi_decides(&self, peer_id: PeerId) -> bool {
+        self.phi_threshold.is_some() && self.arrivals.get(&peer_id).is_some_and(|w| w.ready())
+    }
+
+    /// How many broadcasts are waiting to be gossiped
+    pub fn broadcast_queue_len(&self) -> usize {
+        self.broadcasts.len()
+    }
+
+    /// How many messages of each kind we've sent and received since we were created, and
+    /// how much is queued up right now. Cheap enough to poll for a stats endpoint.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            broadcast_queue_len: self.broadcasts.len(),
+            pending_pings: self.pings.len(),
+            ..self.stats
+        }
+    }
+
+    /// Whether we're still gossiping news about `peer_id` at `incarnation`. It stops once
+    /// it's been sent as often as the cluster's size calls for, or newer news replaces it, so
+    /// this turning false is a sign the cluster has heard.
+    pub fn is_broadcasting(&self, peer_id: PeerId, incarnation: Incarnation) -> bool {
+        self.broadcasts.is_queued(peer_id, incarnation)
+    }
+
+    /// Whether we've left and our departure has been gossiped as often as the cluster's size
+    /// calls for, so shutting down now won't leave peers to notice we're gone by suspecting
+    /// us. Also true once [`Server::with_leave_timeout`] has passed since [`Server::leave`],
+    /// in case we can't reach anyone, or straight away when there's nobody to tell.
+    pub fn is_departed_acknowledged(&self) -> bool {
+        let left_at = match self.left_at {
+            Some(left_at) if self.departed => left_at,
+            _ => return false,
+        };
+        self.memberlist.is_empty()
+            || !self.is_broadcasting(self.id, self.incarnation)
+            || self.clock.now().saturating_duration_since(left_at) >= self.leave_timeout
+    }
 
     /// Apply new information to the specified peer state machine.
     fn upsert_peer(&mut self, peer_id: PeerId, incarnation: Incarnation, rumor_kind: RumorKind) {
         assert_ne!(peer_id, self.id, "We should handle ourselves elsewhere");
+        let rumor_kind = match rumor_kind {
+            // Gossip can't carry this much, so it came in a Push or Pull. Passing it along
+            // would corrupt every rumor after it.
+            RumorKind::Alive { meta, .. } if meta.len() > MAX_META => {
+                debug!(
+                    "{:03} dropped {:03} with {} bytes of metadata",
+                    self.id,
+                    peer_id,
+                    meta.len()
+                );
+                return;
+            }
+            RumorKind::Alive { addr, meta } => {
+                let peer = Peer {
+                    meta,
+                    ..Peer::new(peer_id, addr, incarnation, PeerState::Alive)
+                };
+                match self.peer_filter.admit(&peer) {
+                    Some(admitted) => RumorKind::Alive {
+                        addr: admitted.addr,
+                        meta: peer.meta,
+                    },
+                    None => {
+                        debug!("{:03} filtered out {}", self.id, peer);
+                        return;
+                    }
+                }
+            }
+            kind => kind,
+        };
         if let Some(peer) = self.membership.get_mut(&peer_id) {
-            if incarnation < peer.incarnation {
+            let news = Rumor {
+                peer_id,
+                incarnation,
+                kind: rumor_kind,
+            };
+            // SWIM precedence: Alive(i) overrides Alive/Suspect(j) when i > j,
+            // Suspect(i) overrides Alive(j) when i >= j and Suspect(j) when i > j,
+            // Failed overrides both at equal incarnation and Depart(i) overrides Alive(j) when
+            // i >= j and the rest always, so only rejoining at a later one undoes a departure.
+            if !news.supersedes(&peer.rumor(self.id)) {
                 return;
             }
-            peer.incarnation = incarnation;
-            let state = rumor_kind.into();
+            if incarnation > peer.incarnation {
+                // They may have restarted, sequence numbers and all
+                self.replay_windows.remove(&peer_id);
+            }
+            peer.incarnation = peer.incarnation.max(incarnation);
+            // Pass the news along as we heard it, suspector included
+            let news = Rumor {
+                incarnation: peer.incarnation,
+                ..news
+            };
+            if let RumorKind::Alive { addr, meta } = &news.kind {
+                // Rejoining peers may come back somewhere else, and anyone may have new metadata
+                peer.addr = *addr;
+                peer.meta.clone_from(meta);
+            }
+            let state = PeerState::from(news.kind.clone());
             if peer.state == state {
-                self.broadcasts.push(peer.rumor());
+                self.spread(news);
                 return;
             }
-            info!(
-                "{:03} update peer {:03}: {:?} -> {:?}",
-                self.id, peer.id, peer.state, state
+            // With tracing, `changed` records this as structured fields instead
+            #[cfg(not(feature = "tracing"))]
+            {
+                info!(
+                    "{:03} update peer {:03}: {:?} -> {:?}",
+                    self.id, peer.id, peer.state, state
+                );
+                if !peer.state.is_probed() && state.is_probed() {
+                    info!("{:03} peer {:03} rejoined", self.id, peer.id);
+                }
+            }
+            if state == PeerState::Departed {
+                self.pings.retain(|(id, _), _| *id != peer_id);
+                self.quarantine
+                    .insert(peer_id, (peer.incarnation, self.clock.now()));
+            } else {
+                self.quarantine.remove(&peer_id);
+            }
+            if state == PeerState::Failed {
+                let now = self.clock.now();
+                self.tombstones.insert(peer_id, now);
+                if self.failures.len() == FAILURE_HISTORY_LEN {
+                    self.failures.pop_back();
+                }
+                self.failures.push_front((peer_id, now));
+            } else {
+                self.tombstones.remove(&peer_id);
+            }
+            if state == PeerState::Suspect {
+                let suspicion = Suspicion {
+                    started: self.clock.now(),
+                    suspectors: HashSet::new(),
+                };
+                self.suspicions.insert(peer_id, suspicion);
+            } else {
+                self.suspicions.remove(&peer_id);
+            }
+            let old = peer.state;
+            self.set_member_state(peer_id, state);
+            self.spread(news);
+            let peer = self.membership[&peer_id].clone();
+            self.changed(peer, Some(old));
+            if old.is_probed() {
+                match state {
+                    PeerState::Departed => self.last_departure = Some(self.clock.now()),
+                    PeerState::Failed if self.memberlist.is_empty() => self.lost_everyone(),
+                    _ => {}
+                }
+            }
+        } else if let RumorKind::Failed = rumor_kind {
+            if let Some((failed, _)) = self.unknown_failed.get(&peer_id) {
+                if *failed >= incarnation {
+                    return;
+                }
+            }
+            debug!(
+                "{:03} heard {:03} failed before meeting it",
+                self.id, peer_id
             );
-            if peer.state == PeerState::Failed {
-                // we actually have to probe them now
-                let mut rng = thread_rng();
-                let n: usize = rng.gen_range(0..=self.memberlist.len());
-                self.memberlist.insert(n, peer.id);
-            } else if state == PeerState::Failed {
-                // dont bother probing failed peers
-                let mut idx = usize::MAX;
-                for (i, n) in self.memberlist.iter().enumerate() {
-                    if *n == peer_id {
-                        idx = i;
-                        break;
-                    }
+            let now = self.clock.now();
+            self.unknown_failed.insert(peer_id, (incarnation, now));
+            // Keep it spreading so nobody else lets the dead peer in either
+            self.spread(Rumor {
+                peer_id,
+                incarnation,
+                kind: RumorKind::Failed,
+            });
+        } else if let RumorKind::Depart = rumor_kind {
+            if let Some((departed, _)) = self.quarantine.get(&peer_id) {
+                if *departed >= incarnation {
+                    return;
+                }
+            }
+            debug!(
+                "{:03} heard {:03} departed before meeting it",
+                self.id, peer_id
+            );
+            let now = self.clock.now();
+            self.quarantine.insert(peer_id, (incarnation, now));
+            self.spread(Rumor {
+                peer_id,
+                incarnation,
+                kind: RumorKind::Depart,
+            });
+        } else if let RumorKind::Alive { addr, meta } = rumor_kind {
+            if let Some((failed, _)) = self.unknown_failed.get(&peer_id) {
+                if *failed >= incarnation {
+                    return;
+                }
+                self.unknown_failed.remove(&peer_id);
+            }
+            if let Some((departed, _)) = self.quarantine.get(&peer_id) {
+                if *departed >= incarnation {
+                    return;
                 }
-                assert!(idx != usize::MAX);
-                self.memberlist.swap_remove(idx);
+                self.quarantine.remove(&peer_id);
             }
-            peer.state = state;
-            self.broadcasts.push(peer.rumor());
-        } else if let RumorKind::Alive(addr) = rumor_kind {
-            let peer = Peer::new(peer_id, addr, incarnation, rumor_kind.into());
+            let peer = Peer {
+                meta,
+                ..Peer::new(peer_id, addr, incarnation, PeerState::Alive)
+            };
+            #[cfg(not(feature = "tracing"))]
             info!("{:03} discovered {:03}", self.id, peer);
-            let mut rng = thread_rng();
-            let n: usize = rng.gen_range(0..=self.memberlist.len());
-            self.memberlist.insert(n, peer.id);
-            self.membership.insert(peer.id, peer);
-            self.broadcasts.push(peer.rumor());
+            self.add_member(peer.clone());
+            self.resized();
+            self.spread(peer.rumor(self.id));
+            self.changed(peer, None);
+        }
+    }
+
+    /// Start tracking `peer`, slotting it into the probe rotation at random if it's probed.
+    /// Membership and the rotation only change through here, `set_member_state` and
+    /// `remove_member`, so they can't drift apart.
+    fn add_member(&mut self, peer: Peer) {
+        let id = peer.id;
+        if let Some(old) = self.membership.insert(id, peer) {
+            if old.state.is_probed() {
+                self.leave_rotation(id);
+            }
+        }
+        if self.membership[&id].state.is_probed() {
+            self.join_rotation(id);
+        }
+        debug_assert!(self.rotation_in_sync(), "{:03} added {:03}", self.id, id);
+    }
+
+    /// Stop tracking a peer altogether
+    fn remove_member(&mut self, id: PeerId) -> Option<Peer> {
+        let peer = self.membership.remove(&id)?;
+        self.replay_windows.remove(&id);
+        self.arrivals.remove(&id);
+        self.last_probed.remove(&id);
+        self.responsiveness.remove(&id);
+        self.last_changed.remove(&id);
+        if peer.state.is_probed() {
+            self.leave_rotation(id);
+        }
+        debug_assert!(self.rotation_in_sync(), "{:03} removed {:03}", self.id, id);
+        Some(peer)
+    }
+
+    /// Move a peer we know to `state`, entering or leaving the probe rotation as needed
+    fn set_member_state(&mut self, id: PeerId, state: PeerState) {
+        let peer = match self.membership.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let was_probed = peer.state.is_probed();
+        peer.state = state;
+        if !was_probed && state.is_probed() {
+            // rejoin! we actually have to probe them now
+            self.join_rotation(id);
+        } else if was_probed && !state.is_probed() {
+            // dont bother probing failed or departed peers
+            self.leave_rotation(id);
+        }
+        debug_assert!(self.rotation_in_sync(), "{:03} moved {:03}", self.id, id);
+    }
+
+    /// Whether the probe rotation holds exactly the peers we probe
+    fn rotation_in_sync(&self) -> bool {
+        let probed = self
+            .membership
+            .values()
+            .filter(|p| p.state.is_probed())
+            .count();
+        self.memberlist.len() == probed
+    }
+
+    /// Panic unless our bookkeeping is consistent: the probe rotation holds every peer we
+    /// probe exactly once and nobody else, and we aren't a member of our own cluster. For fuzz
+    /// targets and other harnesses to call between steps.
+    #[cfg(feature = "fuzzing")]
+    pub fn check_invariants(&self) {
+        assert!(
+            self.rotation_in_sync(),
+            "{:03} rotation {:?} out of sync with {:?}",
+            self.id,
+            self.memberlist,
+            self.membership
+        );
+        let mut seen = HashSet::new();
+        for id in &self.memberlist {
+            assert!(seen.insert(*id), "{:03} rotates {:03} twice", self.id, id);
+            assert!(
+                self.membership.get(id).is_some_and(|p| p.state.is_probed()),
+                "{:03} rotates {:03} but doesn't probe it",
+                self.id,
+                id
+            );
+        }
+        assert!(
+            !self.membership.contains_key(&self.id),
+            "{:03} is its own member",
+            self.id
+        );
+    }
+
+    fn join_rotation(&mut self, id: PeerId) {
+        if self.deterministic_insert {
+            self.memberlist.push(id);
+            return;
+        }
+        let n: usize = self.rng.gen_range(0..=self.memberlist.len());
+        self.memberlist.insert(n, id);
+    }
+
+    fn leave_rotation(&mut self, id: PeerId) {
+        if let Some(idx) = self.memberlist.iter().position(|n| *n == id) {
+            self.memberlist.swap_remove(idx);
+        }
+    }
+
+    /// Rescale dissemination and suspicion to the cluster's new size
+    fn resized(&mut self) {
+        let log_n = math::ceil_log10(self.membership.len() + 2);
+        self.max_sends = (self.retransmit_multiplier * log_n) as usize;
+        // The SWIM paper scales a base of 3 protocol periods
+        self.suspicion_period = (self.base_suspicion * log_n)
+            .min(self.max_suspicion)
+            .max(self.min_suspicion);
+    }
+
+    /// Queue membership news for gossip, unless we keep it to ourselves
+    fn spread(&mut self, news: Rumor) {
+        if self.disseminate_membership {
+            self.broadcasts.push(news, self.clock.now());
+        }
+    }
+
+    /// Tell the delegate and queue an event about a peer that is now in a new state
+    fn changed(&mut self, peer: Peer, old: Option<PeerState>) {
+        #[cfg(feature = "tracing")]
+        info!(
+            peer_id = %peer.id,
+            incarnation = %peer.incarnation,
+            state = ?peer.state,
+            old_state = ?old,
+            addr = %peer.addr,
+            "peer changed"
+        );
+        // Evicted peers are reported on their way out, and there's nothing left to stamp
+        if self.membership.contains_key(&peer.id) {
+            self.last_changed.insert(peer.id, self.clock.now());
+        }
+        self.delegate.on_change(peer.clone(), old);
+        self.events.push(match (old, peer.state) {
+            (None, _) => Event::Joined(peer),
+            (_, PeerState::Departed) => Event::Departed(peer.id),
+            (Some(from), to) => Event::StateChanged {
+                id: peer.id,
+                from,
+                to,
+            },
+        });
+    }
+
+    /// Our last reachable peer just failed. Say we've lost the cluster unless it looks like
+    /// everyone was leaving anyway.
+    fn lost_everyone(&mut self) {
+        if self.departed {
+            return;
+        }
+        let now = self.clock.now();
+        let window = self.protocol_period * self.cluster_loss_periods;
+        let recent = |at: Instant| now.saturating_duration_since(at) <= window;
+        if self.last_departure.is_some_and(recent) {
+            return;
+        }
+        let failed: Vec<PeerId> = self
+            .failures
+            .iter()
+            .take_while(|(_, at)| recent(*at))
+            .map(|(id, _)| *id)
+            .collect();
+        warn!(
+            "{:03} lost every peer it could reach: {:?}",
+            self.id, failed
+        );
+        self.events.push(Event::ClusterLost(failed));
+    }
+
+    /// Suspect a peer on `from`'s behalf, counting them toward confirming the suspicion
+    fn suspect(&mut self, peer_id: PeerId, incarnation: Incarnation, from: PeerId) {
+        self.upsert_peer(peer_id, incarnation, RumorKind::Suspect { from });
+        if let Some(suspicion) = self.suspicions.get_mut(&peer_id) {
+            suspicion.suspectors.insert(from);
+        }
+    }
+
+    /// How unhealthy we think we are, from 0 up to 8. Every probe that goes unanswered raises
+    /// it and every ack lowers it; ping and suspicion timeouts are stretched by
+    /// `health_multiplier + 1`, so a struggling node backs off instead of suspecting everyone.
+    pub fn health_multiplier(&self) -> usize {
+        self.health_multiplier
+    }
+
+    /// How many times we've had to refute a peer suspecting us or declaring us failed. A
+    /// climbing count means the cluster sees this node as flaky, usually from a lossy network
+    /// or an overloaded process.
+    pub fn self_refutations(&self) -> usize {
+        self.self_refutations
+    }
+
+    /// Neither the target nor anyone we asked answered one of our probes
+    fn missed_probe(&mut self) {
+        self.consecutive_failed_probes += 1;
+        self.health_multiplier = (self.health_multiplier + 1).min(MAX_HEALTH_MULTIPLIER);
+    }
+
+    /// Stretch a timeout by our local health multiplier
+    fn scaled(&self, timeout: Duration) -> Duration {
+        timeout * (self.health_multiplier as u32 + 1)
+    }
+
+    /// How long suspicions currently last before health scaling, given the cluster's size and
+    /// the bounds from [`Server::with_min_suspicion`] and [`Server::with_max_suspicion`]. Not
+    /// used once [`Server::with_suspicion_timeout`] is set.
+    pub fn suspicion_period(&self) -> Duration {
+        self.suspicion_period
+    }
+
+    /// Pin the suspicion period to `period` however big the cluster grows, by setting both
+    /// [`Server::with_min_suspicion`] and [`Server::with_max_suspicion`] to it. Suspicions
+    /// already underway are timed against the new period.
+    pub fn set_suspicion_period(&mut self, period: Duration) {
+        self.min_suspicion = period;
+        self.max_suspicion = period;
+        self.resized();
+    }
+
+    /// How long we wait for a probe to be acked before asking others to try
+    pub fn ping_interval(&self) -> Duration {
+        self.ping_interval
+    }
+
+    /// Change how long we wait for a probe to be acked before asking others to try, e.g. to
+    /// make allowances for a network that's turned noisy. It has to stay shorter than the
+    /// protocol period.
+    pub fn set_ping_interval(&mut self, ping_interval: Duration) -> Result<(), ConfigError> {
+        if ping_interval >= self.protocol_period {
+            return Err(ConfigError::PingIntervalTooLong {
+                ping_interval,
+                protocol_period: self.protocol_period,
+            });
+        }
+        self.ping_interval = ping_interval;
+        Ok(())
+    }
+
+    /// How often we probe
+    pub fn protocol_period(&self) -> Duration {
+        self.protocol_period
+    }
+
+    /// Change how often we probe, starting with the next probe. It has to be non-zero and
+    /// longer than the ping interval. The suspicion period is recomputed, since it's counted
+    /// in protocol periods.
+    pub fn set_protocol_period(&mut self, protocol_period: Duration) -> Result<(), ConfigError> {
+        if protocol_period.is_zero() {
+            return Err(ConfigError::ZeroProtocolPeriod);
+        }
+        if self.ping_interval >= protocol_period {
+            return Err(ConfigError::PingIntervalTooLong {
+                ping_interval: self.ping_interval,
+                protocol_period,
+            });
+        }
+        // Suspicions keep lasting as many protocol periods as before
+        self.base_suspicion = self
+            .base_suspicion
+            .mul_f64(protocol_period.as_secs_f64() / self.protocol_period.as_secs_f64());
+        self.protocol_period = protocol_period;
+        self.resized();
+        Ok(())
+    }
+
+    /// How long a suspicion of `peer_id` lasts before they're declared failed. Lifeguard
+    /// starts at the maximum and shrinks logarithmically toward the minimum as independent
+    /// confirmations arrive, expecting as many as we'd ask to ping indirectly.
+    fn suspicion_timeout(&self, peer_id: PeerId) -> Duration {
+        let (min, max) = match self.suspicion_bounds {
+            Some(bounds) => bounds,
+            None => return self.scaled(self.suspicion_period),
+        };
+        let confirmations = self
+            .suspicions
+            .get(&peer_id)
+            .map_or(0, |s| s.suspectors.len().saturating_sub(1));
+        let expected = self.pingreq_subgroup_sz.max(1);
+        let frac = math::ln((confirmations + 1) as f64) / math::ln((expected + 1) as f64);
+        let timeout = max
+            .saturating_sub(max.saturating_sub(min).mul_f64(frac.min(1.0)))
+            .max(min);
+        self.scaled(timeout)
+    }
+
+    /// A direct ack disproves a suspicion we raised ourselves, even before the peer gets
+    /// around to refuting it
+    fn withdraw_suspicion(&mut self, peer_id: PeerId, incarnation: Incarnation) {
+        if let Some(peer) = self.membership.get_mut(&peer_id) {
+            // An ack from a later incarnation refutes it too, though the peer's own Alive rumor
+            // has to tell us the rest
+            if peer.state == PeerState::Suspect && peer.incarnation <= incarnation {
+                debug!("{:03} no longer suspects {:03}", self.id, peer_id);
+                self.set_member_state(peer_id, PeerState::Alive);
+                self.suspicions.remove(&peer_id);
+                let peer = self.membership[&peer_id].clone();
+                self.changed(peer, Some(PeerState::Suspect));
+            }
+        }
+    }
+
+    /// Join a cluster the specified peer belongs to, by pulling from it through our transport
+    pub fn join(&mut self, peer_id: PeerId, peer_addr: SocketAddr) {
+        if self.membership.contains_key(&peer_id) {
+            return;
+        }
+
+        let seq_no = self.next_seq_no();
+        self.send(Message {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_label: self.cluster_label,
+            dest_id: peer_id,
+            dest_addr: peer_addr,
+            src_id: self.id,
+            src_addr: self.addr,
+            seq_no,
+            kind: MsgKind::Pull(Vec::new()),
+            mac: Vec::new(),
+        });
+    }
+
+    /// Forget the cluster and start over, e.g. to move to another one or recycle a server
+    /// between tests. Everything we know about other peers, pending pings, queued gossip and
+    /// undelivered messages are dropped without events; our id, address, incarnation and
+    /// configuration are kept. Join again afterwards as if freshly started.
+    pub fn reset(&mut self) {
+        info!("{:03} resetting", self.id);
+        self.replay_windows.clear();
+        self.departed = false;
+        self.left_at = None;
+        self.describe_self();
+        self.broadcasts.clear();
+        self.pings.clear();
+        self.last_pinged = 0;
+        self.last_probed.clear();
+        self.last_tick = None;
+        self.next_probe = None;
+        self.memberlist.clear();
+        self.membership.clear();
+        self.tombstones.clear();
+        self.failures.clear();
+        self.last_departure = None;
+        self.unknown_failed.clear();
+        self.quarantine.clear();
+        self.seeds.clear();
+        self.seeds_pulled_at = None;
+        self.seeds_responded.clear();
+        self.seed_backoff = 0;
+        self.consecutive_failed_probes = 0;
+        self.last_changed.clear();
+        self.responsiveness.clear();
+        self.suspicions.clear();
+        self.health_multiplier = 0;
+        self.arrivals.clear();
+        self.user_messages.clear();
+        self.events.clear();
+        self.transport.clear();
+        self.resized();
+        self.spread_alive();
+        self.report();
+    }
+
+    /// Join a cluster through any of several seeds, pulling from each of them. If none answer
+    /// within a few protocol periods, [`Server::expire`] pulls from them all again.
+    pub fn join_seeds(&mut self, seeds: &[(PeerId, SocketAddr)]) {
+        self.seeds = seeds
+            .iter()
+            .filter(|(id, _)| *id != self.id)
+            .copied()
+            .collect();
+        self.seeds_responded.clear();
+        self.seed_backoff = 0;
+        self.pull_seeds(self.clock.now());
+    }
+
+    /// Whether any seed passed to [`Server::join_seeds`] has answered
+    pub fn seeded(&self) -> bool {
+        !self.seeds_responded.is_empty()
+    }
+
+    fn pull_seeds(&mut self, now: Instant) {
+        self.seeds_pulled_at = Some(now);
+        for (dest_id, dest_addr) in self.seeds.clone() {
+            let seq_no = self.next_seq_no();
+            self.send(Message {
+                protocol_version: PROTOCOL_VERSION,
+                cluster_label: self.cluster_label,
+                dest_id,
+                dest_addr,
+                src_id: self.id,
+                src_addr: self.addr,
+                seq_no,
+                kind: MsgKind::Pull(Vec::new()),
+                mac: Vec::new(),
+            });
+        }
+    }
+
+    /// Whether we seem to have lost contact with the whole cluster: nobody else is alive, or
+    /// our last few probes all went unanswered
+    pub fn isolated(&self) -> bool {
+        self.num_alive() <= 1 || self.consecutive_failed_probes >= ISOLATION_PROBES
+    }
+
+    /// When we next pull from the seeds, if we're still waiting on them or have since been cut
+    /// off from the cluster. Each retry doubles the wait, up to a point.
+    fn seed_retry(&self) -> Option<Instant> {
+        if self.seeds.is_empty() || self.departed || (self.seeded() && !self.isolated()) {
+            return None;
+        }
+        let backoff = 2u32.pow(self.seed_backoff.min(MAX_SEED_BACKOFF));
+        self.seeds_pulled_at
+            .map(|at| at + self.protocol_period * SEED_RETRY_PERIODS * backoff)
+    }
+
+    /// Gracefully leave the cluster.
+    ///
+    /// This queues a Depart rumor about ourselves and pushes it straight to a few peers. The
+    /// rest of the cluster hears about it through gossip, so keep calling [`Server::tick`] and
+    /// attaching [`Server::piggyback`] to outgoing messages until
+    /// [`Server::is_departed_acknowledged`] says it's safe to shut down.
+    pub fn leave(&mut self) {
+        if self.departed {
+            return;
+        }
+        info!("{:03} leaving the cluster", self.id);
+        self.departed = true;
+        self.left_at = Some(self.clock.now());
+        self.describe_self();
+        self.spread(Rumor {
+            peer_id: self.id,
+            incarnation: self.incarnation,
+            kind: RumorKind::Depart,
+        });
+        let peer_self = self.self_peer.clone();
+        let dests: Vec<PeerId> = self
+            .memberlist
+            .choose_multiple(&mut self.rng, self.pingreq_subgroup_sz)
+            .copied()
+            .collect();
+        for dest_id in dests {
+            let dest_addr = self.membership.get(&dest_id).unwrap().addr;
+            let seq_no = self.next_seq_no();
+            self.send(Message {
+                protocol_version: PROTOCOL_VERSION,
+                cluster_label: self.cluster_label,
+                dest_id,
+                dest_addr,
+                src_id: self.id,
+                src_addr: self.addr,
+                seq_no,
+                kind: MsgKind::Push(vec![peer_self.clone()]),
+                mac: Vec::new(),
+            });
+        }
+    }
+
+    /// Remove a peer right away rather than waiting for failure detection, e.g. once it's
+    /// been decommissioned. A Failed rumor tells the rest of the cluster to follow, and the
+    /// peer can only rejoin with a higher incarnation. Returns what we knew of it, if anything.
+    pub fn evict(&mut self, peer_id: PeerId) -> Option<Peer> {
+        if peer_id == self.id {
+            return None;
+        }
+        let mut peer = self.remove_member(peer_id)?;
+        info!("{:03} evicting {:03}", self.id, peer_id);
+        self.pings.retain(|(id, _), _| *id != peer_id);
+        self.suspicions.remove(&peer_id);
+        self.tombstones.remove(&peer_id);
+        let now = self.clock.now();
+        // Treated like a failure we heard about before meeting the peer
+        self.unknown_failed.insert(peer_id, (peer.incarnation, now));
+        self.spread(Rumor {
+            peer_id,
+            incarnation: peer.incarnation,
+            kind: RumorKind::Failed,
+        });
+        self.resized();
+        let old = peer.state;
+        peer.state = PeerState::Failed;
+        if old.is_probed() {
+            self.changed(peer.clone(), Some(old));
+            self.last_departure = Some(now);
+        }
+        Some(peer)
+    }
+
+    /// Capture what we know of the cluster, to persist and [`Server::restore`] after a restart
+    pub fn snapshot(&self) -> MembershipSnapshot {
+        MembershipSnapshot {
+            incarnation: self.incarnation,
+            peers: self.membership.values().cloned().collect(),
+        }
+    }
+
+    /// Everything we know, down to pending pings and queued gossip, to compare with what
+    /// other servers think when the cluster disagrees
+    pub fn debug_state(&self) -> DebugState {
+        let now = self.clock.now();
+        let mut membership: Vec<Peer> = self.membership.values().cloned().collect();
+        membership.sort_by_key(|p| p.id);
+        let mut pings: Vec<PendingPingState> = self
+            .pings
+            .iter()
+            .map(|((target, _), ping)| PendingPingState {
+                target: *target,
+                seq_no: ping.seq_no,
+                state: ping.state,
+                requester: ping.requester,
+                age: now.saturating_duration_since(ping.sent_at),
+            })
+            .collect();
+        pings.sort_by_key(|p| (p.target, p.seq_no));
+        DebugState {
+            id: self.id,
+            incarnation: self.incarnation,
+            seq_no: self.seq_no,
+            membership,
+            probe_order: self.probe_order().to_vec(),
+            next_probe: self.last_pinged,
+            pings,
+            broadcasts: self
+                .broadcasts
+                .iter()
+                .filter_map(|bc| {
+                    let (rumor, _) = Rumor::deserialize(&bc.message).ok()?;
+                    Some(QueuedBroadcast {
+                        rumor,
+                        sends: bc.sends,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// [`Server::debug_state`], pretty-printed for logs. Serialize the state itself with the
+    /// `serde` feature for something machine readable.
+    pub fn debug_dump(&self) -> String {
+        format!("{:#?}", self.debug_state())
+    }
+
+    /// Pick up from a snapshot this server took before restarting. Peers that were alive or
+    /// suspected are remembered as alive and probed again; failed and departed ones are left
+    /// to the cluster. We announce ourselves at a higher incarnation than the snapshot's so
+    /// nobody holds our pre-restart state against us.
+    pub fn restore(&mut self, snapshot: MembershipSnapshot) {
+        self.incarnation = self.incarnation.max(snapshot.incarnation);
+        let mut restored = false;
+        for peer in snapshot.peers {
+            if peer.id == self.id
+                || !peer.state.is_probed()
+                || peer.meta.len() > MAX_META
+                || self.contains(peer.id)
+            {
+                continue;
+            }
+            let peer = Peer {
+                state: PeerState::Alive,
+                ..peer
+            };
+            debug!("{:03} restored {}", self.id, peer);
+            self.add_member(peer.clone());
+            self.changed(peer, None);
+            restored = true;
+        }
+        if restored {
+            self.resized();
+        }
+        self.announce();
+    }
+
+    /// Vouch for a peer known to be healthy despite what our probes say, e.g. during
+    /// maintenance. It's declared alive at a higher incarnation than any we've heard, so the
+    /// cluster converges on it even over a failure; departures are final though. Returns the
+    /// new incarnation, or `None` for peers we don't know or that have left.
+    pub fn declare_alive(&mut self, peer_id: PeerId) -> Option<Incarnation> {
+        let peer = self.membership.get(&peer_id)?;
+        if peer.state == PeerState::Departed {
+            return None;
+        }
+        let mut incarnation = peer.incarnation;
+        incarnation.bump();
+        let kind = RumorKind::Alive {
+            addr: peer.addr,
+            meta: peer.meta.clone(),
+        };
+        info!(
+            "{:03} declaring {:03} alive at {:?}",
+            self.id, peer_id, incarnation
+        );
+        self.upsert_peer(peer_id, incarnation, kind);
+        Some(incarnation)
+    }
+
+    /// Apply the rumors in `buf`, as written by [`Server::gossip`]. Nothing here is
+    /// authenticated, so gossip off the wire should go through [`Server::process_with_gossip`].
+    pub fn process_gossip(&mut self, buf: &[u8]) -> Result<(), DeserializationError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        if buf.len() < 2 {
+            return Err(DeserializationError::TooSmall(2 - buf.len()));
+        }
+        let (count_bytes, mut rest) = buf.split_at(2);
+        let rumors = u16::from_le_bytes(count_bytes.try_into().unwrap());
+        for _ in 0..rumors {
+            let (rumor, sl) = Rumor::deserialize(rest)?;
+            trace!("{:03} heard {:?}", self.id, rumor);
+            self.stats.rumors_received += 1;
+            self.process_rumor(rumor);
+            rest = sl;
+        }
+        Ok(())
+    }
+
+    pub fn process_rumor(&mut self, rumor: Rumor) {
+        if let RumorKind::User { tag, data, .. } = &rumor.kind {
+            let msg = UserMessage {
+                from: rumor.peer_id,
+                tag: *tag,
+                data: data.clone(),
+            };
+            let now = self.clock.now();
+            let first_time =
+                self.delivered.insert(rumor.subject(), now).is_none() && rumor.peer_id != self.id;
+            // Pass along anything we aren't already, ours included
+            self.broadcasts.push(rumor, now);
+            if first_time {
+                self.events.push(Event::UserMessage(msg.clone()));
+                self.user_messages.push(msg);
+            }
+            return;
+        }
+        if rumor.peer_id != self.id {
+            match rumor.kind {
+                RumorKind::Suspect { from } => self.suspect(rumor.peer_id, rumor.incarnation, from),
+                kind => self.upsert_peer(rumor.peer_id, rumor.incarnation, kind),
+            }
+            return;
+        }
+        // There's nothing to refute once we've left
+        if rumor.incarnation < self.incarnation || self.departed {
+            return;
         }
+        match &rumor.kind {
+            // Someone vouched for us at a later incarnation, see `declare_alive`. Catch up so
+            // our own news still outranks theirs, and correct them if they're out of date.
+            RumorKind::Alive { addr, meta } if rumor.incarnation > self.incarnation => {
+                self.incarnation = rumor.incarnation;
+                if *addr != self.addr || *meta != self.meta {
+                    self.announce();
+                } else {
+                    self.describe_self();
+                    self.delegate.on_incarnation(self.incarnation);
+                }
+            }
+            // Our own news making its way back to us
+            RumorKind::Alive { .. } | RumorKind::User { .. } => {}
+            // How we left before a reset, still going round. Outrank it or it's final.
+            RumorKind::Depart => {
+                self.incarnation = rumor.incarnation;
+                self.announce();
+            }
+            RumorKind::Suspect { .. } | RumorKind::Failed => {
+                // Reports of my death have been greatly exaggerated.
+                // Refuting is the only reason to bump our incarnation, and we have to outrank
+                // the rumor to do it.
+                self.incarnation = rumor.incarnation;
+                self.announce();
+                self.self_refutations += 1;
+                self.events.push(Event::SelfSuspected(self.incarnation));
+            }
+        }
+    }
+
+    /// Bump our incarnation and broadcast that we're alive, so our news outranks whatever the
+    /// cluster last heard about us
+    fn announce(&mut self) {
+        self.incarnation.bump();
+        self.describe_self();
+        self.delegate.on_incarnation(self.incarnation);
+        self.spread_alive();
+    }
+
+    /// Gossip that we're alive at our current incarnation
+    fn spread_alive(&mut self) {
+        let rumor = self.self_peer.rumor(self.id);
+        self.spread(rumor);
+    }
+
+    /// Attach metadata (a role, zone, version...) to ourselves and gossip it to the cluster.
+    /// Changing it bumps our incarnation so the new metadata supersedes the old.
+    pub fn set_meta(&mut self, meta: Vec<u8>) -> Result<(), BroadcastError> {
+        if meta.len() > MAX_META {
+            return Err(BroadcastError::MetaTooLarge(meta.len()));
+        }
+        if meta != self.meta {
+            self.meta = meta;
+            self.announce();
+        }
+        Ok(())
+    }
+
+    /// Our own metadata
+    pub fn meta(&self) -> &[u8] {
+        &self.meta
+    }
+
+    /// We heard from a peer directly. That proves it's alive but says nothing about its
+    /// incarnation or metadata, so only peers we've never met are added, at incarnation 0,
+    /// for their own Alive rumors to fill in. Peers we think failed are told so, as they'd
+    /// have refuted it if they knew and the news may have long since stopped spreading.
+    fn heard_from(&mut self, peer_id: PeerId, addr: SocketAddr) {
+        if peer_id == self.id {
+            return;
+        }
+        let incarnation = match self.membership.get(&peer_id) {
+            None => {
+                self.upsert_peer(peer_id, Incarnation(0), RumorKind::alive(addr));
+                return;
+            }
+            Some(peer) if peer.state == PeerState::Failed => peer.incarnation,
+            Some(_) => return,
+        };
+        debug!("{:03} telling {:03} it failed", self.id, peer_id);
+        let mut buf = 1u16.to_le_bytes().to_vec();
+        buf.extend(
+            Rumor {
+                peer_id,
+                incarnation,
+                kind: RumorKind::Failed,
+            }
+            .serialize(),
+        );
+        let seq_no = self.next_seq_no();
+        self.send(Message {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_label: self.cluster_label,
+            dest_id: peer_id,
+            dest_addr: addr,
+            src_id: self.id,
+            src_addr: self.addr,
+            seq_no,
+            kind: MsgKind::Gossip(buf),
+            mac: Vec::new(),
+        });
+    }
+
+    /// Gossip application data to the cluster alongside membership rumors. With a `ttl` we
+    /// stop sending it once that's passed, however few times it's been sent; peers relaying
+    /// it don't know the TTL and send it as often as any other broadcast.
+    ///
+    /// Fails with [`BroadcastError::QueueFull`] when a bounded queue, see
+    /// [`Server::with_broadcast_capacity`], is already full of membership news.
+    pub fn broadcast_user(
+        &mut self,
+        tag: u8,
+        data: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), BroadcastError> {
+        if data.len() > MAX_USER_DATA {
+            return Err(BroadcastError::TooLarge(data.len()));
+        }
+        let rumor = Rumor {
+            peer_id: self.id,
+            incarnation: self.incarnation,
+            kind: RumorKind::User {
+                seq_no: self.next_seq_no(),
+                tag,
+                data,
+            },
+        };
+        let now = self.clock.now();
+        let subject = rumor.subject();
+        if !self
+            .broadcasts
+            .push_until(rumor, now, ttl.and_then(|ttl| now.checked_add(ttl)))
+        {
+            return Err(BroadcastError::QueueFull);
+        }
+        self.delivered.insert(subject, now);
+        Ok(())
+    }
+
+    /// Take the user broadcasts we've heard from other peers since the last call. Each is
+    /// handed over once, however many times it's gossiped to us.
+    pub fn user_messages(&mut self) -> Vec<UserMessage> {
+        take(&mut self.user_messages)
+    }
+
+    /// Append as many rumors as we can into the provided buffer, returning how many bytes
+    /// were used.
+    pub fn gossip(&mut self, buffer: &mut [u8]) -> usize {
+        self.broadcasts.expire(self.clock.now());
+        // Held back until we're done so a rumor goes into the buffer at most once
+        let mut sent: Vec<Broadcast> = Vec::new();
+        let idx = self.gossip_into(buffer, &mut sent);
+        for bc in sent {
+            self.broadcasts.replay(bc);
+        }
+        idx
+    }
+
+    /// Fill `buffer` with gossip like [`Server::gossip`] to send alongside `msg` from our
+    /// outbox, signing `msg` again so its HMAC covers the gossip too. The receiver hands both
+    /// to [`Server::process_with_gossip`].
+    pub fn piggyback(&mut self, msg: &mut Message, buffer: &mut [u8]) -> usize {
+        let len = self.gossip(buffer);
+        self.sign(msg, &buffer[..len]);
+        len
+    }
+
+    /// Fill `buffer` like [`Server::gossip`], adding what we packed that should be sent again
+    /// to `sent` rather than requeueing it, so further buffers get different rumors
+    fn gossip_into(&mut self, buffer: &mut [u8], sent: &mut Vec<Broadcast>) -> usize {
+        let mut tmp: Vec<Broadcast> = Vec::new();
+        let mut rumors: u16 = 0;
+        let mut user_budget = (buffer.len() as f64 * self.user_gossip_fraction) as usize;
+        // First two bytes are for the number of rumors
+        let mut idx = 2;
+        while idx < buffer.len() && (rumors as usize) < self.max_piggyback {
+            if buffer.len() - idx < SMALLEST_RUMOR {
+                break;
+            }
+            if let Some(broadcast) = self.broadcasts.pop() {
+                assert_ne!(
+                    broadcast.message.len(),
+                    0,
+                    "invalid broadcast: {:?}",
+                    broadcast
+                );
+                let is_user = broadcast.priority == Priority::User;
+                if is_user && broadcast.message.len() > user_budget {
+                    tmp.push(broadcast);
+                } else if broadcast.message.len() <= buffer.len() - idx {
+                    if is_user {
+                        user_budget -= broadcast.message.len();
+                    }
+                    buffer[idx..idx + broadcast.message.len()].copy_from_slice(&broadcast.message);
+                    idx += broadcast.message.len();
+                    rumors += 1;
+                    if broadcast.sends + 1 < self.max_sends {
+                        sent.push(broadcast);
+                    } else {
+                        self.broadcasts.finish(broadcast);
+                    }
+                } else {
+                    tmp.push(broadcast);
+                }
+            } else {
+                break;
+            }
+        }
+        buffer[0..2].copy_from_slice(&rumors.to_le_bytes());
+        self.stats.rumors_sent += u64::from(rumors);
+        for bc in tmp {
+            self.broadcasts.push_broadcast(bc);
+        }
+        idx
+    }
+
+    /// Gossip queued broadcasts to a few random peers in messages of their own, independent
+    /// of probing. Each peer gets as many messages as it takes to carry the queue, up to
+    /// [`Server::with_max_gossip_messages`].
+    pub fn disseminate(&mut self) {
+        self.broadcasts.expire(self.clock.now());
+        let peers: Vec<PeerId> = self
+            .memberlist
+            .choose_multiple(&mut self.rng, self.gossip_fanout)
+            .copied()
+            .collect();
+        for dest_id in peers {
+            if self.broadcasts.is_empty() {
+                break;
+            }
+            let dest_addr = self.membership.get(&dest_id).unwrap().addr;
+            let mut sent = Vec::new();
+            for _ in 0..self.max_gossip_messages {
+                let mut buf = vec![0; GOSSIP_BUFFER_SIZE];
+                let len = self.gossip_into(&mut buf, &mut sent);
+                // Just the count: everything left was already sent or doesn't fit
+                if len <= 2 {
+                    break;
+                }
+                buf.truncate(len);
+                let seq_no = self.next_seq_no();
+                self.send(Message {
+                    protocol_version: PROTOCOL_VERSION,
+                    cluster_label: self.cluster_label,
+                    dest_id,
+                    dest_addr,
+                    src_id: self.id,
+                    src_addr: self.addr,
+                    seq_no,
+                    kind: MsgKind::Gossip(buf),
+                    mac: Vec::new(),
+                });
+            }
+            for bc in sent {
+                self.broadcasts.replay(bc);
+            }
+        }
+    }
+
+    /// Handle an incoming message, sending any response through our transport. Returns what
+    /// happened since the last `process` or `probe`, including anything learned from gossip.
+    ///
+    /// Messages that aren't for us, or that we can't trust or understand, are dropped with an
+    /// error before they can change anything.
+    pub fn process(&mut self, msg: Message) -> Result<Vec<Event>, ProcessError> {
+        self.process_with_gossip(msg, &[])
+    }
+
+    /// Handle `msg` like [`Server::process`], then the `gossip` that came with it from
+    /// [`Server::piggyback`]. The gossip is dropped along with a message we won't accept.
+    pub fn process_with_gossip(
+        &mut self,
+        mut msg: Message,
+        gossip: &[u8],
+    ) -> Result<Vec<Event>, ProcessError> {
+        #[cfg(feature = "tracing")]
+        let _span = debug_span!(
+            "process",
+            node_id = %self.id,
+            src_id = %msg.src_id,
+            seq_no = msg.seq_no,
+            kind = msg.kind.name(),
+        )
+        .entered();
+        if msg.dest_id != self.id {
+            return Err(ProcessError::WrongRecipient(msg.dest_id));
+        }
+        if !self.authentic(&mut msg, gossip) {
+            self.metrics.incr_unauthenticated_messages();
+            return Err(ProcessError::Unauthenticated(msg.src_id));
+        }
+        if msg.cluster_label != self.cluster_label {
+            return Err(ProcessError::ForeignCluster(msg.cluster_label));
+        }
+        if !compatible_version(msg.protocol_version) {
+            self.metrics
+                .incr_incompatible_messages(msg.protocol_version);
+            return Err(ProcessError::IncompatibleVersion(msg.protocol_version));
+        }
+        if !self.fresh(&msg) {
+            self.metrics.incr_replayed_messages();
+            return Err(ProcessError::Replayed(msg.src_id, msg.seq_no));
+        }
+        self.stats.received.incr(&msg.kind);
+        // Strangers we pinged for someone else don't join just by answering
+        let relayed_ack = matches!(msg.kind, MsgKind::Ack(..))
+            && self
+                .pings
+                .get(&(msg.src_id, msg.seq_no))
+                .is_some_and(|p| p.state == PingState::FromElsewhere);
+        if !relayed_ack {
+            self.heard_from(msg.src_id, msg.src_addr);
+        }
+        let echoes_ping = matches!(msg.kind, MsgKind::Ack(..) | MsgKind::Nack { .. });
+        match msg.kind {
+            MsgKind::Push(peers) => {
+                if self.seeds.iter().any(|(id, _)| *id == msg.src_id) {
+                    self.seeds_responded.insert(msg.src_id);
+                }
+                // Merge with our state
+                for peer in peers {
+                    if peer.id != self.id {
+                        self.upsert_peer(peer.id, peer.incarnation, peer.rumor_kind(msg.src_id))
+                    } else {
+                        // Refute anything they think happened to us while we were away
+                        self.process_rumor(peer.rumor(msg.src_id));
+                    }
+                }
+            }
+            MsgKind::Pull(peers) => {
+                // Respond with our state in a Push
+                let our_peers = self.sample(self.live_members());
+                // TODO what if they think we're suspect?
+                for peer in peers {
+                    if peer.id != self.id {
+                        self.upsert_peer(peer.id, peer.incarnation, peer.rumor_kind(msg.src_id))
+                    }
+                }
+                let seq_no = self.next_seq_no();
+                self.send(Message {
+                    protocol_version: PROTOCOL_VERSION,
+                    cluster_label: self.cluster_label,
+                    dest_id: msg.src_id,
+                    dest_addr: msg.src_addr,
+                    src_id: self.id,
+                    src_addr: self.addr,
+                    seq_no,
+                    kind: MsgKind::Push(our_peers),
+                    mac: Vec::new(),
+                });
+            }
+            MsgKind::PullDigest(digest) => {
+                let theirs: HashMap<PeerId, PeerDigest> =
+                    digest.into_iter().map(|d| (d.id, d)).collect();
+                let news = self.sample(self.news_for(&theirs));
+                let seq_no = self.next_seq_no();
+                self.send(Message {
+                    protocol_version: PROTOCOL_VERSION,
+                    cluster_label: self.cluster_label,
+                    dest_id: msg.src_id,
+                    dest_addr: msg.src_addr,
+                    src_id: self.id,
+                    src_addr: self.addr,
+                    seq_no,
+                    kind: MsgKind::Push(news),
+                    mac: Vec::new(),
+                });
+            }
+            MsgKind::Ping => self.ack(
+                self.id,
+                self.incarnation,
+                msg.src_id,
+                msg.src_addr,
+                msg.seq_no,
+            ),
+            // Asked to ping ourselves, we can answer for ourselves
+            MsgKind::PingReq { target_id, .. } if target_id == self.id => self.ack(
+                self.id,
+                self.incarnation,
+                msg.src_id,
+                msg.src_addr,
+                msg.seq_no,
+            ),
+            MsgKind::PingReq { target_id, target } => {
+                let now = self.clock.now();
+                self.ping(target_id, target, Some((msg.src_id, msg.seq_no)), now)
+            }
+            MsgKind::Ack(peer_id, incarnation) => {
+                if let Some(ping) = self.pings.remove(&(peer_id, msg.seq_no)) {
+                    let now = self.clock.now();
+                    let rtt = now.saturating_duration_since(ping.sent_at);
+                    let (last_rtt, last_seen) =
+                        self.responsiveness.entry(peer_id).or_insert((None, now));
+                    // Acks relayed through a helper took a detour
+                    if msg.src_id == peer_id {
+                        *last_rtt = Some(rtt);
+                    }
+                    *last_seen = now;
+                    if self.phi_threshold.is_some() {
+                        self.arrivals.entry(peer_id).or_default().heartbeat(now);
+                    }
+                    if ping.requester != self.id {
+                        // The requester may have been forgotten while we waited
+                        if let Some(addr) = self.membership.get(&ping.requester).map(|p| p.addr) {
+                            self.ack(
+                                peer_id,
+                                incarnation,
+                                ping.requester,
+                                addr,
+                                ping.requester_seq_no,
+                            );
+                        }
+                    } else {
+                        // Older probes of ours are answered too
+                        let me = self.id;
+                        self.pings
+                            .retain(|(id, _), p| *id != peer_id || p.requester != me);
+                        self.metrics.observe_probe_rtt(rtt);
+                        self.consecutive_failed_probes = 0;
+                        self.health_multiplier = self.health_multiplier.saturating_sub(1);
+                        self.withdraw_suspicion(peer_id, incarnation);
+                        self.heard_from(peer_id, ping.addr);
+                    }
+                }
+            }
+            MsgKind::Gossip(buf) => {
+                if let Err(e) = self.process_gossip(&buf) {
+                    warn!("{:03} bad gossip from {:03}: {}", self.id, msg.src_id, e);
+                }
+            }
+            MsgKind::Nack { target_id } => {
+                if let Some(ping) = self.pings.get_mut(&(target_id, msg.seq_no)) {
+                    if ping.state == PingState::Forwarded {
+                        ping.nacks += 1;
+                    }
+                }
+            }
+        }
+        // Afterwards, so that a restart it announced starts the window over
+        if !echoes_ping {
+            self.track_seq_no(msg.src_id, msg.seq_no);
+        }
+        if let Err(e) = self.process_gossip(gossip) {
+            warn!("{:03} bad gossip from {:03}: {}", self.id, msg.src_id, e);
+        }
+        self.report();
+        Ok(take(&mut self.events))
+    }
+
+    /// Summarize our membership for a [`MsgKind::PullDigest`]
+    pub fn digest(&self) -> Vec<PeerDigest> {
+        self.members().map(PeerDigest::from).collect()
+    }
+
+    /// The peers we know more about than `theirs` does
+    fn news_for(&self, theirs: &HashMap<PeerId, PeerDigest>) -> Vec<Peer> {
+        self.live_members()
+            .into_iter()
+            .filter(|ours| match theirs.get(&ours.id) {
+                None => true,
+                Some(d) => {
+                    let theirs = Peer::new(d.id, ours.addr, d.incarnation, d.state);
+                    ours.rumor(self.id).supersedes(&theirs.rumor(self.id))
+                }
+            })
+            .collect()
+    }
+
+    /// Cut `peers` down to our push sample size, keeping ourselves and the most recently
+    /// changed peers and filling the rest at random
+    fn sample(&mut self, mut peers: Vec<Peer>) -> Vec<Peer> {
+        if peers.len() <= self.push_sample_size {
+            return peers;
+        }
+        // We count as the most recent change of all
+        peers.sort_by_key(|p| {
+            (
+                p.id != self.id,
+                core::cmp::Reverse(self.last_changed.get(&p.id).copied()),
+            )
+        });
+        let mut rest = peers.split_off(self.push_sample_size.div_ceil(2));
+        let (sampled, _) = rest.partial_shuffle(&mut self.rng, self.push_sample_size - peers.len());
+        peers.extend_from_slice(sampled);
+        peers
+    }
+
+    /// Start an anti-entropy round with a random peer. Small clusters pull each other's full
+    /// state; larger ones send a digest and get back only what they're missing.
+    pub fn push_pull(&mut self) {
+        // run an anti-entropy cycle against a random node, if any are left
+        let dest_id = match self.memberlist.choose(&mut self.rng) {
+            Some(dest_id) => *dest_id,
+            None => return,
+        };
+        let dest_addr = self.membership.get(&dest_id).unwrap().addr;
+        let seq_no = self.next_seq_no();
+        self.send(Message {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_label: self.cluster_label,
+            dest_id,
+            dest_addr,
+            src_id: self.id,
+            src_addr: self.addr,
+            seq_no,
+            kind: if self.membership.len() <= FULL_SYNC_MAX_MEMBERS {
+                MsgKind::Pull(self.live_members())
+            } else {
+                MsgKind::PullDigest(self.digest())
+            },
+            mac: Vec::new(),
+        });
+    }
+
+    /// Choose up to `pingreq_subgroup_sz` live peers to ping `target` for us, at random but
+    /// favoring any our helper preference picks out
+    fn helpers(&mut self, target: PeerId) -> Vec<PeerId> {
+        // The target itself can't help, and suspects are likely no better off
+        let mut helpers: Vec<PeerId> = self
+            .memberlist
+            .iter()
+            .copied()
+            .filter(|id| *id != target && self.membership[id].state == PeerState::Alive)
+            .collect();
+        helpers.shuffle(&mut self.rng);
+        if let (Some(prefer), Some(target)) =
+            (&self.helper_preference, self.membership.get(&target))
+        {
+            // Stable, so both groups stay shuffled
+            helpers.sort_by_key(|id| !prefer(target, &self.membership[id]));
+        }
+        helpers.truncate(self.pingreq_subgroup_sz);
+        helpers
+    }
+
+    /// How long departed peers are quarantined
+    fn quarantine_ttl(&self) -> Duration {
+        self.suspicion_period * QUARANTINE_SUSPICION_PERIODS
+    }
+
+    /// When there's next something to do: the next probe is due, a pending ping times out, a
+    /// suspicion, tombstone or quarantine expires, or it's time to retry our seeds. Event loops
+    /// can sleep until then instead of polling, and [`Server::expire`] there unless it's
+    /// [`Server::next_probe`].
+    pub fn next_deadline(&self) -> Instant {
+        let last_tick = match self.last_tick {
+            Some(t) => t,
+            None => return self.clock.now(),
+        };
+        let pings = self.pings.iter().map(|((id, _), ping)| {
+            if ping.state != PingState::Forwarded {
+                ping.sent_at + self.scaled(self.ping_interval)
+            } else if ping.sent_at + self.protocol_period > last_tick {
+                ping.sent_at + self.protocol_period
+            } else {
+                ping.sent_at + ping.shorten_suspicion(self.suspicion_timeout(*id))
+            }
+        });
+        let suspicions = self
+            .suspicions
+            .iter()
+            .map(|(id, s)| s.started + self.suspicion_timeout(*id));
+        let tombstones = self
+            .tombstones
+            .values()
+            .chain(self.unknown_failed.values().map(|(_, at)| at))
+            .map(|at| *at + self.tombstone_ttl);
+        let quarantine = self
+            .quarantine
+            .values()
+            .map(|(_, at)| *at + self.quarantine_ttl());
+        pings
+            .chain(suspicions)
+            .chain(tombstones)
+            .chain(quarantine)
+            .chain(self.seed_retry())
+            .fold(self.next_probe(), Instant::min)
+    }
+
+    /// When the next probe is due, which is right away if we haven't probed yet. Drivers
+    /// should [`Server::tick`] then, and otherwise only [`Server::expire`] by
+    /// [`Server::next_deadline`].
+    pub fn next_probe(&self) -> Instant {
+        self.next_probe.unwrap_or_else(|| self.clock.now())
+    }
+
+    /// Schedule the probe after the one we're running at `now`. If we're late, the schedule
+    /// keeps its phase and skips the probes we missed rather than running them back to back.
+    fn reschedule_probe(&mut self, now: Instant) {
+        let due = self.next_probe.filter(|due| *due < now).unwrap_or(now);
+        let late = now - due;
+        let missed = late.as_nanos() / self.protocol_period.as_nanos();
+        if missed > 0 {
+            debug!("{:03} missed {} probes", self.id, missed);
+        }
+        let into_period = late.as_nanos() % self.protocol_period.as_nanos();
+        self.next_probe =
+            Some(now - Duration::from_nanos(into_period as u64) + self.jittered_period());
+    }
+
+    /// A protocol period, give or take our probe jitter
+    fn jittered_period(&mut self) -> Duration {
+        if self.probe_jitter <= 0.0 {
+            return self.protocol_period;
+        }
+        let spread = self.protocol_period.mul_f64(self.probe_jitter);
+        self.protocol_period - spread + spread.mul_f64(2.0 * self.rng.gen::<f64>())
+    }
+
+    /// Run the failure detector once per protocol period: [`Server::probe`] then
+    /// [`Server::disseminate`]. Call those separately to gossip at a different cadence.
+    pub fn tick(&mut self, now: Instant) -> Vec<Event> {
+        #[cfg(feature = "tracing")]
+        let _span = debug_span!("tick", node_id = %self.id).entered();
+        let events = self.probe(now);
+        self.disseminate();
+        events
+    }
+
+    /// Handle pending pings that have timed out, then probe the next peer if a probe is due,
+    /// with the current time. Probes and ping requests go out through our transport.
+    ///
+    /// Taking `now` lets a simulator drive many servers from one logical clock; it should come
+    /// from the same timeline as this server's [`Clock`]. Returns what happened since the last
+    /// `process` or `probe`.
+    ///
+    /// In a two-node cluster there's nobody to ask for an indirect probe, so a probe that
+    /// isn't acked within the ping interval suspects the other peer straight away. An ack to
+    /// any later probe clears the suspicion; without one the peer fails once the suspicion
+    /// period runs out. Every missed probe also counts against our own health, which
+    /// stretches both timeouts. A peer that's really gone is declared failed within a
+    /// protocol period (to be probed), plus the ping interval and the suspicion period, each
+    /// stretched up to ninefold by the time our health bottoms out.
+    pub fn probe(&mut self, now: Instant) -> Vec<Event> {
+        self.time_out(now);
+        // Called early, e.g. at a ping's deadline, there's nobody new to probe yet
+        if self.next_probe.is_none_or(|due| now >= due) {
+            self.reschedule_probe(now);
+            self.probe_next(now);
+        }
+        self.report();
+        take(&mut self.events)
+    }
+
+    /// Handle pending pings, suspicions, tombstones and quarantines that have timed out, and
+    /// retry our seeds if it's time, without probing anyone new. Returns what happened since
+    /// the last `process` or `probe`.
+    pub fn expire(&mut self, now: Instant) -> Vec<Event> {
+        self.time_out(now);
+        self.report();
+        take(&mut self.events)
+    }
+
+    fn time_out(&mut self, now: Instant) {
+        self.last_tick = Some(now);
+        let mut to_rm = Vec::new();
+        // Counted up after the loop, so every ping is timed out against the same health
+        let mut missed = 0;
+        let mut pings = take(&mut self.pings);
+        for (key, ping) in pings.iter_mut() {
+            let node = &key.0;
+            // Forward late pings first, even if we ticked too late to do it on time
+            let forward_by = ping.sent_at + self.scaled(self.ping_interval);
+            if ping.state != PingState::Forwarded && now > forward_by {
+                if ping.state != PingState::Normal {
+                    debug!(
+                        "{:03} expire ping from {:03} to {:03}",
+                        self.id, ping.requester, node
+                    );
+                    if let Some(requester) = self.membership.get(&ping.requester) {
+                        self.send(Message {
+                            protocol_version: PROTOCOL_VERSION,
+                            cluster_label: self.cluster_label,
+                            dest_id: ping.requester,
+                            dest_addr: requester.addr,
+                            src_id: self.id,
+                            src_addr: self.addr,
+                            seq_no: ping.requester_seq_no,
+                            kind: MsgKind::Nack { target_id: *node },
+                            mac: Vec::new(),
+                        });
+                    }
+                    to_rm.push(*key);
+                    continue;
+                }
+                // late, send ping_req to k nodes
+                let incarnation = match self.membership.get(node) {
+                    Some(peer) => peer.incarnation,
+                    // Forgotten while we waited, so there's nobody left to suspect
+                    None => {
+                        to_rm.push(*key);
+                        continue;
+                    }
+                };
+                // Unless we're a whole period late, when helpers would have been out of time
+                // too
+                let helpers = if !self.indirect_probes || now > forward_by + self.protocol_period {
+                    Vec::new()
+                } else {
+                    self.helpers(*node)
+                };
+                if helpers.is_empty() {
+                    missed += 1;
+                    to_rm.push(*key);
+                    if !self.phi_decides(*node) {
+                        debug!("{:03} suspects that {:03} has failed", self.id, node);
+                        self.suspect(*node, incarnation, self.id);
+                    }
+                    continue;
+                }
+                for &dest_id in &helpers {
+                    let dest_addr = self.membership.get(&dest_id).unwrap().addr;
+                    self.send(Message {
+                        protocol_version: PROTOCOL_VERSION,
+                        cluster_label: self.cluster_label,
+                        dest_id,
+                        dest_addr,
+                        src_id: self.id,
+                        src_addr: self.addr,
+                        seq_no: ping.seq_no,
+                        kind: MsgKind::PingReq {
+                            target_id: *node,
+                            target: ping.addr,
+                        },
+                        mac: Vec::new(),
+                    });
+                }
+                ping.state = PingState::Forwarded;
+                ping.helpers = helpers.len();
+            } else if now > ping.sent_at + ping.shorten_suspicion(self.suspicion_timeout(*node)) {
+                if let Some(peer) = self.membership.get(node) {
+                    let incarnation = peer.incarnation;
+                    self.upsert_peer(*node, incarnation, RumorKind::Failed);
+                }
+                to_rm.push(*key);
+            } else if now > (ping.sent_at + self.protocol_period) {
+                // At this point we throw out pings for non-member peers.
+                if ping.state == PingState::FromElsewhere || !self.membership.contains_key(node) {
+                    to_rm.push(*key);
+                    continue;
+                }
+                if !self.phi_decides(*node) {
+                    let incarnation = self.membership.get(node).unwrap().incarnation;
+                    debug!("{} suspects that {} has failed", self.id, node);
+                    self.suspect(*node, incarnation, self.id);
+                }
+                if !ping.missed {
+                    ping.missed = true;
+                    missed += 1;
+                }
+            }
+        }
+        self.pings = pings;
+        for _ in 0..missed {
+            self.missed_probe();
+        }
+        for key in to_rm {
+            trace!("{:03} expire ping {} to {}", self.id, key.1, key.0);
+            self.pings.remove(&key);
+        }
+        if let Some(threshold) = self.phi_threshold {
+            let min_std_dev = self.phi_min_std_dev();
+            let overdue: Vec<PeerId> = self
+                .arrivals
+                .iter()
+                .filter(|(_, w)| w.ready() && w.phi(now, min_std_dev) > threshold)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in overdue {
+                let incarnation = match self.membership.get(&id) {
+                    Some(peer) if peer.state == PeerState::Alive => peer.incarnation,
+                    _ => continue,
+                };
+                debug!("{:03} suspects {:03} by phi", self.id, id);
+                self.suspect(id, incarnation, self.id);
+                if let Some(window) = self.arrivals.get_mut(&id) {
+                    window.restart(now);
+                }
+            }
+        }
+        // Suspicions nobody refuted in time
+        let expired: Vec<PeerId> = self
+            .suspicions
+            .iter()
+            .filter(|(id, s)| now > s.started + self.suspicion_timeout(**id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            match self.membership.get(&id) {
+                Some(peer) => {
+                    let incarnation = peer.incarnation;
+                    self.upsert_peer(id, incarnation, RumorKind::Failed);
+                }
+                None => {
+                    self.suspicions.remove(&id);
+                }
+            }
+        }
+        // Forget long-failed peers so they can rejoin from scratch
+        let ttl = self.tombstone_ttl;
+        let forgotten: Vec<PeerId> = self
+            .tombstones
+            .iter()
+            .filter(|(_, failed_at)| now.saturating_duration_since(**failed_at) >= ttl)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &forgotten {
+            trace!("{:03} forget failed peer {:03}", self.id, id);
+            self.tombstones.remove(id);
+            self.remove_member(*id);
+        }
+        if !forgotten.is_empty() {
+            self.resized();
+        }
+        self.unknown_failed
+            .retain(|_, (_, failed_at)| now.saturating_duration_since(*failed_at) < ttl);
+        // and long-departed ones, so stale gossip has died out by the time they're gone
+        let ttl = self.quarantine_ttl();
+        let released: Vec<PeerId> = self
+            .quarantine
+            .iter()
+            .filter(|(_, (_, departed_at))| now.saturating_duration_since(*departed_at) >= ttl)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut forgot_any = false;
+        for id in &released {
+            self.quarantine.remove(id);
+            if self
+                .membership
+                .get(id)
+                .is_some_and(|p| p.state == PeerState::Departed)
+            {
+                trace!("{:03} forget departed peer {:03}", self.id, id);
+                self.remove_member(*id);
+                forgot_any = true;
+            }
+        }
+        if forgot_any {
+            self.resized();
+        }
+        // and user broadcasts nobody's repeated in as long
+        self.delivered
+            .retain(|_, heard_at| now.saturating_duration_since(*heard_at) < ttl);
+        if self.seed_retry().is_some_and(|at| now >= at) {
+            debug!(
+                "{:03} cut off from the cluster, pulling from seeds",
+                self.id
+            );
+            self.seed_backoff += 1;
+            self.pull_seeds(now);
+        } else if self.seeded() && !self.isolated() {
+            self.seed_backoff = 0;
+        }
+        if let Some(cutoff) = self
+            .broadcast_max_age
+            .and_then(|periods| now.checked_sub(self.protocol_period * periods))
+        {
+            self.broadcasts.retire(cutoff);
+        }
+        self.broadcasts.expire(now);
+        let probed = self
+            .membership
+            .values()
+            .filter(|p| p.state.is_probed())
+            .count();
+        debug_assert_eq!(
+            self.memberlist.len(),
+            probed,
+            "membership {:?}\nmemberlist {:?}",
+            self.membership,
+            self.memberlist
+        );
+        if self.memberlist.len() != probed {
+            warn!(
+                "{:03} probe rotation drifted from membership, rebuilding it",
+                self.id
+            );
+            self.memberlist = self
+                .membership
+                .values()
+                .filter(|p| p.state.is_probed())
+                .map(|p| p.id)
+                .collect();
+            self.last_pinged = self.memberlist.len();
+        }
+    }
+
+    /// Ping the next few peers in the rotation
+    fn probe_next(&mut self, now: Instant) {
+        let count = self.probes_per_period.min(self.memberlist.len());
+        let mut probed = Vec::with_capacity(count);
+        while probed.len() < count {
+            let ping_rcpt = match self.probe_selection {
+                ProbeSelection::Shuffled => {
+                    // Failures above may have shrunk the memberlist under us
+                    if self.last_pinged >= self.memberlist.len() {
+                        self.memberlist.shuffle(&mut self.rng);
+                        self.last_pinged = 0;
+                    }
+                    let ping_rcpt = self.memberlist[self.last_pinged];
+                    self.last_pinged += 1;
+                    // Reshuffled partway through, so we may have just pinged them
+                    if probed.contains(&ping_rcpt) {
+                        continue;
+                    }
+                    ping_rcpt
+                }
+                ProbeSelection::LeastRecentlyProbed => match self.least_recently_probed(&probed) {
+                    Some(ping_rcpt) => ping_rcpt,
+                    None => break,
+                },
+            };
+            let ping_addr = self.membership.get(&ping_rcpt).unwrap().addr;
+            self.ping(ping_rcpt, ping_addr, None, now);
+            self.last_probed.insert(ping_rcpt, now);
+            probed.push(ping_rcpt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
+
+    /// A clock that only moves when told to
+    #[derive(Clone)]
+    struct MockClock(Rc<Cell<Instant>>);
+
+    impl MockClock {
+        fn new() -> Self {
+            MockClock(Rc::new(Cell::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    type Change = (PeerId, Option<PeerState>, PeerState);
+
+    /// Records every change it's told about
+    #[derive(Clone, Default)]
+    struct Changes(Rc<RefCell<Vec<Change>>>);
+
+    impl MembershipDelegate for Changes {
+        fn on_change(&mut self, peer: Peer, old: Option<PeerState>) {
+            self.0.borrow_mut().push((peer.id, old, peer.state));
+        }
+    }
+
+    fn addr(id: u64) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 8000 + id as u16))
+    }
+
+    /// A server that's already announced itself, so tests only see the news they cause
+    fn server(id: u64) -> Server {
+        let mut s = Server::new(
+            id.into(),
+            addr(id),
+            Duration::from_millis(10),
+            3,
+            Duration::from_millis(50),
+            Duration::from_millis(150),
+        );
+        s.broadcasts.clear();
+        s
+    }
+
+    fn server_with_clock(id: u64) -> (Server, MockClock) {
+        let clock = MockClock::new();
+        (server(id).with_clock(clock.clone()), clock)
+    }
+
+    fn message(src: u64, dest: u64, seq_no: u64, kind: MsgKind) -> Message {
+        Message {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_label: 0,
+            dest_id: dest.into(),
+            dest_addr: addr(dest),
+            src_id: src.into(),
+            src_addr: addr(src),
+            seq_no,
+            kind,
+            mac: Vec::new(),
+        }
+    }
+
+    /// Tell `s` about a live peer
+    fn meet<T: Transport>(s: &mut Server<T>, id: u64) {
+        s.process_rumor(Rumor {
+            peer_id: id.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::alive(addr(id)),
+        });
+    }
+
+    fn fail(s: &mut Server, id: u64) {
+        s.process_rumor(Rumor {
+            peer_id: id.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Failed,
+        });
+    }
+
+    /// Every rumor `s` has queued for gossip
+    fn rumors(s: &mut Server) -> Vec<Rumor> {
+        let mut buf = [0u8; 1024];
+        let len = s.gossip(&mut buf);
+        rumors_in(&buf[..len])
+    }
+
+    /// The rumors in a gossip buffer
+    fn rumors_in(buf: &[u8]) -> Vec<Rumor> {
+        let count = u16::from_le_bytes([buf[0], buf[1]]);
+        let mut rest = &buf[2..];
+        let mut rumors = Vec::new();
+        for _ in 0..count {
+            let (rumor, r) = Rumor::deserialize(rest).unwrap();
+            rumors.push(rumor);
+            rest = r;
+        }
+        rumors
+    }
+
+    fn probe(s: &mut Server, now: Instant) -> Vec<Message> {
+        s.probe(now);
+        s.outbox()
+    }
+
+    /// Process `msg`, returning our single response if there was one
+    fn respond(s: &mut Server, msg: Message) -> Option<Message> {
+        s.process(msg).unwrap();
+        let mut sent = s.outbox();
+        assert!(sent.len() <= 1, "{:?}", sent);
+        sent.pop()
+    }
+
+    fn pings(msgs: &[Message]) -> Vec<&Message> {
+        msgs.iter().filter(|m| m.kind == MsgKind::Ping).collect()
+    }
+
+    #[test]
+    fn tick_pings_random_peer() {
+        let mut s = server(0);
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        fail(&mut s, 3);
+        let mut pinged = HashSet::new();
+        let mut now = Instant::now();
+        for _ in 0..6 {
+            let msgs = probe(&mut s, now);
+            let pings = pings(&msgs);
+            assert_eq!(pings.len(), 1, "{:?}", msgs);
+            pinged.insert(pings[0].dest_id);
+            s.pings.clear();
+            now += s.protocol_period;
+        }
+        assert_eq!(pinged, HashSet::from([1.into(), 2.into()]));
+    }
+
+    #[test]
+    fn disseminate_gossips_to_random_peers() {
+        let mut s = server(0);
+        for id in 1..=4 {
+            meet(&mut s, id);
+        }
+        s.disseminate();
+        let msgs = s.outbox();
+        let dests: HashSet<_> = msgs.iter().map(|m| m.dest_id).collect();
+        assert_eq!(dests.len(), 3);
+        assert!(msgs.iter().all(|m| matches!(m.kind, MsgKind::Gossip(_))));
+
+        let msg = msgs[0].clone();
+        let mut b = server(msg.dest_id.0);
+        b.process(msg).unwrap();
+        for id in (1..=4).filter(|id| *id != b.id.0) {
+            assert!(b.contains(id.into()));
+        }
+    }
+
+    #[test]
+    fn backlogs_drain_over_several_gossip_messages() {
+        let gossip = |max| {
+            let mut s = server(0)
+                .with_gossip_fanout(1)
+                .with_max_piggyback(2)
+                .with_max_gossip_messages(max);
+            for id in 1..=5 {
+                meet(&mut s, id);
+            }
+            s.disseminate();
+            let msgs = s.outbox();
+            assert!(msgs.iter().all(|m| m.dest_id == msgs[0].dest_id));
+            msgs.into_iter()
+                .map(|m| match m.kind {
+                    MsgKind::Gossip(buf) => rumors_in(&buf).len(),
+                    kind => panic!("unexpected {:?}", kind),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(gossip(1), [2]);
+        // No rumor twice, and no empty message once they've all gone
+        assert_eq!(gossip(3), [2, 2, 1]);
+        assert_eq!(gossip(5), [2, 2, 1]);
+    }
+
+    #[test]
+    fn tick_probes_and_disseminates() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        s.tick(Instant::now());
+        let kinds: Vec<_> = s.outbox().into_iter().map(|m| m.kind).collect();
+        assert_eq!(kinds.len(), 2);
+        assert!(kinds.contains(&MsgKind::Ping));
+        assert!(kinds.iter().any(|k| matches!(k, MsgKind::Gossip(_))));
+    }
+
+    #[test]
+    fn full_queues_refuse_user_broadcasts() {
+        let mut s = server(0).with_broadcast_capacity(2);
+        s.broadcast_user(1, b"first".to_vec(), None).unwrap();
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        assert_eq!(
+            s.broadcast_user(1, b"second".to_vec(), None),
+            Err(BroadcastError::QueueFull)
+        );
+        assert_eq!(s.broadcast_queue_len(), 2);
+        assert_eq!(s.delivered.len(), 1);
+    }
+
+    #[test]
+    fn stale_broadcasts_retire() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_broadcast_capacity(2).with_broadcast_max_age(2);
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        assert_eq!(s.broadcast_queue_len(), 2);
+        clock.advance(Duration::from_millis(100));
+        meet(&mut s, 4);
+        assert_eq!(s.broadcast_queue_len(), 2);
+        // Only the rumor about peer 4 is younger than two protocol periods
+        clock.advance(Duration::from_millis(1));
+        s.probe(clock.now());
+        assert_eq!(s.broadcast_queue_len(), 1);
+        let peers: Vec<_> = rumors(&mut s).iter().map(|r| r.peer_id).collect();
+        assert_eq!(peers, vec![4.into()]);
+    }
+
+    #[test]
+    fn user_broadcasts_expire() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        while s.broadcast_queue_len() > 0 {
+            rumors(&mut s);
+        }
+        s.broadcast_user(1, b"short".to_vec(), Some(Duration::from_millis(30)))
+            .unwrap();
+        s.broadcast_user(2, b"long".to_vec(), None).unwrap();
+        let tags = |s: &mut Server| {
+            rumors(s)
+                .into_iter()
+                .filter_map(|r| match r.kind {
+                    RumorKind::User { tag, .. } => Some(tag),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tags(&mut s).len(), 2);
+        clock.advance(Duration::from_millis(30));
+        // Expired before it used up its sends
+        assert_eq!(tags(&mut s), [2]);
+        assert_eq!(s.broadcast_queue_len(), 1);
+    }
+
+    #[test]
+    fn membership_news_outranks_user_data() {
+        let mut s = server(0);
+        for id in 1..=4 {
+            meet(&mut s, id);
+        }
+        while s.broadcast_queue_len() > 0 {
+            rumors(&mut s);
+        }
+        s.broadcast_user(1, vec![7; 32], None).unwrap();
+        fail(&mut s, 1);
+        fail(&mut s, 2);
+        // Room for both failures or the user data, but not all three
+        let mut buf = [0u8; 64];
+        for _ in 0..3 {
+            let len = s.gossip(&mut buf);
+            let kinds: Vec<_> = rumors_in(&buf[..len]).into_iter().map(|r| r.kind).collect();
+            assert_eq!(kinds, [RumorKind::Failed, RumorKind::Failed]);
+        }
+        // With membership news out of the way, user data still only gets its share
+        let mut s = s.with_user_gossip_fraction(0.5);
+        let len = s.gossip(&mut buf);
+        assert!(rumors_in(&buf[..len]).is_empty());
+        let mut buf = [0u8; 128];
+        let len = s.gossip(&mut buf);
+        assert!(matches!(
+            rumors_in(&buf[..len])[..],
+            [Rumor {
+                kind: RumorKind::User { tag: 1, .. },
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn broadcasts_can_be_awaited() {
+        let mut s = server(0).with_max_piggyback(1);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        assert!(s.is_broadcasting(1.into(), 1.into()));
+        assert!(!s.is_broadcasting(1.into(), 2.into()));
+        assert!(!s.is_broadcasting(3.into(), 1.into()));
+        let mut sends = 0;
+        while s.is_broadcasting(1.into(), 1.into()) {
+            sends += rumors(&mut s)
+                .iter()
+                .filter(|r| r.peer_id == 1.into())
+                .count();
+        }
+        assert_eq!(sends, s.max_sends);
+        // Newer news replaces what we were sending
+        s.process_rumor(Rumor {
+            peer_id: 2.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::alive(addr(2)),
+        });
+        assert!(!s.is_broadcasting(2.into(), 1.into()));
+        assert!(s.is_broadcasting(2.into(), 2.into()));
+    }
+
+    #[test]
+    fn membership_can_stay_local() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_disseminate_membership(false);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        assert_eq!(s.members().count(), 3);
+        assert_eq!(s.broadcast_queue_len(), 0);
+        assert!(rumors(&mut s).is_empty());
+
+        // Failure detection carries on without telling anyone
+        let target = probe(&mut s, clock.now()).pop().unwrap().dest_id;
+        clock.advance(Duration::from_millis(20));
+        probe(&mut s, clock.now());
+        clock.advance(Duration::from_millis(40));
+        let events = s.probe(clock.now());
+        assert!(events.contains(&Event::StateChanged {
+            id: target,
+            from: PeerState::Alive,
+            to: PeerState::Suspect,
+        }));
+        assert!(rumors(&mut s).is_empty());
+
+        // but user broadcasts still go out
+        s.broadcast_user(1, b"hi".to_vec(), None).unwrap();
+        assert_eq!(rumors(&mut s).len(), 1);
+    }
+
+    #[test]
+    fn piggybacking_is_tunable() {
+        for multiplier in [1, 2] {
+            let mut s = server(0)
+                .with_max_piggyback(2)
+                .with_retransmit_multiplier(multiplier);
+            for id in 1..=3 {
+                meet(&mut s, id);
+            }
+            // Each of the 3 rumors goes out `multiplier` times, at most 2 to a buffer
+            let mut sent = Vec::new();
+            while s.broadcast_queue_len() > 0 {
+                let rumors = rumors(&mut s);
+                assert!(rumors.len() <= 2);
+                sent.extend(rumors);
+            }
+            assert_eq!(sent.len(), 3 * multiplier as usize);
+        }
+    }
+
+    #[test]
+    fn timing_scales_with_membership() {
+        let mut s = server(0);
+        for id in 1..=8 {
+            meet(&mut s, id);
+        }
+        assert_eq!(s.suspicion_period, Duration::from_millis(150));
+        assert_eq!(s.max_sends, 3);
+        // Neither gossiping nor probing changes it
+        rumors(&mut s);
+        probe(&mut s, Instant::now());
+        assert_eq!(s.suspicion_period, Duration::from_millis(150));
+        meet(&mut s, 9);
+        assert_eq!(s.suspicion_period, Duration::from_millis(300));
+        assert_eq!(s.max_sends, 6);
+    }
+
+    #[test]
+    fn timing_can_be_tuned_at_runtime() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        assert_eq!(s.ping_interval(), Duration::from_millis(10));
+        assert_eq!(s.protocol_period(), Duration::from_millis(50));
+        assert_eq!(s.suspicion_period(), Duration::from_millis(150));
+
+        assert_eq!(
+            s.set_ping_interval(Duration::from_millis(50)),
+            Err(ConfigError::PingIntervalTooLong {
+                ping_interval: Duration::from_millis(50),
+                protocol_period: Duration::from_millis(50),
+            })
+        );
+        assert_eq!(
+            s.set_protocol_period(Duration::ZERO),
+            Err(ConfigError::ZeroProtocolPeriod)
+        );
+        assert_eq!(
+            s.set_protocol_period(Duration::from_millis(10)),
+            Err(ConfigError::PingIntervalTooLong {
+                ping_interval: Duration::from_millis(10),
+                protocol_period: Duration::from_millis(10),
+            })
+        );
+        assert_eq!(s.ping_interval(), Duration::from_millis(10));
+        assert_eq!(s.protocol_period(), Duration::from_millis(50));
+
+        s.set_protocol_period(Duration::from_millis(100)).unwrap();
+        s.set_ping_interval(Duration::from_millis(40)).unwrap();
+        assert_eq!(s.ping_interval(), Duration::from_millis(40));
+        assert_eq!(s.suspicion_period(), Duration::from_millis(300));
+
+        // Pinned, it no longer follows the membership
+        s.set_suspicion_period(Duration::from_millis(500));
+        assert_eq!(s.suspicion_period(), Duration::from_millis(500));
+        for id in 2..=9 {
+            meet(&mut s, id);
+        }
+        assert_eq!(s.suspicion_period(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn suspicion_period_scales_from_the_given_base() {
+        let mut s = Server::new(
+            0.into(),
+            addr(0),
+            Duration::from_millis(10),
+            3,
+            Duration::from_millis(50),
+            Duration::from_millis(400),
+        );
+        meet(&mut s, 1);
+        assert_eq!(s.suspicion_period(), Duration::from_millis(400));
+        for id in 2..=9 {
+            meet(&mut s, id);
+        }
+        assert_eq!(s.suspicion_period(), Duration::from_millis(800));
+        s.set_protocol_period(Duration::from_millis(100)).unwrap();
+        assert_eq!(s.suspicion_period(), Duration::from_millis(1600));
+    }
+
+    #[test]
+    fn suspicion_period_is_clamped() {
+        let mut s = server(0).with_min_suspicion(Duration::from_millis(200));
+        meet(&mut s, 1);
+        assert_eq!(s.suspicion_period(), Duration::from_millis(200));
+        let mut s = s.with_max_suspicion(Duration::from_millis(250));
+        for id in 2..=9 {
+            meet(&mut s, id);
+        }
+        assert_eq!(s.suspicion_period(), Duration::from_millis(250));
+        let s = s.with_max_suspicion(Duration::MAX);
+        assert_eq!(s.suspicion_period(), Duration::from_millis(300));
+    }
+
+    /// Run `servers` for `rounds` protocol periods, delivering every message within the round
+    fn simulate(servers: &mut [Server], rounds: usize) {
+        let mut now = Instant::now();
+        for _ in 0..rounds {
+            now += Duration::from_millis(50);
+            let mut msgs = Vec::new();
+            for s in servers.iter_mut() {
+                s.tick(now);
+                msgs.extend(s.outbox());
+            }
+            while let Some(msg) = msgs.pop() {
+                let s = &mut servers[msg.dest_id.0 as usize];
+                s.process(msg).unwrap();
+                msgs.extend(s.outbox());
+            }
+        }
+    }
+
+    #[test]
+    fn fanout_spreads_rumors_in_idle_clusters() {
+        // Epidemics can miss the odd peer before a rumor's sends run out, so allow a few
+        for (fanout, reached) in [(0, 0..=0), (3, 45..=49)] {
+            let mut servers: Vec<Server> = (0..50)
+                .map(|id| server(id).with_gossip_fanout(fanout))
+                .collect();
+            for s in servers.iter_mut() {
+                let me = s.id.0;
+                for id in (0..50).filter(|id| *id != me) {
+                    meet(s, id);
+                }
+            }
+            servers[0].broadcast_user(1, vec![42], None).unwrap();
+            simulate(&mut servers, 10);
+            let heard = servers
+                .iter_mut()
+                .filter_map(|s| s.user_messages().pop())
+                .count();
+            assert!(
+                reached.contains(&heard),
+                "fanout {}: {} heard",
+                fanout,
+                heard
+            );
+        }
+    }
+
+    /// Records where everything was sent
+    #[derive(Default)]
+    struct MockTransport {
+        sent: Vec<(PeerId, MsgKind)>,
+    }
+
+    impl Transport for MockTransport {
+        fn send(&mut self, msg: &Message) {
+            self.sent.push((msg.dest_id, msg.kind.clone()));
+        }
+    }
+
+    #[test]
+    fn sends_through_transport() {
+        let mut s = Server::with_transport(
+            0.into(),
+            addr(0),
+            Duration::from_millis(10),
+            3,
+            Duration::from_millis(50),
+            Duration::from_millis(150),
+            MockTransport::default(),
+        );
+        meet(&mut s, 1);
+        s.probe(Instant::now());
+        s.process(message(2, 0, 9, MsgKind::Ping)).unwrap();
+        assert_eq!(
+            s.transport().sent,
+            vec![
+                (1.into(), MsgKind::Ping),
+                (2.into(), MsgKind::Ack(0.into(), 1.into()))
+            ]
+        );
+    }
+
+    #[test]
+    fn push_pull_only_chooses_live_peers() {
+        let mut s = server(0);
+        s.push_pull();
+        assert!(s.outbox().is_empty());
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        fail(&mut s, 2);
+        for _ in 0..20 {
+            s.push_pull();
+            let msg = s.outbox().pop().unwrap();
+            assert_eq!(msg.dest_id, 1.into());
+            assert!(matches!(msg.kind, MsgKind::Pull(_)));
+        }
+        assert_eq!(s.stats().sent.pulls, 20);
+    }
+
+    #[test]
+    fn pushes_respond_to_pulls() {
+        let mut s = server(1);
+        meet(&mut s, 2);
+        let resp = respond(&mut s, message(0, 1, 0, MsgKind::Pull(Vec::new())));
+        let resp = resp.expect("pulls should be answered");
+        assert_eq!(resp.dest_id, 0.into());
+        match resp.kind {
+            MsgKind::Push(peers) => {
+                let ids: HashSet<PeerId> = peers.iter().map(|p| p.id).collect();
+                assert!(ids.contains(&PeerId(1)));
+                assert!(ids.contains(&PeerId(2)));
+            }
+            kind => panic!("expected a push, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn digests_are_answered_with_news() {
+        let mut s = server(1);
+        for id in 2..=5 {
+            meet(&mut s, id);
+        }
+        fail(&mut s, 4);
+        let digest = |id: u64, state| PeerDigest {
+            id: id.into(),
+            incarnation: 1.into(),
+            state,
+        };
+        let resp = respond(
+            &mut s,
+            message(
+                0,
+                1,
+                0,
+                MsgKind::PullDigest(vec![
+                    digest(0, PeerState::Alive),
+                    digest(1, PeerState::Alive),
+                    digest(2, PeerState::Alive),
+                    digest(3, PeerState::Suspect),
+                    digest(4, PeerState::Alive),
+                ]),
+            ),
+        );
+        let mut ids = match resp.unwrap().kind {
+            MsgKind::Push(peers) => peers.iter().map(|p| p.id).collect::<Vec<_>>(),
+            kind => panic!("expected a push, got {:?}", kind),
+        };
+        ids.sort_by_key(|id| id.0);
+        assert_eq!(ids, vec![4.into(), 5.into()]);
+        assert!(s.contains(0.into()));
+    }
+
+    #[test]
+    fn large_clusters_pull_digests() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        s.push_pull();
+        assert!(matches!(s.outbox().pop().unwrap().kind, MsgKind::Pull(_)));
+        for id in 2..=20 {
+            meet(&mut s, id);
+        }
+        s.push_pull();
+        match s.outbox().pop().unwrap().kind {
+            MsgKind::PullDigest(digest) => assert_eq!(digest.len(), 21),
+            kind => panic!("expected a digest, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn pushes_are_sampled() {
+        let (s, clock) = server_with_clock(1);
+        let mut s = s.with_push_sample_size(5);
+        for id in (0..=30).filter(|id| *id != 1) {
+            meet(&mut s, id);
+            clock.advance(Duration::from_millis(1));
+        }
+        fail(&mut s, 7);
+        let mut seen = HashSet::new();
+        for seq_no in 0..20 {
+            let resp = respond(&mut s, message(0, 1, seq_no, MsgKind::Pull(Vec::new())));
+            let peers = match resp.unwrap().kind {
+                MsgKind::Push(peers) => peers,
+                kind => panic!("expected a push, got {:?}", kind),
+            };
+            assert_eq!(peers.len(), 5);
+            // Ourselves, then the latest changes
+            let ids: Vec<_> = peers.iter().map(|p| p.id).collect();
+            assert_eq!(ids[..3], [1.into(), 7.into(), 30.into()]);
+            seen.extend(ids);
+        }
+        // Everyone else still gets a turn
+        assert!(seen.len() > 10);
+    }
+
+    #[test]
+    fn large_pushes_keep_the_rotation_in_sync() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        let states = [
+            PeerState::Alive,
+            PeerState::Suspect,
+            PeerState::Failed,
+            PeerState::Departed,
+        ];
+        // Including news about us and conflicting news about the same peers
+        let peers: Vec<_> = (0..400u64)
+            .chain(0..100)
+            .enumerate()
+            .map(|(i, id)| Peer::new(id.into(), addr(id), (i as u64 % 3).into(), states[i % 4]))
+            .collect();
+        s.process(message(1, 0, 0, MsgKind::Push(peers))).unwrap();
+        s.tick(clock.now());
+        let mut rotation = s.memberlist.clone();
+        rotation.sort();
+        let mut probed: Vec<_> = s
+            .membership
+            .values()
+            .filter(|p| p.state.is_probed())
+            .map(|p| p.id)
+            .collect();
+        probed.sort();
+        assert!(probed.len() >= 100);
+        assert_eq!(rotation, probed);
+    }
+
+    #[test]
+    fn suspects_and_recent_failures_are_listed() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_tombstone_ttl(Duration::from_millis(100));
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Suspect { from: 2.into() },
+        });
+        let suspects: Vec<_> = s.suspects().into_iter().map(Peer::id).collect();
+        assert_eq!(suspects, [1.into()]);
+
+        let failed_at = clock.now();
+        fail(&mut s, 1);
+        assert!(s.suspects().is_empty());
+        clock.advance(Duration::from_millis(10));
+        fail(&mut s, 2);
+        assert_eq!(
+            s.recent_failures().collect::<Vec<_>>(),
+            [
+                (2.into(), failed_at + Duration::from_millis(10)),
+                (1.into(), failed_at)
+            ]
+        );
+        // Still there once they're forgotten
+        clock.advance(Duration::from_millis(200));
+        s.probe(clock.now());
+        assert!(!s.contains(1.into()));
+        assert_eq!(s.recent_failures().count(), 2);
+
+        // Only the latest are kept
+        for id in 10..50 {
+            meet(&mut s, id);
+            fail(&mut s, id);
+        }
+        let failures: Vec<_> = s.recent_failures().map(|(id, _)| id).collect();
+        assert_eq!(failures.len(), FAILURE_HISTORY_LEN);
+        assert_eq!(failures[0], 49.into());
+        s.reset();
+        assert_eq!(s.recent_failures().count(), 0);
+    }
+
+    #[test]
+    fn reset_forgets_the_cluster() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_tombstone_ttl(Duration::from_secs(1));
+        let incarnation = s.incarnation;
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        fail(&mut s, 3);
+        s.join_seeds(&[(4.into(), addr(4))]);
+        probe(&mut s, clock.now());
+        s.broadcast_user(1, b"hi".to_vec(), None).unwrap();
+        s.reset();
+
+        assert_eq!(s.id, 0.into());
+        assert_eq!(s.incarnation, incarnation);
+        assert_eq!(s.members().count(), 1);
+        assert!(s.probe_order().is_empty());
+        assert!(s.pings.is_empty());
+        // Only our own announcement, ready for the next cluster
+        assert_eq!(
+            rumors(&mut s),
+            vec![Rumor {
+                peer_id: 0.into(),
+                incarnation,
+                kind: RumorKind::alive(addr(0)),
+            }]
+        );
+        assert!(s.outbox().is_empty());
+        assert!(s.suspicions.is_empty() && s.tombstones.is_empty() && s.seeds.is_empty());
+        assert_eq!(s.debug_state().membership, vec![]);
+
+        // Old news is news again
+        meet(&mut s, 3);
+        assert!(s.contains(3.into()));
+        assert!(s.is_broadcasting(3.into(), 1.into()));
+    }
+
+    #[test]
+    fn servers_announce_themselves_on_startup() {
+        let fresh = || {
+            Server::new(
+                0.into(),
+                addr(0),
+                Duration::from_millis(10),
+                3,
+                Duration::from_millis(50),
+                Duration::from_millis(150),
+            )
+        };
+        assert_eq!(
+            rumors(&mut fresh()),
+            vec![Rumor {
+                peer_id: 0.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::alive(addr(0)),
+            }]
+        );
+        // Resuming announces the resumed incarnation instead
+        let mut s = fresh().with_incarnation(5.into());
+        assert_eq!(rumors(&mut s).len(), 1);
+        assert!(s.is_broadcasting(0.into(), 6.into()));
+        assert!(rumors(&mut fresh().with_disseminate_membership(false)).is_empty());
+
+        let mut s = Server::builder(0.into(), addr(0)).build().unwrap();
+        assert!(s.is_broadcasting(0.into(), 1.into()));
+        s.reset();
+        assert!(s.is_broadcasting(0.into(), 1.into()));
+    }
+
+    #[test]
+    fn joins_through_seeds() {
+        let (mut s, clock) = server_with_clock(0);
+        s.join_seeds(&[
+            (0.into(), addr(0)),
+            (1.into(), addr(1)),
+            (2.into(), addr(2)),
+        ]);
+        let pulled: Vec<_> = s.outbox().into_iter().map(|m| m.dest_id).collect();
+        assert_eq!(pulled, vec![1.into(), 2.into()]);
+        // Nobody answered, so we try them all again
+        clock.advance(Duration::from_millis(250));
+        let pulls = probe(&mut s, clock.now())
+            .into_iter()
+            .filter(|m| matches!(m.kind, MsgKind::Pull(_)))
+            .count();
+        assert_eq!(pulls, 2);
+        // One seed is enough
+        let peers = vec![Peer::new(3.into(), addr(3), 1.into(), PeerState::Alive)];
+        s.process(message(2, 0, 0, MsgKind::Push(peers))).unwrap();
+        assert!(s.seeded());
+        assert!(s.contains(3.into()));
+        clock.advance(Duration::from_millis(250));
+        let pulls = probe(&mut s, clock.now())
+            .into_iter()
+            .filter(|m| matches!(m.kind, MsgKind::Pull(_)))
+            .count();
+        assert_eq!(pulls, 0);
+    }
+
+    #[test]
+    fn isolated_nodes_rejoin_through_seeds() {
+        let (mut s, clock) = server_with_clock(0);
+        s.join_seeds(&[(1.into(), addr(1))]);
+        s.outbox();
+        let peers = vec![
+            Peer::new(1.into(), addr(1), 1.into(), PeerState::Alive),
+            Peer::new(2.into(), addr(2), 1.into(), PeerState::Alive),
+            Peer::new(0.into(), addr(0), 1.into(), PeerState::Failed),
+        ];
+        s.process(message(1, 0, 0, MsgKind::Push(peers))).unwrap();
+        // The seed thought we'd failed; we should have refuted that
+        assert_eq!(s.incarnation, 2.into());
+        assert!(!s.isolated());
+        // Then the network goes quiet
+        let mut pulls = Vec::new();
+        for _ in 0..100 {
+            clock.advance(Duration::from_millis(50));
+            let sent = probe(&mut s, clock.now());
+            if sent.iter().any(|m| matches!(m.kind, MsgKind::Pull(_))) {
+                pulls.push(clock.now());
+            }
+        }
+        assert!(s.isolated());
+        assert!(pulls.len() >= 3, "{:?}", pulls);
+        // Backing off between attempts
+        assert!(pulls[2] - pulls[1] > pulls[1] - pulls[0]);
+    }
+
+    #[test]
+    fn foreign_clusters_are_ignored() {
+        let mut staging = server(0).with_cluster_label(1);
+        let mut prod = server(1).with_cluster_label(2);
+        staging.join(1.into(), addr(1));
+        prod.join_seeds(&[(0.into(), addr(0))]);
+        let mut msgs = staging.outbox();
+        msgs.extend(prod.outbox());
+        while let Some(msg) = msgs.pop() {
+            let s = if msg.dest_id == 0.into() {
+                &mut staging
+            } else {
+                &mut prod
+            };
+            assert!(matches!(
+                s.process(msg),
+                Err(ProcessError::ForeignCluster(_))
+            ));
+            msgs.extend(s.outbox());
+        }
+        assert!(!staging.contains(1.into()));
+        assert!(!prod.contains(0.into()));
+    }
+
+    #[test]
+    fn misrouted_messages_are_dropped() {
+        let mut s = server(1);
+        assert_eq!(
+            s.process(message(0, 2, 7, MsgKind::Ping)),
+            Err(ProcessError::WrongRecipient(2.into()))
+        );
+        assert!(s.outbox().is_empty());
+        assert!(!s.contains(0.into()));
+    }
+
+    #[test]
+    fn incompatible_versions_are_dropped() {
+        #[derive(Default, Clone)]
+        struct Incompatible(Rc<RefCell<Vec<u16>>>);
+
+        impl Metrics for Incompatible {
+            fn incr_incompatible_messages(&self, version: u16) {
+                self.0.borrow_mut().push(version);
+            }
+        }
+
+        let metrics = Incompatible::default();
+        let mut s = server(1).with_metrics(metrics.clone());
+        let mut msg = message(0, 1, 7, MsgKind::Ping);
+        msg.protocol_version = PROTOCOL_VERSION + 0x100;
+        assert_eq!(
+            s.process(msg),
+            Err(ProcessError::IncompatibleVersion(PROTOCOL_VERSION + 0x100))
+        );
+        assert!(!s.contains(0.into()));
+        assert_eq!(*metrics.0.borrow(), vec![PROTOCOL_VERSION + 0x100]);
+        // Newer minor versions are still understood
+        let mut msg = message(0, 1, 7, MsgKind::Ping);
+        msg.protocol_version = PROTOCOL_VERSION + 1;
+        assert!(respond(&mut s, msg).is_some());
+    }
+
+    #[test]
+    fn replayed_messages_are_dropped() {
+        #[derive(Default, Clone)]
+        struct Replays(Rc<Cell<usize>>);
+
+        impl Metrics for Replays {
+            fn incr_replayed_messages(&self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let metrics = Replays::default();
+        let mut s = server(1).with_metrics(metrics.clone());
+        let ping = message(0, 1, 7, MsgKind::Ping);
+        assert!(respond(&mut s, ping.clone()).is_some());
+        assert_eq!(s.process(ping), Err(ProcessError::Replayed(0.into(), 7)));
+        assert!(respond(&mut s, message(0, 1, 5, MsgKind::Ping)).is_some());
+        assert_eq!(metrics.0.get(), 1);
+
+        // Restarted peers pick up from the clock, well ahead of where they were
+        let mut peer = server(0);
+        peer.join(1.into(), addr(1));
+        let pull = peer.outbox().pop().unwrap();
+        assert!(respond(&mut s, pull).is_some());
+        assert_eq!(metrics.0.get(), 1);
+    }
+
+    #[test]
+    fn restarted_peers_are_heard_again() {
+        let mut s = server(1);
+        assert!(respond(&mut s, message(0, 1, 1000, MsgKind::Ping)).is_some());
+        // Restarted without a wall clock, so its sequence numbers started over
+        assert_eq!(
+            s.process(message(0, 1, 5, MsgKind::Ping)),
+            Err(ProcessError::Replayed(0.into(), 5))
+        );
+        // until we hear of it at a higher incarnation
+        s.process_rumor(Rumor {
+            peer_id: 0.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::alive(addr(0)),
+        });
+        assert!(respond(&mut s, message(0, 1, 6, MsgKind::Ping)).is_some());
+        assert_eq!(
+            s.process(message(0, 1, 6, MsgKind::Ping)),
+            Err(ProcessError::Replayed(0.into(), 6))
+        );
+        // or it tells us itself while rejoining
+        let rejoined = Peer::new(0.into(), addr(0), 3.into(), PeerState::Alive);
+        let pull = message(0, 1, 6, MsgKind::Pull(vec![rejoined.clone()]));
+        assert!(respond(&mut s, pull).is_some());
+        // which only works once
+        let pull = message(0, 1, 6, MsgKind::Pull(vec![rejoined]));
+        assert_eq!(s.process(pull), Err(ProcessError::Replayed(0.into(), 6)));
+    }
+
+    #[test]
+    fn only_members_get_replay_windows() {
+        let mut s = server(0).with_peer_filter(|_: &Peer| None);
+        for id in 1..=100 {
+            s.process(message(id, 0, 7, MsgKind::Ping)).unwrap();
+        }
+        assert!(s.replay_windows.is_empty());
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn unauthenticated_messages_are_dropped() {
+        #[derive(Default, Clone)]
+        struct Unauthenticated(Rc<Cell<usize>>);
+
+        impl Metrics for Unauthenticated {
+            fn incr_unauthenticated_messages(&self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let metrics = Unauthenticated::default();
+        let mut s = server(1)
+            .with_secret_key("new")
+            .with_secret_key("old")
+            .with_metrics(metrics.clone());
+        assert_eq!(
+            s.process(message(0, 1, 7, MsgKind::Ping)),
+            Err(ProcessError::Unauthenticated(0.into()))
+        );
+        assert!(!s.contains(0.into()));
+        assert_eq!(metrics.0.get(), 1);
+
+        // Peers still signing with the old key are understood while it's rotated out
+        let mut peer = server(0).with_secret_key("old");
+        peer.join(1.into(), addr(1));
+        let pull = peer.outbox().pop().unwrap();
+        s.process(pull).unwrap();
+        assert!(s.contains(0.into()));
+        // but they can't read what we sign with the new one until they add it
+        let push = s.outbox().pop().unwrap();
+        assert!(peer.process(push.clone()).is_err());
+        assert!(!peer.contains(1.into()));
+        peer.set_secret_keys(vec![b"old".to_vec(), b"new".to_vec()]);
+        peer.process(push).unwrap();
+        assert!(peer.contains(1.into()));
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn piggybacked_gossip_is_signed() {
+        let mut s = server(0).with_secret_key("key");
+        let mut peer = server(1).with_secret_key("key");
+        meet(&mut s, 1);
+        let mut ping = probe(&mut s, Instant::now()).pop().unwrap();
+        s.broadcasts.clear();
+        meet(&mut s, 2);
+        let mut buf = [0u8; 1024];
+        let len = s.piggyback(&mut ping, &mut buf);
+        let mut gossip = buf[..len].to_vec();
+        *gossip.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            peer.process_with_gossip(ping.clone(), &gossip),
+            Err(ProcessError::Unauthenticated(0.into()))
+        );
+        assert!(!peer.contains(2.into()));
+        peer.process_with_gossip(ping, &buf[..len]).unwrap();
+        assert!(peer.contains(2.into()));
+    }
+
+    #[test]
+    fn members_include_ourselves() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        fail(&mut s, 2);
+        assert_eq!(s.members().next().unwrap().id(), 0.into());
+        let mut ids: Vec<_> = s.members().map(|p| p.id()).collect();
+        ids.sort();
+        assert_eq!(ids, [0.into(), 1.into(), 2.into()]);
+        let mut owned: Vec<_> = s.live_members().iter().map(|p| p.id()).collect();
+        owned.sort();
+        assert_eq!(owned, [0.into(), 1.into(), 2.into()]);
+    }
+
+    #[test]
+    fn we_keep_our_own_entry_up_to_date() {
+        let mut s = server(0);
+        s.set_meta(b"db".to_vec()).unwrap();
+        s.leave();
+        let us = s.members().next().unwrap();
+        assert_eq!(us.incarnation(), 2.into());
+        assert_eq!(us.meta(), b"db");
+        assert_eq!(us.state(), PeerState::Departed);
+        s.reset();
+        assert_eq!(s.members().next().unwrap().state(), PeerState::Alive);
+    }
+
+    #[test]
+    fn pulls_update_state() {
+        let mut s = server(0);
+        let peers = vec![
+            Peer::new(1.into(), addr(1), 1.into(), PeerState::Alive),
+            Peer::new(2.into(), addr(2), 3.into(), PeerState::Alive),
+        ];
+        s.process(message(1, 0, 0, MsgKind::Pull(peers))).unwrap();
+        let peer = s.membership.get(&PeerId(2)).expect("should learn about 2");
+        assert_eq!(peer.state, PeerState::Alive);
+        assert_eq!(peer.incarnation, 3.into());
+        assert!(s.memberlist.contains(&PeerId(2)));
+    }
+
+    #[test]
+    fn pings_are_acked() {
+        let mut s = server(1);
+        let resp = respond(&mut s, message(0, 1, 7, MsgKind::Ping));
+        assert_eq!(
+            resp,
+            Some(message(1, 0, 7, MsgKind::Ack(1.into(), 1.into())))
+        );
+    }
+
+    #[test]
+    fn stats_count_traffic() {
+        let (mut s, clock) = server_with_clock(0);
+        assert_eq!(s.stats(), Stats::default());
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        probe(&mut s, clock.now());
+        s.process(message(2, 0, 7, MsgKind::Ping)).unwrap();
+        s.outbox();
+        // Replays aren't counted as received
+        s.process(message(2, 0, 7, MsgKind::Ping)).unwrap_err();
+        let gossiped = rumors(&mut s).len() as u64;
+        assert!(gossiped > 0);
+
+        let mut other = server(3);
+        meet(&mut other, 4);
+        let mut buf = [0u8; 1024];
+        let len = other.gossip(&mut buf);
+        s.process_gossip(&buf[..len]).unwrap();
+
+        let stats = s.stats();
+        assert_eq!(stats.sent.pings, 1);
+        assert_eq!(stats.sent.acks, 1);
+        assert_eq!(stats.sent.total(), 2);
+        assert_eq!(stats.received.pings, 1);
+        assert_eq!(stats.received.total(), 1);
+        assert_eq!(stats.rumors_sent, gossiped);
+        assert_eq!(stats.rumors_received, 1);
+        assert_eq!(stats.broadcast_queue_len, s.broadcast_queue_len());
+        assert_eq!(stats.pending_pings, 1);
+    }
+
+    #[test]
+    fn losing_every_peer_to_failure_loses_the_cluster() {
+        let (mut s, clock) = server_with_clock(0);
+        let lost = |s: &mut Server, clock: &MockClock| -> Vec<Vec<PeerId>> {
+            s.tick(clock.now())
+                .into_iter()
+                .filter_map(|e| match e {
+                    Event::ClusterLost(failed) => Some(failed),
+                    _ => None,
+                })
+                .collect()
+        };
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        fail(&mut s, 1);
+        clock.advance(Duration::from_millis(10));
+        fail(&mut s, 2);
+        assert!(lost(&mut s, &clock).is_empty());
+        fail(&mut s, 3);
+        assert_eq!(
+            lost(&mut s, &clock),
+            vec![vec![3.into(), 2.into(), 1.into()]]
+        );
+
+        // Only failures within the window are blamed
+        meet(&mut s, 4);
+        clock.advance(Duration::from_secs(1));
+        fail(&mut s, 4);
+        assert_eq!(lost(&mut s, &clock), vec![vec![4.into()]]);
+
+        // Peers leaving gracefully winds the cluster down instead
+        meet(&mut s, 5);
+        meet(&mut s, 6);
+        s.process_rumor(Rumor {
+            peer_id: 5.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Depart,
+        });
+        fail(&mut s, 6);
+        assert!(lost(&mut s, &clock).is_empty());
+
+        meet(&mut s, 7);
+        meet(&mut s, 8);
+        s.evict(7.into());
+        fail(&mut s, 8);
+        assert!(lost(&mut s, &clock).is_empty());
+    }
+
+    #[test]
+    fn pingreqs_beget_pings() {
+        let mut s = server(1);
+        let resp = respond(
+            &mut s,
+            message(
+                0,
+                1,
+                7,
+                MsgKind::PingReq {
+                    target_id: 2.into(),
+                    target: addr(2),
+                },
+            ),
+        );
+        let ping = resp.expect("should ping the target");
+        assert_eq!(ping.kind, MsgKind::Ping);
+        assert_eq!(ping.dest_id, 2.into());
+        assert_eq!(ping.dest_addr, addr(2));
+    }
+
+    #[test]
+    fn pingreqs_about_ourselves_are_answered_directly() {
+        let mut s = server(1);
+        let resp = respond(
+            &mut s,
+            message(
+                0,
+                1,
+                7,
+                MsgKind::PingReq {
+                    target_id: 1.into(),
+                    target: addr(1),
+                },
+            ),
+        );
+        assert_eq!(
+            resp,
+            Some(message(1, 0, 7, MsgKind::Ack(1.into(), s.incarnation)))
+        );
+    }
+
+    #[test]
+    fn pingreq_acks_are_forwarded() {
+        let mut s = server(1);
+        let ping = respond(
+            &mut s,
+            message(
+                0,
+                1,
+                7,
+                MsgKind::PingReq {
+                    target_id: 2.into(),
+                    target: addr(2),
+                },
+            ),
+        )
+        .unwrap();
+        let resp = respond(
+            &mut s,
+            message(2, 1, ping.seq_no, MsgKind::Ack(2.into(), 4.into())),
+        );
+        assert_eq!(
+            resp,
+            Some(message(1, 0, 7, MsgKind::Ack(2.into(), 4.into())))
+        );
+    }
+
+    #[test]
+    fn gossip_flows_through_indirect_acks() {
+        let clock = MockClock::new();
+        let mut servers: Vec<_> = (0..3)
+            .map(|id| server(id).with_clock(clock.clone()))
+            .collect();
+        for s in servers.iter_mut() {
+            for id in 0..3 {
+                if id != s.id.0 {
+                    meet(s, id);
+                }
+            }
+            s.broadcasts.clear();
+        }
+        let target = probe(&mut servers[0], clock.now()).pop().unwrap().dest_id;
+        // Only the target knows about peer 3
+        meet(&mut servers[target.0 as usize], 3);
+        clock.advance(Duration::from_millis(20));
+        servers[0].probe(clock.now());
+
+        // Deliver everything with its sender's gossip, the way drivers do, except between
+        // us and the target
+        let cut_off = |m: &Message| {
+            m.src_id == 0.into() && m.dest_id == target
+                || m.src_id == target && m.dest_id == 0.into()
+        };
+        for _ in 0..8 {
+            let mut in_flight = Vec::new();
+            for s in servers.iter_mut() {
+                for msg in s.outbox() {
+                    let mut gossip = vec![0; GOSSIP_BUFFER_SIZE];
+                    let len = s.gossip(&mut gossip);
+                    gossip.truncate(len);
+                    in_flight.push((msg, gossip));
+                }
+            }
+            for (msg, gossip) in in_flight {
+                if cut_off(&msg) {
+                    continue;
+                }
+                let dest = &mut servers[msg.dest_id.0 as usize];
+                dest.process(msg).unwrap();
+                dest.process_gossip(&gossip).unwrap();
+            }
+        }
+        // The target answered through the helper, and its news came along
+        assert!(!servers[0].pings.keys().any(|(id, _)| *id == target));
+        assert!(servers[0].contains(3.into()));
+    }
+
+    #[test]
+    fn pingreqs_for_strangers_dont_make_members() {
+        let (mut s, clock) = server_with_clock(1);
+        let pingreq = |seq_no| {
+            message(
+                0,
+                1,
+                seq_no,
+                MsgKind::PingReq {
+                    target_id: 2.into(),
+                    target: addr(2),
+                },
+            )
+        };
+        // Answered
+        let ping = respond(&mut s, pingreq(7)).unwrap();
+        s.process(message(2, 1, ping.seq_no, MsgKind::Ack(2.into(), 1.into())))
+            .unwrap();
+        assert_eq!(
+            s.outbox(),
+            vec![message(1, 0, 7, MsgKind::Ack(2.into(), 1.into()))]
+        );
+        assert!(!s.contains(2.into()));
+
+        // Unanswered
+        respond(&mut s, pingreq(8)).unwrap();
+        clock.advance(Duration::from_millis(20));
+        let events = s.probe(clock.now());
+        assert!(s.outbox().contains(&message(
+            1,
+            0,
+            8,
+            MsgKind::Nack {
+                target_id: 2.into()
+            }
+        )));
+        assert!(!s.pings.keys().any(|(id, _)| *id == 2.into()));
+        assert!(!s.contains(2.into()));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::Joined(p) if p.id == 2.into())));
+    }
+
+    #[test]
+    fn pings_to_the_same_peer_are_tracked_separately() {
+        let mut s = server(1);
+        meet(&mut s, 2);
+        let probe = probe(&mut s, Instant::now()).pop().unwrap();
+        assert_eq!(probe.dest_id, 2.into());
+        let relayed = respond(
+            &mut s,
+            message(
+                0,
+                1,
+                7,
+                MsgKind::PingReq {
+                    target_id: 2.into(),
+                    target: addr(2),
+                },
+            ),
+        )
+        .unwrap();
+        assert_ne!(probe.seq_no, relayed.seq_no);
+        // Our own probe still gets its answer
+        s.process(message(
+            2,
+            1,
+            probe.seq_no,
+            MsgKind::Ack(2.into(), 1.into()),
+        ))
+        .unwrap();
+        assert!(s.pings.contains_key(&(2.into(), relayed.seq_no)));
+        let resp = respond(
+            &mut s,
+            message(2, 1, relayed.seq_no, MsgKind::Ack(2.into(), 1.into())),
+        );
+        assert_eq!(
+            resp,
+            Some(message(1, 0, 7, MsgKind::Ack(2.into(), 1.into())))
+        );
+        assert!(s.pings.is_empty());
+    }
+
+    #[test]
+    fn late_acks_are_suspect() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        s.probe(clock.now());
+        rumors(&mut s);
+        clock.advance(Duration::from_millis(60));
+        s.probe(clock.now());
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Suspect { from: 0.into() },
+        }));
+    }
+
+    #[test]
+    fn timely_acks_clear_suspicion() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        let ping = probe(&mut s, clock.now()).pop().unwrap();
+        let target = ping.dest_id;
+        clock.advance(Duration::from_millis(60));
+        s.probe(clock.now());
+        s.process(message(
+            target.0,
+            0,
+            ping.seq_no,
+            MsgKind::Ack(target, 1.into()),
+        ))
+        .unwrap();
+        assert!(!s.pings.contains_key(&(target, ping.seq_no)));
+        assert_eq!(s.membership.get(&target).unwrap().state, PeerState::Alive);
+    }
+
+    #[test]
+    fn acks_match_across_seq_no_wraparound() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        s.seq_no = u64::MAX - 1;
+        let mut seq_nos = Vec::new();
+        for _ in 0..3 {
+            let ping = probe(&mut s, clock.now()).pop().unwrap();
+            assert!(s.pings.contains_key(&(1.into(), ping.seq_no)));
+            s.process(message(1, 0, ping.seq_no, MsgKind::Ack(1.into(), 1.into())))
+                .unwrap();
+            assert!(s.pings.is_empty());
+            seq_nos.push(ping.seq_no);
+            clock.advance(Duration::from_millis(50));
+        }
+        assert_eq!(seq_nos, [u64::MAX, 0, 1]);
+        assert_eq!(s.consecutive_failed_probes, 0);
+        assert_eq!(s.peer(1.into()).unwrap().state(), PeerState::Alive);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn wide_seq_nos_and_incarnations_survive_the_wire() {
+        let big = u64::from(u32::MAX) + 7;
+        let msg = message(1, 0, big, MsgKind::Ack(1.into(), big.into()));
+        let decoded = Message::from_bytes(&msg.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.seq_no, big);
+        assert_eq!(decoded, msg);
+
+        let rumor = Rumor {
+            peer_id: 1.into(),
+            incarnation: big.into(),
+            kind: RumorKind::alive(addr(1)),
+        };
+        let bytes = rumor.serialize();
+        let (decoded, rest) = Rumor::deserialize(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.incarnation, Incarnation::from(big));
+
+        // A 32-bit truncation would make this look older than what we know
+        let mut s = server(0);
+        meet(&mut s, 1);
+        s.process(message(
+            1,
+            0,
+            1,
+            MsgKind::Push(vec![Peer::new(
+                1.into(),
+                addr(1),
+                big.into(),
+                PeerState::Alive,
+            )]),
+        ))
+        .unwrap();
+        assert_eq!(s.peer(1.into()).unwrap().incarnation, big.into());
+    }
+
+    #[test]
+    fn phi_accrual_suspects_unusual_silence() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_phi_threshold(8.0);
+        meet(&mut s, 1);
+        assert_eq!(s.phi(1.into()), None);
+        for _ in 0..5 {
+            let ping = probe(&mut s, clock.now()).pop().unwrap();
+            s.process(message(1, 0, ping.seq_no, MsgKind::Ack(1.into(), 1.into())))
+                .unwrap();
+            clock.advance(Duration::from_millis(50));
+        }
+        // A timed out probe alone no longer raises suspicion
+        probe(&mut s, clock.now());
+        clock.advance(Duration::from_millis(15));
+        probe(&mut s, clock.now());
+        assert!(s.phi(1.into()).unwrap() < 8.0);
+        assert_eq!(s.peer(1.into()).unwrap().state(), PeerState::Alive);
+        // but staying quiet well past the usual interval does
+        clock.advance(Duration::from_millis(20));
+        probe(&mut s, clock.now());
+        assert_eq!(s.peer(1.into()).unwrap().state(), PeerState::Suspect);
+    }
+
+    #[test]
+    fn probes_are_jittered_within_bounds() {
+        let (mut s, clock) = server_with_clock(0);
+        let start = clock.now();
+        assert_eq!(s.next_probe(), start);
+        s.probe(start);
+        assert_eq!(s.next_probe(), start + s.protocol_period);
+
+        let mut s = s.with_probe_jitter(0.5).with_rng(StdRng::seed_from_u64(63));
+        let mut now = s.next_probe();
+        let gaps: HashSet<_> = (0..20)
+            .map(|_| {
+                s.probe(now);
+                let gap = s.next_probe() - now;
+                now = s.next_probe();
+                gap
+            })
+            .collect();
+        assert!(gaps.len() > 1);
+        assert!(gaps
+            .iter()
+            .all(|gap| { *gap >= Duration::from_millis(25) && *gap <= Duration::from_millis(75) }));
+
+        // Out of range fractions are clamped rather than scheduling probes in the past
+        let mut s = s.with_probe_jitter(2.0);
+        for _ in 0..20 {
+            s.probe(now);
+            assert!(s.next_probe() <= now + Duration::from_millis(100));
+            now = s.next_probe();
+        }
+        for fraction in [-1.0, f64::NAN] {
+            s = s.with_probe_jitter(fraction);
+            s.probe(now);
+            assert_eq!(s.next_probe(), now + s.protocol_period);
+            now = s.next_probe();
+        }
+    }
+
+    #[test]
+    fn late_ticks_catch_up() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_probes_per_period(3);
+        let start = clock.now();
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        let mut pinged = HashSet::new();
+        for _ in 0..3 {
+            pinged.extend(probe(&mut s, clock.now()).into_iter().map(|m| m.dest_id));
+            clock.advance(Duration::from_millis(3));
+        }
+        assert_eq!(pinged.len(), 3);
+        // Probing off schedule leaves it be
+        assert_eq!(s.next_probe(), start + Duration::from_millis(50));
+
+        // Stalled for well over three periods
+        clock.advance(Duration::from_millis(171));
+        s.tick(clock.now());
+        // Too late to ask anyone else, so every probe fails outright
+        assert_eq!(s.suspicions.len(), 3);
+        assert_eq!(s.consecutive_failed_probes, 3);
+        assert!(pinged
+            .iter()
+            .all(|id| s.peer(*id).unwrap().state() == PeerState::Suspect));
+        // leaving only the probes we just sent
+        assert_eq!(s.pings.len(), 3);
+        assert!(!s
+            .outbox()
+            .iter()
+            .any(|m| matches!(m.kind, MsgKind::PingReq { .. })));
+        // The next probe keeps to the schedule
+        assert_eq!(s.next_probe(), start + Duration::from_millis(200));
+    }
+
+    #[test]
+    fn debug_state_shows_everything() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 2);
+        meet(&mut s, 1);
+        let ping = probe(&mut s, clock.now()).pop().unwrap();
+        clock.advance(Duration::from_millis(5));
+        let state = s.debug_state();
+        assert_eq!(state.id, 0.into());
+        assert_eq!(state.incarnation, s.incarnation);
+        assert_eq!(state.seq_no, s.seq_no);
+        let ids: Vec<_> = state.membership.iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![1.into(), 2.into()]);
+        assert_eq!(state.probe_order, s.memberlist);
+        assert_eq!(state.probe_order[state.next_probe - 1], ping.dest_id);
+        assert_eq!(
+            state.pings,
+            vec![PendingPingState {
+                target: ping.dest_id,
+                seq_no: ping.seq_no,
+                state: PingState::Normal,
+                requester: 0.into(),
+                age: Duration::from_millis(5),
+            }]
+        );
+        let queued: HashSet<_> = state.broadcasts.iter().map(|b| b.rumor.peer_id).collect();
+        assert_eq!(queued, HashSet::from([1.into(), 2.into()]));
+        assert!(state.broadcasts.iter().all(|b| b.sends == 0));
+        assert!(s.debug_dump().contains("probe_order"));
+    }
+
+    #[test]
+    fn probe_order_is_visible() {
+        let (mut s, clock) = server_with_clock(0);
+        assert_eq!(s.next_probe_target(), None);
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        assert_eq!(s.probe_order().len(), 3);
+        // A fresh rotation starts with a shuffle
+        let first = probe(&mut s, clock.now()).pop().unwrap().dest_id;
+        let order = s.probe_order().to_vec();
+        assert_eq!(order[0], first);
+        for expected in &order[1..] {
+            assert_eq!(s.next_probe_target(), Some(*expected));
+            clock.advance(Duration::from_millis(50));
+            assert_eq!(
+                probe(&mut s, clock.now()).last().unwrap().dest_id,
+                *expected
+            );
+        }
+        assert_eq!(s.next_probe_target(), None);
+    }
+
+    #[test]
+    fn deterministic_inserts_append() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_deterministic_insert(true);
+        for id in 1..=5 {
+            meet(&mut s, id);
+        }
+        let ids: Vec<PeerId> = (1..=5).map(PeerId::from).collect();
+        assert_eq!(s.probe_order(), &ids[..]);
+        probe(&mut s, clock.now());
+        let mut order = s.probe_order().to_vec();
+        meet(&mut s, 6);
+        order.push(6.into());
+        assert_eq!(s.probe_order(), &order[..]);
+    }
+
+    #[test]
+    fn seq_distances_wrap() {
+        assert_eq!(seq_distance(5, 7), 2);
+        assert_eq!(seq_distance(7, 5), -2);
+        assert_eq!(seq_distance(u64::MAX, 1), 2);
+        assert_eq!(seq_distance(1, u64::MAX), -2);
+    }
+
+    #[test]
+    fn recognize_failed_peer() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        let target = probe(&mut s, clock.now()).pop().unwrap().dest_id;
+        clock.advance(Duration::from_millis(20));
+        let msgs = probe(&mut s, clock.now());
+        assert!(msgs
+            .iter()
+            .any(|m| matches!(m.kind, MsgKind::PingReq { target_id, .. } if target_id == target)));
+        // Past the suspicion period for the target, but not the peer we pinged in between
+        clock.advance(Duration::from_millis(140));
+        s.probe(clock.now());
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: target,
+            incarnation: 1.into(),
+            kind: RumorKind::Failed,
+        }));
+    }
+
+    #[test]
+    fn failed_peers_are_tombstoned() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_tombstone_ttl(Duration::from_secs(1));
+        meet(&mut s, 1);
+        fail(&mut s, 1);
+        // Stale gossip can't resurrect them
+        meet(&mut s, 1);
+        assert_eq!(
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Failed
+        );
+
+        clock.advance(Duration::from_millis(999));
+        s.probe(clock.now());
+        assert!(s.membership.contains_key(&PeerId(1)));
+
+        // A restarted peer comes back with a fresh incarnation once we've forgotten them
+        clock.advance(Duration::from_millis(1));
+        s.probe(clock.now());
+        assert!(!s.membership.contains_key(&PeerId(1)));
+        assert!(s.tombstones.is_empty());
+        meet(&mut s, 1);
+        assert_eq!(
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Alive
+        );
+    }
+
+    #[test]
+    fn unknown_failed_peers_are_kept_out() {
+        let mut s = server(0);
+        let failed = Rumor {
+            peer_id: 5.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::Failed,
+        };
+        s.process_rumor(failed.clone());
+        assert_eq!(rumors(&mut s), vec![failed]);
+        // Neither stale news nor the dead peer itself gets it back in
+        s.process_rumor(Rumor {
+            peer_id: 5.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::alive(addr(5)),
+        });
+        s.process(message(5, 0, 1, MsgKind::Ping)).unwrap();
+        assert!(!s.contains(5.into()));
+        s.process_rumor(Rumor {
+            peer_id: 5.into(),
+            incarnation: 3.into(),
+            kind: RumorKind::alive(addr(5)),
+        });
+        assert_eq!(s.peer(5.into()).unwrap().state(), PeerState::Alive);
+    }
+
+    #[test]
+    fn metadata_spreads_with_alive_rumors() {
+        let mut a = server(0);
+        let mut b = server(1);
+        meet(&mut b, 0);
+        a.set_meta(b"zone=a".to_vec()).unwrap();
+        let gossip = |from: &mut Server, to: &mut Server| {
+            for rumor in rumors(from) {
+                to.process_rumor(rumor);
+            }
+        };
+        gossip(&mut a, &mut b);
+        assert_eq!(b.peer(0.into()).unwrap().meta(), b"zone=a");
+        let stale = b.peer(0.into()).unwrap().rumor(1.into());
+        a.set_meta(b"zone=b".to_vec()).unwrap();
+        gossip(&mut a, &mut b);
+        assert_eq!(b.peer(0.into()).unwrap().meta(), b"zone=b");
+        // Older metadata can't come back
+        b.process_rumor(stale);
+        assert_eq!(b.peer(0.into()).unwrap().meta(), b"zone=b");
+        assert_eq!(
+            a.set_meta(vec![0; MAX_META + 1]),
+            Err(BroadcastError::MetaTooLarge(MAX_META + 1))
+        );
+    }
+
+    #[test]
+    fn oversized_metadata_is_turned_away() {
+        let mut s = server(0);
+        let peers = vec![
+            Peer {
+                meta: vec![0; MAX_META + 1],
+                ..Peer::new(1.into(), addr(1), 1.into(), PeerState::Alive)
+            },
+            Peer::new(2.into(), addr(2), 1.into(), PeerState::Alive),
+        ];
+        s.process(message(2, 0, 0, MsgKind::Push(peers))).unwrap();
+        assert!(!s.contains(1.into()));
+        assert!(s.contains(2.into()));
+        // What we gossip still reads back
+        let mut buf = [0u8; 1024];
+        let len = s.gossip(&mut buf);
+        assert!(rumors_in(&buf[..len]).iter().all(|r| r.peer_id == 2.into()));
+    }
+
+    #[test]
+    fn helpers_are_chosen_by_preference() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_helper_preference(|target, helper| target.meta() == helper.meta());
+        for id in 1..=6 {
+            let zone = if id <= 3 { b"a" } else { b"b" };
+            s.process_rumor(Rumor {
+                peer_id: id.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::Alive {
+                    addr: addr(id),
+                    meta: zone.to_vec(),
+                },
+            });
+        }
+        let target = probe(&mut s, clock.now()).pop().unwrap().dest_id;
+        clock.advance(Duration::from_millis(20));
+        let helpers: Vec<_> = probe(&mut s, clock.now())
+            .into_iter()
+            .filter(|m| matches!(m.kind, MsgKind::PingReq { .. }))
+            .map(|m| m.dest_id)
+            .collect();
+        // Both of the target's zone mates, and one other to make up the subgroup
+        assert_eq!(helpers.len(), 3);
+        let zone = |id: PeerId| (id.0 - 1) / 3;
+        assert_eq!(
+            helpers.iter().filter(|h| zone(**h) == zone(target)).count(),
+            2
+        );
+        assert!(!helpers.contains(&target));
+    }
+
+    #[test]
+    fn suspects_dont_help() {
+        let (mut s, clock) = server_with_clock(0);
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        for id in 2..=3 {
+            s.process_rumor(Rumor {
+                peer_id: id.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::Suspect { from: 1.into() },
+            });
+        }
+        let target = probe(&mut s, clock.now()).pop().unwrap().dest_id;
+        clock.advance(Duration::from_millis(20));
+        let helpers: Vec<_> = probe(&mut s, clock.now())
+            .into_iter()
+            .filter(|m| matches!(m.kind, MsgKind::PingReq { .. }))
+            .map(|m| m.dest_id)
+            .collect();
+        if target == 1.into() {
+            // Nobody left to ask, so it's suspected straight away
+            assert!(helpers.is_empty());
+            assert_eq!(s.peer(target).unwrap().state, PeerState::Suspect);
+        } else {
+            assert_eq!(helpers, vec![1.into()]);
+        }
+    }
+
+    #[test]
+    fn probes_can_favor_the_least_recently_probed() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_probe_selection(ProbeSelection::LeastRecentlyProbed);
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        let next = |s: &mut Server| {
+            clock.advance(Duration::from_millis(50));
+            let target = s.next_probe_target();
+            let pings: Vec<_> = probe(s, clock.now())
+                .into_iter()
+                .filter(|m| m.kind == MsgKind::Ping)
+                .map(|m| m.dest_id)
+                .collect();
+            assert_eq!(pings.len(), 1);
+            assert_eq!(Some(pings[0]), target);
+            s.pings.clear();
+            pings[0]
+        };
+        let first_pass: Vec<_> = (0..3).map(|_| next(&mut s)).collect();
+        assert_eq!(first_pass.iter().collect::<HashSet<_>>().len(), 3);
+        next(&mut s);
+        // A newcomer doesn't wait for the rest of the pass
+        meet(&mut s, 4);
+        assert_eq!(next(&mut s), 4.into());
+        assert_eq!(next(&mut s), first_pass[1]);
+    }
+
+    #[test]
+    fn probes_ping_several_distinct_peers() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s
+            .with_probes_per_period(3)
+            .with_rng(StdRng::seed_from_u64(65));
+        for id in 1..=4 {
+            meet(&mut s, id);
+        }
+        let mut pinged = HashMap::new();
+        for _ in 0..8 {
+            let dests: Vec<_> = probe(&mut s, clock.now())
+                .into_iter()
+                .filter(|m| m.kind == MsgKind::Ping)
+                .map(|m| m.dest_id)
+                .collect();
+            assert_eq!(dests.len(), 3);
+            assert_eq!(dests.iter().collect::<HashSet<_>>().len(), 3);
+            for dest in dests {
+                *pinged.entry(dest).or_insert(0) += 1;
+            }
+            // Each ping is pending on its own
+            assert_eq!(s.pings.len(), 3);
+            s.pings.clear();
+            clock.advance(Duration::from_millis(50));
+        }
+        assert_eq!(pinged.len(), 4);
+        assert_eq!(pinged.values().sum::<usize>(), 24);
+        // Everyone gets a fair share, reshuffling or not
+        assert!(pinged.values().all(|&n| n >= 4));
+
+        // and no more than there are peers
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_probes_per_period(3);
+        meet(&mut s, 1);
+        assert_eq!(probe(&mut s, clock.now()).len(), 1);
+    }
+
+    #[test]
+    fn indirect_probes_can_be_disabled() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_indirect_probes(false);
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        let target = probe(&mut s, clock.now()).pop().unwrap().dest_id;
+        clock.advance(Duration::from_millis(20));
+        let msgs = probe(&mut s, clock.now());
+        assert!(!msgs
+            .iter()
+            .any(|m| matches!(m.kind, MsgKind::PingReq { .. })));
+        assert_eq!(s.peer(target).unwrap().state(), PeerState::Suspect);
+
+        // We still help others
+        let req = message(
+            1,
+            0,
+            7,
+            MsgKind::PingReq {
+                target_id: 2.into(),
+                target: addr(2),
+            },
+        );
+        let ping = respond(&mut s, req).unwrap();
+        assert_eq!((ping.dest_id, ping.kind), (2.into(), MsgKind::Ping));
+    }
+
+    #[test]
+    fn seeded_rngs_are_reproducible() {
+        let run = |seed| {
+            let (s, clock) = server_with_clock(0);
+            let mut s = s.with_rng(StdRng::seed_from_u64(seed));
+            for id in 1..=8 {
+                meet(&mut s, id);
+            }
+            let mut pinged = Vec::new();
+            for _ in 0..16 {
+                for ping in probe(&mut s, clock.now()) {
+                    if ping.kind == MsgKind::Ping {
+                        pinged.push(ping.dest_id);
+                        s.process(message(
+                            ping.dest_id.0,
+                            0,
+                            ping.seq_no,
+                            MsgKind::Ack(ping.dest_id, 1.into()),
+                        ))
+                        .unwrap();
+                    }
+                }
+                clock.advance(Duration::from_millis(50));
+            }
+            (s.memberlist.clone(), pinged)
+        };
+        assert_eq!(run(7), run(7));
+    }
+
+    #[test]
+    fn solo_nodes_tick_indefinitely() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_tombstone_ttl(Duration::from_millis(500));
+        for _ in 0..1000 {
+            s.tick(clock.now());
+            clock.advance(Duration::from_millis(10));
+        }
+        s.push_pull();
+        assert!(s.outbox().is_empty());
+        assert_eq!(s.num_alive(), 1);
+        // And can still join later
+        meet(&mut s, 1);
+        let pinged = (0..2)
+            .flat_map(|_| {
+                clock.advance(Duration::from_millis(50));
+                probe(&mut s, clock.now())
+            })
+            .any(|m| m.kind == MsgKind::Ping && m.dest_id == 1.into());
+        assert!(pinged);
+    }
+
+    #[test]
+    fn two_node_clusters_survive_a_failure() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_tombstone_ttl(Duration::from_millis(500));
+        meet(&mut s, 1);
+        // 1 never answers, so it's suspected with no one to help, then fails. Missing every
+        // probe leaves us unhealthy, which stretches the suspicion out.
+        for _ in 0..200 {
+            s.tick(clock.now());
+            clock.advance(Duration::from_millis(10));
+            if s.peer(1.into()).unwrap().state == PeerState::Failed {
+                break;
+            }
+        }
+        assert_eq!(s.peer(1.into()).unwrap().state, PeerState::Failed);
+        s.outbox();
+        s.push_pull();
+        assert!(s.outbox().is_empty());
+        // Past the tombstone, still alone
+        for _ in 0..100 {
+            s.tick(clock.now());
+            clock.advance(Duration::from_millis(10));
+        }
+        assert!(!s.contains(1.into()));
+        s.outbox();
+        // 1 comes back and pings us
+        s.process(message(1, 0, 1, MsgKind::Ping)).unwrap();
+        assert!(s.contains(1.into()));
+        for _ in 0..50 {
+            s.tick(clock.now());
+            clock.advance(Duration::from_millis(10));
+        }
+    }
+
+    /// Drive a two-node cluster where 1 ignores pings until `recovers_at`, returning whether
+    /// it was ever suspected and when, if ever, it was declared failed
+    fn two_nodes(recovers_at: Duration) -> (Server, bool, Option<Duration>) {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        let start = clock.now();
+        let mut suspected = false;
+        let mut failed_at = None;
+        for _ in 0..400 {
+            let elapsed = clock.now() - start;
+            s.tick(clock.now());
+            for ping in pings(&s.outbox()) {
+                if elapsed >= recovers_at {
+                    let ack = MsgKind::Ack(1.into(), 1.into());
+                    s.process(message(1, 0, ping.seq_no, ack)).unwrap();
+                }
+            }
+            match s.peer(1.into()).unwrap().state {
+                PeerState::Suspect => suspected = true,
+                PeerState::Failed if failed_at.is_none() => failed_at = Some(elapsed),
+                _ => {}
+            }
+            clock.advance(Duration::from_millis(5));
+        }
+        (s, suspected, failed_at)
+    }
+
+    #[test]
+    fn two_node_clusters_ride_out_a_slow_peer() {
+        // Back well within the 150ms suspicion period
+        let (s, suspected, failed_at) = two_nodes(Duration::from_millis(60));
+        assert!(suspected);
+        assert_eq!(failed_at, None);
+        assert_eq!(s.peer(1.into()).unwrap().state, PeerState::Alive);
+        assert_eq!(s.health_multiplier(), 0);
+        // Answering every probe, it's never suspected at all
+        let (_, suspected, failed_at) = two_nodes(Duration::ZERO);
+        assert!(!suspected);
+        assert_eq!(failed_at, None);
+    }
+
+    #[test]
+    fn two_node_clusters_detect_failures_in_bounded_time() {
+        let (s, suspected, failed_at) = two_nodes(Duration::MAX);
+        assert!(suspected);
+        let failed_at = failed_at.unwrap();
+        // A period to be probed, then the ping interval and suspicion period at their most
+        // stretched
+        let scale = MAX_HEALTH_MULTIPLIER as u32 + 1;
+        let bound = s.protocol_period + (s.ping_interval + s.suspicion_period()) * scale;
+        assert!(failed_at <= bound, "{:?} > {:?}", failed_at, bound);
+        assert!(failed_at >= s.ping_interval + s.suspicion_period());
+    }
+
+    #[test]
+    fn arbitrary_gossip_never_panics() {
+        let mut rng = StdRng::seed_from_u64(48);
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_tombstone_ttl(Duration::from_millis(200));
+        for _ in 0..5000 {
+            if rng.gen_bool(0.5) {
+                let kind = match rng.gen_range(0..4) {
+                    0 => RumorKind::alive(addr(rng.gen_range(1..8))),
+                    1 => RumorKind::Suspect {
+                        from: rng.gen_range(0..8).into(),
+                    },
+                    2 => RumorKind::Failed,
+                    _ => RumorKind::Depart,
+                };
+                let mut buf = vec![1, 0];
+                buf.extend(
+                    Rumor {
+                        peer_id: rng.gen_range(0..8).into(),
+                        incarnation: rng.gen_range(0..4).into(),
+                        kind,
+                    }
+                    .serialize(),
+                );
+                s.process_gossip(&buf).unwrap();
+            } else {
+                // Garbage is rejected, not trusted
+                let mut buf = vec![0u8; rng.gen_range(0..32)];
+                rng.fill(&mut buf[..]);
+                let _ = s.process_gossip(&buf);
+            }
+            if rng.gen_bool(0.1) {
+                clock.advance(Duration::from_millis(rng.gen_range(0..100)));
+                s.tick(clock.now());
+                s.outbox();
+            }
+            let mut rotation = s.memberlist.clone();
+            rotation.sort();
+            let mut probed: Vec<_> = s
+                .membership
+                .values()
+                .filter(|p| p.state.is_probed())
+                .map(|p| p.id)
+                .collect();
+            probed.sort();
+            assert_eq!(rotation, probed);
+        }
+    }
+
+    #[test]
+    fn removed_peers_leave_nothing_behind() {
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_tombstone_ttl(Duration::from_millis(100));
+        for id in 1..=2 {
+            meet(&mut s, id);
+            s.responsiveness.insert(id.into(), (None, clock.now()));
+        }
+        assert_eq!(s.responsiveness.len(), 2);
+        fail(&mut s, 1);
+        s.evict(2.into());
+        clock.advance(Duration::from_millis(100));
+        s.expire(clock.now());
+        assert!(!s.contains(1.into()));
+        assert!(s.responsiveness.is_empty());
+        assert!(s.last_changed.is_empty());
+    }
+
+    #[test]
+    fn evicted_peers_are_dropped_at_once() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        rumors(&mut s);
+        assert_eq!(s.evict(9.into()), None);
+        assert_eq!(s.evict(0.into()), None);
+        assert_eq!(s.evict(1.into()).unwrap().state, PeerState::Failed);
+        assert!(!s.contains(1.into()));
+        assert_eq!(s.memberlist, vec![2.into()]);
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Failed,
+        }));
+        assert!(s.probe(clock.now()).contains(&Event::StateChanged {
+            id: 1.into(),
+            from: PeerState::Alive,
+            to: PeerState::Failed,
+        }));
+        // Stale news can't bring it back, but a rejoin can
+        meet(&mut s, 1);
+        assert!(!s.contains(1.into()));
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::alive(addr(1)),
+        });
+        assert_eq!(s.peer(1.into()).unwrap().state, PeerState::Alive);
+    }
+
+    #[test]
+    fn peers_can_be_declared_alive() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        meet(&mut s, 3);
+        s.suspect(1.into(), 1.into(), 2.into());
+        fail(&mut s, 2);
+        s.process_rumor(Rumor {
+            peer_id: 3.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Depart,
+        });
+        rumors(&mut s);
+        assert_eq!(s.declare_alive(9.into()), None);
+        assert_eq!(s.declare_alive(3.into()), None);
+        for id in [1, 2] {
+            assert_eq!(s.declare_alive(id.into()), Some(2.into()));
+            assert_eq!(s.peer(id.into()).unwrap().state, PeerState::Alive);
+            assert!(s.memberlist.contains(&id.into()));
+        }
+        assert!(s.suspicions.is_empty());
+        assert!(s.tombstones.is_empty());
+        let news = rumors(&mut s);
+        for id in [1, 2] {
+            assert!(news.contains(&Rumor {
+                peer_id: id.into(),
+                incarnation: 2.into(),
+                kind: RumorKind::alive(addr(id)),
+            }));
+        }
+        // The stale failure can't undo it
+        fail(&mut s, 2);
+        s.probe(clock.now());
+        assert_eq!(s.peer(2.into()).unwrap().state, PeerState::Alive);
+    }
+
+    #[test]
+    fn vouching_for_us_raises_our_incarnation() {
+        let mut s = server(0);
+        s.process_rumor(Rumor {
+            peer_id: 0.into(),
+            incarnation: 3.into(),
+            kind: RumorKind::alive(addr(0)),
+        });
+        assert_eq!(s.incarnation, 3.into());
+        // Nothing to correct
+        assert!(rumors(&mut s).is_empty());
+        s.process_rumor(Rumor {
+            peer_id: 0.into(),
+            incarnation: 4.into(),
+            kind: RumorKind::alive(addr(10)),
+        });
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 0.into(),
+            incarnation: 5.into(),
+            kind: RumorKind::alive(addr(0)),
+        }));
+    }
+
+    #[test]
+    fn probing_at_a_timeout_sends_no_new_probe() {
+        let (mut s, clock) = server_with_clock(0);
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        let start = clock.now();
+        assert_eq!(pings(&probe(&mut s, start)).len(), 1);
+        let deadline = s.next_deadline();
+        assert_eq!(deadline, start + Duration::from_millis(10));
+        let msgs = probe(&mut s, deadline + Duration::from_millis(1));
+        assert!(pings(&msgs).is_empty(), "{:?}", msgs);
+        assert!(msgs
+            .iter()
+            .any(|m| matches!(m.kind, MsgKind::PingReq { .. })));
+        assert_eq!(s.next_probe(), start + Duration::from_millis(50));
+        // Expiring never probes, even once one is due
+        s.expire(s.next_probe());
+        assert!(pings(&s.outbox()).is_empty());
+        assert_eq!(s.next_probe(), start + Duration::from_millis(50));
+    }
+
+    #[test]
+    fn failed_peers_are_told_when_they_get_in_touch() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        fail(&mut s, 1);
+        rumors(&mut s);
+        s.process(message(1, 0, 7, MsgKind::Ping)).unwrap();
+        let told: Vec<_> = s
+            .outbox()
+            .into_iter()
+            .filter_map(|m| match m.kind {
+                MsgKind::Gossip(buf) if m.dest_id == 1.into() => Some(rumors_in(&buf)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            told,
+            vec![vec![Rumor {
+                peer_id: 1.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::Failed,
+            }]]
+        );
+        // and refute it, rejoining
+        let mut failed = server(1);
+        meet(&mut failed, 0);
+        for rumors in told {
+            for rumor in rumors {
+                failed.process_rumor(rumor);
+            }
+        }
+        assert!(rumors(&mut failed)
+            .iter()
+            .any(|r| r.peer_id == 1.into() && r.incarnation > 1.into()));
+    }
+
+    #[test]
+    fn failed_peers_rejoin_with_higher_incarnation() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        fail(&mut s, 1);
+        assert!(!s.memberlist.contains(&PeerId(1)));
+        let moved = addr(11);
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::alive(moved),
+        });
+        let peer = s.membership.get(&PeerId(1)).unwrap();
+        assert_eq!(peer.state, PeerState::Alive);
+        assert_eq!(peer.addr, moved);
+        assert!(s.memberlist.contains(&PeerId(1)));
+        assert!(!s.tombstones.contains_key(&PeerId(1)));
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 1.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::alive(moved),
+        }));
+        // Rejoined peers get probed again at their new address
+        let pinged: Vec<_> = (0..2)
+            .flat_map(|_| {
+                clock.advance(Duration::from_millis(50));
+                probe(&mut s, clock.now())
+            })
+            .filter(|m| m.kind == MsgKind::Ping)
+            .map(|m| (m.dest_id, m.dest_addr))
+            .collect();
+        assert!(pinged.contains(&(1.into(), moved)));
+    }
+
+    #[test]
+    fn delegate_hears_about_changes() {
+        use PeerState::*;
+        let (s, clock) = server_with_clock(0);
+        let changes = Changes::default();
+        let mut s = s.with_delegate(changes.clone());
+        // Enough peers that we don't come back around to the target
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        meet(&mut s, 3);
+        let target = probe(&mut s, clock.now()).pop().unwrap().dest_id;
+        for ms in [20, 40] {
+            clock.advance(Duration::from_millis(ms));
+            s.probe(clock.now());
+        }
+        // Everyone else answers, so missing the target's probe doesn't stretch our timeouts
+        let others: Vec<_> = s
+            .pings
+            .iter()
+            .filter(|((id, _), _)| *id != target)
+            .map(|((id, seq_no), _)| (*id, *seq_no))
+            .collect();
+        for (id, seq_no) in others {
+            s.process(message(id.0, 0, seq_no, MsgKind::Ack(id, 1.into())))
+                .unwrap();
+        }
+        clock.advance(Duration::from_millis(100));
+        s.probe(clock.now());
+        let changes: Vec<_> = changes
+            .0
+            .borrow()
+            .iter()
+            .filter(|(id, _, _)| *id == target)
+            .map(|(_, old, new)| (*old, *new))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (None, Alive),
+                (Some(Alive), Suspect),
+                (Some(Suspect), Failed)
+            ]
+        );
+        assert_eq!(s.membership.get(&target).unwrap().state, Failed);
+        assert!(!s.memberlist.contains(&target));
+    }
+
+    #[derive(Default, Clone)]
+    struct Recorder {
+        sent: Rc<RefCell<Vec<MsgKind>>>,
+        rtts: Rc<RefCell<Vec<Duration>>>,
+        gauges: Rc<Cell<(usize, usize, usize)>>,
+        dropped: Rc<Cell<usize>>,
+    }
+
+    impl Metrics for Recorder {
+        fn incr_messages_sent(&self, kind: &MsgKind) {
+            self.sent.borrow_mut().push(kind.clone());
+        }
+
+        fn observe_probe_rtt(&self, rtt: Duration) {
+            self.rtts.borrow_mut().push(rtt);
+        }
+
+        fn incr_dropped_messages(&self) {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+
+        fn set_alive_count(&self, count: usize) {
+            let (_, suspects, queued) = self.gauges.get();
+            self.gauges.set((count, suspects, queued));
+        }
+
+        fn set_suspect_count(&self, count: usize) {
+            let (alive, _, queued) = self.gauges.get();
+            self.gauges.set((alive, count, queued));
+        }
+
+        fn set_broadcast_queue_len(&self, len: usize) {
+            let (alive, suspects, _) = self.gauges.get();
+            self.gauges.set((alive, suspects, len));
+        }
+    }
+
+    #[test]
+    fn full_outboxes_drop_and_count() {
+        let metrics = Recorder::default();
+        let mut s = server(0)
+            .with_max_outbox(2)
+            .with_gossip_fanout(3)
+            .with_metrics(metrics.clone());
+        for id in 1..=3 {
+            meet(&mut s, id);
+        }
+        s.tick(Instant::now());
+        let sent = metrics.sent.borrow().len();
+        assert!(sent > 2);
+        let kept = s.outbox();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(metrics.dropped.get(), sent - 2);
+        // The probe outranks gossip about peers we just met
+        assert!(kept.iter().any(|m| m.kind == MsgKind::Ping));
+    }
+
+    #[test]
+    fn metrics_observe_probes() {
+        let (s, clock) = server_with_clock(0);
+        let metrics = Recorder::default();
+        let mut s = s.with_metrics(metrics.clone());
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        let ping = probe(&mut s, clock.now()).pop().unwrap();
+        assert_eq!(*metrics.sent.borrow(), vec![MsgKind::Ping]);
+        assert_eq!(metrics.gauges.get(), (3, 0, 2));
+        clock.advance(Duration::from_millis(4));
+        let id = ping.dest_id;
+        s.process(message(id.0, 0, ping.seq_no, MsgKind::Ack(id, 1.into())))
+            .unwrap();
+        assert_eq!(*metrics.rtts.borrow(), vec![Duration::from_millis(4)]);
+
+        let other = if id == 1.into() { 2 } else { 1 };
+        s.process_rumor(Rumor {
+            peer_id: other.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Suspect { from: 3.into() },
+        });
+        s.probe(clock.now());
+        assert_eq!(metrics.gauges.get().0, 2);
+        assert_eq!(metrics.gauges.get().1, 1);
+    }
+
+    #[test]
+    fn tracks_peer_responsiveness() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        assert_eq!(s.peer_rtt(1.into()), None);
+        assert_eq!(s.last_seen(1.into()), None);
+        let ping = probe(&mut s, clock.now()).pop().unwrap();
+        clock.advance(Duration::from_millis(3));
+        s.process(message(1, 0, ping.seq_no, MsgKind::Ack(1.into(), 1.into())))
+            .unwrap();
+        assert_eq!(s.peer_rtt(1.into()), Some(Duration::from_millis(3)));
+        assert_eq!(s.last_seen(1.into()), Some(clock.now()));
+
+        // Acks relayed by a helper say the peer is up but not how fast it is
+        clock.advance(Duration::from_millis(50));
+        let ping = probe(&mut s, clock.now()).pop().unwrap();
+        clock.advance(Duration::from_millis(20));
+        s.process(message(2, 0, ping.seq_no, MsgKind::Ack(1.into(), 1.into())))
+            .unwrap();
+        assert_eq!(s.peer_rtt(1.into()), Some(Duration::from_millis(3)));
+        assert_eq!(s.last_seen(1.into()), Some(clock.now()));
+    }
+
+    #[test]
+    fn changes_are_returned_as_events() {
+        let (mut s, clock) = server_with_clock(0);
+        s.probe(clock.now());
+        let events = s.process(message(1, 0, 0, MsgKind::Ping)).unwrap();
+        assert_eq!(
+            events,
+            vec![Event::Joined(Peer::new(
+                1.into(),
+                addr(1),
+                0.into(),
+                PeerState::Alive
+            ))]
+        );
+        // Gossip is reported along with the next message or tick
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 0.into(),
+            kind: RumorKind::Suspect { from: 2.into() },
+        });
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 0.into(),
+            kind: RumorKind::User {
+                seq_no: 1,
+                tag: 7,
+                data: vec![1, 2],
+            },
+        });
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 0.into(),
+            kind: RumorKind::Depart,
+        });
+        assert_eq!(
+            s.probe(clock.now()),
+            vec![
+                Event::StateChanged {
+                    id: 1.into(),
+                    from: PeerState::Alive,
+                    to: PeerState::Suspect,
+                },
+                Event::UserMessage(UserMessage {
+                    from: 1.into(),
+                    tag: 7,
+                    data: vec![1, 2],
+                }),
+                Event::Departed(1.into()),
+            ]
+        );
+        assert!(s.probe(clock.now()).is_empty());
     }
 
-    /// Join a cluster the specified peer belongs to
-    pub fn join(&mut self, peer_id: PeerId, peer_addr: SocketAddr) -> Option<Message> {
-        if self.membership.contains_key(&peer_id) {
-            return None;
-        }
-
-        Some(Message {
-            protocol_version: PROTOCOL_VERSION,
-            dest_id: peer_id,
-            dest_addr: peer_addr,
-            src_id: self.id,
-            src_addr: self.addr,
-            seq_no: 0,
-            kind: MsgKind::Pull(Vec::new()),
-        })
+    #[test]
+    fn counts_members() {
+        let mut s = server(0);
+        assert_eq!((s.num_members(), s.num_alive()), (1, 1));
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        meet(&mut s, 3);
+        fail(&mut s, 3);
+        assert_eq!((s.num_members(), s.num_alive()), (4, 3));
+        assert!(s.contains(3.into()));
+        assert!(!s.contains(4.into()));
+        assert_eq!(s.peer(3.into()).unwrap().state, PeerState::Failed);
+        assert!(s.peer(0.into()).is_none());
+        s.leave();
+        assert_eq!((s.num_members(), s.num_alive()), (4, 2));
     }
 
-    pub fn process_gossip(&mut self, buf: &[u8]) -> Result<(), DeserializationError> {
-        if buf.len() == 0 {
-            return Ok(());
-        }
+    #[test]
+    fn deadlines_track_pending_work() {
+        let (mut s, clock) = server_with_clock(0);
+        let start = clock.now();
+        assert_eq!(s.next_deadline(), start);
+        s.probe(start);
+        assert_eq!(s.next_deadline(), start + Duration::from_millis(50));
 
-        let (count_bytes, mut rest) = buf.split_at(2);
-        let rumors = u16::from_le_bytes(count_bytes.try_into().unwrap());
-        for _ in 0..rumors {
-            let (rumor, sl) = Rumor::deserialize(rest)?;
-            trace!("{:03} heard {:?}", self.id, rumor);
-            self.process_rumor(rumor);
-            rest = sl;
-        }
-        Ok(())
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        meet(&mut s, 3);
+        clock.advance(Duration::from_millis(50));
+        let sent_at = clock.now();
+        s.probe(sent_at);
+        // The ping interval for our probe
+        assert_eq!(s.next_deadline(), sent_at + Duration::from_millis(10));
+        // Forwarded, so the helpers have until the next probe
+        clock.advance(Duration::from_millis(20));
+        s.probe(clock.now());
+        assert_eq!(s.next_deadline(), sent_at + Duration::from_millis(50));
+        // The first probe is suspect, so it's down to the suspicion period. That's doubled
+        // now that we've missed a probe, which puts it after the next probe.
+        clock.advance(Duration::from_millis(100));
+        s.probe(clock.now());
+        s.pings.retain(|_, ping| ping.sent_at == sent_at);
+        assert_eq!(s.next_deadline(), sent_at + Duration::from_millis(150));
     }
 
-    pub fn process_rumor(&mut self, rumor: Rumor) {
-        if rumor.peer_id != self.id {
-            self.upsert_peer(rumor.peer_id, rumor.incarnation, rumor.kind);
-            return;
-        }
-        if rumor.incarnation < self.incarnation {
-            return;
-        }
-        match &rumor.kind {
-            RumorKind::Alive(_) => self.incarnation.bump(),
-            RumorKind::Suspect | RumorKind::Failed => {
-                // Reports of my death have been greatly exaggerated.
-                self.incarnation.bump();
-                self.broadcasts.push(Rumor {
-                    peer_id: self.id,
-                    incarnation: self.incarnation,
-                    kind: RumorKind::Alive(self.addr),
-                });
+    #[test]
+    fn unreachable_pingreq_targets_are_nacked() {
+        let (mut s, clock) = server_with_clock(1);
+        s.process(message(
+            0,
+            1,
+            7,
+            MsgKind::PingReq {
+                target_id: 2.into(),
+                target: addr(2),
+            },
+        ))
+        .unwrap();
+        assert_eq!(s.outbox().pop().unwrap().dest_id, 2.into());
+        clock.advance(Duration::from_millis(20));
+        let nack = probe(&mut s, clock.now())
+            .into_iter()
+            .find(|m| m.dest_id == 0.into())
+            .unwrap();
+        assert_eq!(nack.seq_no, 7);
+        assert_eq!(
+            nack.kind,
+            MsgKind::Nack {
+                target_id: 2.into()
             }
-        }
+        );
     }
 
-    /// Append as many rumors as we can into the provided buffer.
-    pub fn gossip(&mut self, buffer: &mut [u8]) {
-        let n = (self.membership.len() + 2) as f32;
-        let max_sends = 3 * n.log10().ceil() as u32;
-        let mut tmp: Vec<Broadcast> = Vec::new();
-        let mut rumors: u16 = 0;
-        // First two bytes are for the number of rumors
-        let mut idx = 2;
-        while idx < buffer.len() {
-            if buffer.len() - idx < SMALLEST_RUMOR {
-                break;
-            }
-            if let Some(broadcast) = self.broadcasts.pop() {
-                assert_ne!(
-                    broadcast.message.len(),
-                    0,
-                    "invalid broadcast: {:?}",
-                    broadcast
-                );
-                if broadcast.message.len() <= buffer.len() - idx {
-                    buffer[idx..idx + broadcast.message.len()].copy_from_slice(&broadcast.message);
-                    idx += broadcast.message.len();
-                    rumors += 1;
-                    if broadcast.sends < (max_sends as usize - 1) {
-                        self.broadcasts.replay(broadcast);
-                    }
-                } else {
-                    tmp.push(broadcast);
-                }
-            } else {
-                break;
-            }
-        }
-        buffer[0..2].copy_from_slice(&rumors.to_le_bytes());
-        for bc in tmp {
-            self.broadcasts.push_broadcast(bc);
+    #[test]
+    fn nacks_shorten_suspicion() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        meet(&mut s, 3);
+        let ping = probe(&mut s, clock.now()).pop().unwrap();
+        let target = ping.dest_id;
+        clock.advance(Duration::from_millis(20));
+        let helpers: Vec<_> = probe(&mut s, clock.now())
+            .into_iter()
+            .filter(|m| matches!(m.kind, MsgKind::PingReq { .. }))
+            .map(|m| m.dest_id)
+            .collect();
+        assert_eq!(helpers.len(), 2);
+        for helper in helpers {
+            s.process(message(
+                helper.0,
+                0,
+                ping.seq_no,
+                MsgKind::Nack { target_id: target },
+            ))
+            .unwrap();
         }
+        // Half of the 150ms suspicion period
+        clock.advance(Duration::from_millis(60));
+        s.probe(clock.now());
+        assert_eq!(s.membership.get(&target).unwrap().state, PeerState::Failed);
     }
 
-    // TODO: return a response
-    pub fn process(&mut self, msg: Message) -> Option<Message> {
+    #[test]
+    fn unrefuted_suspicions_fail() {
+        let (mut s, clock) = server_with_clock(0);
+        // Enough live peers that probing them never lacks helpers, which would count against
+        // our health and stretch the suspicion
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        meet(&mut s, 3);
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Suspect { from: 2.into() },
+        });
+        clock.advance(Duration::from_millis(100));
+        s.probe(clock.now());
         assert_eq!(
-            msg.dest_id, self.id,
-            "Simulator bug; sent {:?} to the wrong node",
-            msg
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Suspect
+        );
+        clock.advance(Duration::from_millis(60));
+        s.probe(clock.now());
+        assert_eq!(
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Failed
+        );
+        assert!(s.suspicions.is_empty());
+    }
+
+    #[test]
+    fn confirmations_shorten_suspicion() {
+        let (s, clock) = server_with_clock(0);
+        let mut s =
+            s.with_suspicion_timeout(Duration::from_millis(100), Duration::from_millis(400));
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        s.suspect(1.into(), 1.into(), 0.into());
+        assert_eq!(s.suspicion_timeout(1.into()), Duration::from_millis(400));
+        clock.advance(Duration::from_millis(350));
+        s.probe(clock.now());
+        assert_eq!(
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Suspect
         );
-        self.upsert_peer(msg.src_id, Incarnation(0), RumorKind::Alive(msg.src_addr));
-        let resp = match msg.kind {
-            MsgKind::Push(peers) => {
-                // Merge with our state
-                for peer in peers {
-                    if peer.id != self.id {
-                        self.upsert_peer(peer.id, peer.incarnation, peer.rumor_kind())
-                    }
-                }
-                None
-            }
-            MsgKind::Pull(peers) => {
-                // Respond with our state in a Push
-                let our_peers = self.live_members();
-                // TODO what if they think we're suspect?
-                for peer in peers {
-                    if peer.id != self.id {
-                        self.upsert_peer(peer.id, peer.incarnation, peer.rumor_kind())
-                    }
-                }
-                Some(Message {
-                    protocol_version: PROTOCOL_VERSION,
-                    dest_id: msg.src_id,
-                    dest_addr: msg.src_addr,
-                    src_id: self.id,
-                    src_addr: self.addr,
-                    seq_no: 0,
-                    kind: MsgKind::Push(our_peers),
-                })
-            }
-            MsgKind::Ping => Some(self.ack(self.id, msg.src_id, msg.src_addr)),
-            MsgKind::PingReq { target_id, target } => {
-                Some(self.ping(target_id, target, msg.src_id))
-            }
-            MsgKind::Ack(peer_id, incarnation) => {
-                if let Some(ping) = self.pings.remove(&peer_id) {
-                    if ping.seq_no == msg.seq_no {
-                        if ping.requester != self.id {
-                            Some(self.ack(
-                                peer_id,
-                                ping.requester,
-                                self.membership.get(&ping.requester).unwrap().addr,
-                            ))
-                        } else {
-                            self.upsert_peer(peer_id, incarnation, RumorKind::Alive(ping.addr));
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            }
-        };
 
-        resp
+        // Two of the three confirmations we expect
+        s.suspect(1.into(), 1.into(), 2.into());
+        s.suspect(1.into(), 1.into(), 3.into());
+        assert!(s.suspicion_timeout(1.into()) < Duration::from_millis(350));
+        assert!(s.suspicion_timeout(1.into()) > Duration::from_millis(100));
+        s.probe(clock.now());
+        assert_eq!(
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Failed
+        );
     }
 
-    pub fn push_pull(&mut self) -> Option<Message> {
-        // run an anti-entropy cycle against a random node
-        if self.membership.len() == 0 {
-            return None;
+    #[test]
+    fn gossip_counts_distinct_suspectors() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        for from in [2, 3, 2] {
+            s.process_rumor(Rumor {
+                peer_id: 1.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::Suspect { from: from.into() },
+            });
         }
-        let mut rng = thread_rng();
-        let dest_id = *self.memberlist.choose(&mut rng).unwrap();
-        let dest_addr = self.membership.get(&dest_id).unwrap().addr;
-        Some(Message {
-            protocol_version: PROTOCOL_VERSION,
-            dest_id,
-            dest_addr,
-            src_id: self.id,
-            src_addr: self.addr,
-            seq_no: 0,
-            kind: MsgKind::Pull(self.live_members()),
-        })
+        let suspectors = &s.suspicions.get(&PeerId(1)).unwrap().suspectors;
+        assert_eq!(suspectors.len(), 2);
+        // The first suspicion is the one we pass along
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Suspect { from: 2.into() },
+        }));
     }
 
-    /// Called once per protocol period
-    pub fn tick(&mut self) -> Vec<Message> {
-        // From the SWIM paper
-        self.suspicion_period =
-            self.protocol_period * 3 * ((self.membership.len() + 2) as f32).log10().ceil() as u32;
+    #[test]
+    fn missed_probes_stretch_timeouts() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        meet(&mut s, 3);
+        s.probe(clock.now());
+        clock.advance(Duration::from_millis(20));
+        s.probe(clock.now());
+        clock.advance(Duration::from_millis(40));
+        s.probe(clock.now());
+        assert_eq!(s.health_multiplier(), 1);
+        // Still suspect, but we don't count the same probe twice
+        clock.advance(Duration::from_millis(1));
+        s.probe(clock.now());
+        assert_eq!(s.health_multiplier(), 1);
+        assert_eq!(s.suspicion_timeout(1.into()), Duration::from_millis(300));
+
+        // Our latest probe gets twice the usual ping interval before we ask for help
+        let target = s.outbox().pop().unwrap().dest_id;
+        clock.advance(Duration::from_millis(15));
+        assert!(!probe(&mut s, clock.now())
+            .iter()
+            .any(|m| matches!(m.kind, MsgKind::PingReq { target_id, .. } if target_id == target)));
+        // Other probes may have gone unanswered meanwhile, but every ack helps
+        let health = s.health_multiplier();
+        let (_, seq_no) = *s.pings.keys().find(|(id, _)| *id == target).unwrap();
+        s.process(message(target.0, 0, seq_no, MsgKind::Ack(target, 1.into())))
+            .unwrap();
+        assert_eq!(s.health_multiplier(), health - 1);
+    }
 
-        if self.last_pinged >= self.memberlist.len() {
-            let mut rng = thread_rng();
-            self.memberlist.shuffle(&mut rng);
-            self.last_pinged = 0;
+    #[test]
+    fn incarnation_only_grows_to_refute() {
+        let mut s = server(1);
+        for seq_no in 0..100 {
+            s.process(message(0, 1, seq_no, MsgKind::Ping)).unwrap();
         }
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::alive(addr(1)),
+        });
+        assert_eq!(s.incarnation, 1.into());
 
-        let mut to_rm = Vec::new();
-        let mut outbox = Vec::new();
-        let mut pings = take(&mut self.pings);
-        let now = Instant::now();
-        for (node, ping) in pings.iter_mut() {
-            if now > (ping.sent_at + self.suspicion_period) {
-                assert!(ping.state == PingState::Forwarded);
-                let peer = self.membership.get(node).unwrap();
-                self.broadcasts.push(Rumor {
-                    peer_id: *node,
-                    incarnation: peer.incarnation,
-                    kind: RumorKind::Failed,
-                });
-                to_rm.push(*node);
-            } else if now > (ping.sent_at + self.protocol_period) {
-                // At this point we throw out pings for non-member peers.
-                if ping.state == PingState::FromElsewhere || !self.membership.contains_key(node) {
-                    to_rm.push(*node);
-                    continue;
-                }
-                let peer = self.membership.get(node).unwrap();
-                debug!("{} suspects that {} has failed", self.id, node);
-                self.broadcasts.push(Rumor {
-                    peer_id: *node,
-                    incarnation: peer.incarnation,
-                    kind: RumorKind::Suspect,
-                });
-            } else if ping.state != PingState::Forwarded
-                && now > (ping.sent_at + self.ping_interval)
-            {
-                if ping.state != PingState::Normal {
-                    debug!(
-                        "{:03} expire ping from {:03} to {:03}",
-                        self.id, ping.requester, node
-                    );
-                    to_rm.push(*node);
-                    continue;
-                }
-                // late, send ping_req to k nodes
-                let mut chosen = HashSet::new();
-                let mut rng = thread_rng();
-                let subgroup_sz = self.pingreq_subgroup_sz.min(self.memberlist.len());
-                let incarnation = self
-                    .membership
-                    .get(node)
-                    .map(|p| p.incarnation)
-                    .unwrap_or(0.into());
-                if self.memberlist.len() <= 1 {
-                    debug!("{:03} suspects that {:03} has failed", self.id, node);
-                    to_rm.push(*node);
-                    self.broadcasts.push(Rumor {
-                        peer_id: *node,
-                        incarnation,
-                        kind: RumorKind::Suspect,
-                    });
-                    continue;
-                }
-                while chosen.len() < subgroup_sz {
-                    let dest_id = *self.memberlist.choose(&mut rng).unwrap();
-                    if dest_id != *node && !chosen.contains(&dest_id) {
-                        chosen.insert(dest_id);
-                        let dest_addr = self.membership.get(&dest_id).unwrap().addr;
-                        let m = Message {
-                            protocol_version: PROTOCOL_VERSION,
-                            dest_id,
-                            dest_addr,
-                            src_id: self.id,
-                            src_addr: self.addr,
-                            seq_no: ping.seq_no,
-                            kind: MsgKind::PingReq {
-                                target_id: *node,
-                                target: ping.addr,
-                            },
-                        };
-                        outbox.push(m);
-                    }
-                }
-                ping.state = PingState::Forwarded;
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 3.into(),
+            kind: RumorKind::Suspect { from: 2.into() },
+        });
+        assert_eq!(s.incarnation, 4.into());
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 1.into(),
+            incarnation: 4.into(),
+            kind: RumorKind::alive(addr(1)),
+        }));
+    }
+
+    #[test]
+    fn departures_from_before_a_reset_are_outranked() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        s.leave();
+        s.reset();
+        s.process_rumor(Rumor {
+            peer_id: 0.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Depart,
+        });
+        assert_eq!(s.incarnation, 2.into());
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 0.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::alive(addr(0)),
+        }));
+    }
+
+    #[test]
+    fn self_refutations_are_counted() {
+        let mut s = server(1);
+        assert_eq!(s.self_refutations(), 0);
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Suspect { from: 2.into() },
+        });
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 2.into(),
+            kind: RumorKind::Failed,
+        });
+        // Stale, so there's nothing to refute
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Suspect { from: 3.into() },
+        });
+        assert_eq!(s.self_refutations(), 2);
+        assert_eq!(
+            take(&mut s.events),
+            [
+                Event::SelfSuspected(2.into()),
+                Event::SelfSuspected(3.into())
+            ]
+        );
+    }
+
+    #[test]
+    fn departed_peers_stay_departed() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        rumors(&mut s);
+        // Departures from before a rejoin are stale
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 0.into(),
+            kind: RumorKind::Depart,
+        });
+        assert_eq!(
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Alive
+        );
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Depart,
+        });
+        assert_eq!(
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Departed
+        );
+        assert!(!s.memberlist.contains(&PeerId(1)));
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Depart,
+        }));
+
+        // Stale gossip from before they left
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::alive(addr(1)),
+        });
+        assert_eq!(
+            s.membership.get(&PeerId(1)).unwrap().state,
+            PeerState::Departed
+        );
+        for _ in 0..4 {
+            for msg in probe(&mut s, Instant::now()) {
+                assert_eq!(msg.dest_id, 2.into());
             }
         }
-        self.pings = pings;
-        for node in to_rm {
-            trace!("{:03} expire ping to {}", self.id, node);
-            self.pings.remove(&node);
-        }
-        if !self.membership.is_empty() {
+    }
+
+    #[test]
+    fn departed_peers_are_quarantined() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        let depart = |id: u64| Rumor {
+            peer_id: id.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Depart,
+        };
+        let alive = |id: u64, incarnation: u64| Rumor {
+            peer_id: id.into(),
+            incarnation: incarnation.into(),
+            kind: RumorKind::alive(addr(id)),
+        };
+        s.process_rumor(depart(1));
+        s.process_rumor(depart(3));
+        // Even strangers that left are kept out
+        s.process_rumor(alive(3, 1));
+        assert!(s.peer(3.into()).is_none());
+        assert!(s.quarantine.contains_key(&PeerId(3)));
+
+        // Until the quarantine's up, when they're forgotten entirely
+        clock.advance(s.quarantine_ttl());
+        s.probe(clock.now());
+        assert!(s.peer(1.into()).is_none());
+        assert!(s.quarantine.is_empty());
+        s.process_rumor(alive(3, 1));
+        assert_eq!(s.peer(3.into()).unwrap().state(), PeerState::Alive);
+
+        // A genuine rejoin comes back at a higher incarnation
+        s.process_rumor(depart(2));
+        s.process_rumor(alive(2, 2));
+        assert_eq!(s.peer(2.into()).unwrap().state(), PeerState::Alive);
+        assert!(s.quarantine.is_empty());
+        assert!(s.probe_order().contains(&PeerId(2)));
+    }
+
+    #[test]
+    fn state_precedence() {
+        use PeerState::*;
+        let kind = |state| match state {
+            Alive => RumorKind::alive(addr(1)),
+            Suspect => RumorKind::Suspect { from: 2.into() },
+            Failed => RumorKind::Failed,
+            Departed => RumorKind::Depart,
+        };
+        // (known state, incoming state, incoming incarnation, applied?), known at incarnation 2
+        let table = [
+            (Alive, Alive, 1, false),
+            (Alive, Alive, 2, false),
+            (Alive, Alive, 3, true),
+            (Suspect, Alive, 2, false),
+            (Suspect, Alive, 3, true),
+            (Alive, Suspect, 1, false),
+            (Alive, Suspect, 2, true),
+            (Alive, Suspect, 3, true),
+            (Suspect, Suspect, 2, false),
+            (Suspect, Suspect, 3, true),
+            (Alive, Failed, 2, true),
+            (Suspect, Failed, 2, true),
+            (Failed, Alive, 2, false),
+            (Failed, Suspect, 2, false),
+            (Departed, Alive, 2, false),
+            (Departed, Alive, 3, true),
+            (Departed, Suspect, 3, false),
+            (Departed, Failed, 3, false),
+        ];
+        for (known, incoming, incarnation, applied) in table {
+            let mut s = server(0);
+            s.process_rumor(Rumor {
+                peer_id: 1.into(),
+                incarnation: 2.into(),
+                kind: RumorKind::alive(addr(1)),
+            });
+            s.process_rumor(Rumor {
+                peer_id: 1.into(),
+                incarnation: 2.into(),
+                kind: kind(known),
+            });
+            s.process_rumor(Rumor {
+                peer_id: 1.into(),
+                incarnation: incarnation.into(),
+                kind: kind(incoming),
+            });
+            let peer = s.membership.get(&PeerId(1)).unwrap();
+            let expected = if applied {
+                (incoming, incarnation.into())
+            } else {
+                (known, 2.into())
+            };
             assert_eq!(
-                self.memberlist.len(),
-                self.membership.len(),
-                "membership {:?}\nmemberlist {:?}",
-                self.membership,
-                self.memberlist
+                (peer.state, peer.incarnation),
+                expected,
+                "{:?}@2 <- {:?}@{}",
+                known,
+                incoming,
+                incarnation
             );
-            let ping_rcpt = self.memberlist[self.last_pinged];
-            let ping_peer = self.membership.get(&ping_rcpt).unwrap().clone();
-            outbox.push(self.ping(ping_rcpt, ping_peer.addr, self.id));
-            self.last_pinged += 1;
         }
-        outbox
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn leaving_announces_departure() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        s.leave();
+        let sent = s.outbox();
+        assert_eq!(sent.len(), 2);
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 0.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Depart,
+        }));
+        // No coming back from a departure
+        s.process_rumor(Rumor {
+            peer_id: 0.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::Suspect { from: 2.into() },
+        });
+        assert_eq!(s.incarnation, 1.into());
+
+        let mut peer = server(1);
+        meet(&mut peer, 0);
+        for msg in sent.into_iter().filter(|m| m.dest_id == 1.into()) {
+            peer.process(msg).unwrap();
+        }
+        assert_eq!(
+            peer.membership.get(&PeerId(0)).unwrap().state,
+            PeerState::Departed
+        );
+    }
 
     #[test]
-    fn tick_pings_random_peer() {
-        // FIXME ensure it doesn't pick dead peers
-        todo!()
+    fn departures_are_acknowledged_once_spread() {
+        let (s, _) = server_with_clock(0);
+        let mut s = s.with_max_piggyback(1);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        assert!(!s.is_departed_acknowledged());
+        s.leave();
+        let mut sends = 0;
+        while !s.is_departed_acknowledged() {
+            sends += rumors(&mut s)
+                .iter()
+                .filter(|r| r.kind == RumorKind::Depart)
+                .count();
+        }
+        assert_eq!(sends, s.max_sends);
+
+        // Unheard, we give up waiting after the timeout
+        let (s, clock) = server_with_clock(0);
+        let mut s = s.with_leave_timeout(Duration::from_secs(1));
+        meet(&mut s, 1);
+        s.leave();
+        clock.advance(Duration::from_millis(999));
+        assert!(!s.is_departed_acknowledged());
+        clock.advance(Duration::from_millis(1));
+        assert!(s.is_departed_acknowledged());
+
+        // Alone, there's nobody to wait for
+        let mut s = server(0);
+        s.leave();
+        assert!(s.is_departed_acknowledged());
+        s.reset();
+        assert!(!s.is_departed_acknowledged());
     }
 
     #[test]
-    fn push_pull_only_chooses_live_peers() {
-        todo!()
+    fn user_broadcasts_are_relayed() {
+        let mut a = server(0);
+        assert_eq!(
+            a.broadcast_user(1, vec![0; MAX_USER_DATA + 1], None),
+            Err(BroadcastError::TooLarge(MAX_USER_DATA + 1))
+        );
+        a.broadcast_user(1, b"config".to_vec(), None).unwrap();
+        let mut buf = [0u8; 64];
+        a.gossip(&mut buf);
+
+        let mut b = server(1);
+        b.process_gossip(&buf).unwrap();
+        b.process_gossip(&buf).unwrap();
+        assert_eq!(
+            b.user_messages(),
+            vec![UserMessage {
+                from: 0.into(),
+                tag: 1,
+                data: b"config".to_vec(),
+            }]
+        );
+        assert!(b.membership.is_empty());
+        assert!(rumors(&mut b)
+            .iter()
+            .any(|r| matches!(r.kind, RumorKind::User { tag: 1, .. })));
+
+        // Our own broadcast coming back around isn't news
+        a.process_gossip(&buf).unwrap();
+        assert!(a.user_messages().is_empty());
     }
 
     #[test]
-    fn pushes_respond_to_pulls() {
-        todo!()
+    fn user_broadcasts_are_delivered_once() {
+        let mut a = server(0);
+        a.broadcast_user(1, b"config".to_vec(), None).unwrap();
+        let mut buf = [0u8; 64];
+        let len = a.gossip(&mut buf);
+        let gossip = buf[..len].to_vec();
+
+        let mut b = server(1);
+        let mut delivered = Vec::new();
+        for (src, seq_no) in [(0, 1), (2, 1), (0, 2)] {
+            b.process_gossip(&gossip).unwrap();
+            delivered.extend(b.process(message(src, 1, seq_no, MsgKind::Ping)).unwrap());
+        }
+        // Even once we've forgotten the cluster and are hearing it all again
+        b.reset();
+        b.process_gossip(&gossip).unwrap();
+        delivered.extend(b.process(message(3, 1, 1, MsgKind::Ping)).unwrap());
+        let delivered: Vec<_> = delivered
+            .into_iter()
+            .filter(|e| matches!(e, Event::UserMessage(_)))
+            .collect();
+        assert_eq!(
+            delivered,
+            [Event::UserMessage(UserMessage {
+                from: 0.into(),
+                tag: 1,
+                data: b"config".to_vec(),
+            })]
+        );
+        assert!(b.user_messages().is_empty());
+        // but still pass it along
+        assert!(rumors(&mut b)
+            .iter()
+            .any(|r| matches!(r.kind, RumorKind::User { tag: 1, .. })));
     }
 
     #[test]
-    fn pulls_update_state() {
-        todo!()
+    fn repeated_user_broadcasts_are_delivered_again() {
+        let (mut a, clock) = server_with_clock(0);
+        let mut b = server(1).with_clock(clock.clone());
+        for _ in 0..2 {
+            a.broadcast_user(1, b"ping".to_vec(), None).unwrap();
+        }
+        let mut buf = [0u8; 128];
+        let len = a.gossip(&mut buf);
+        b.process_gossip(&buf[..len]).unwrap();
+        assert_eq!(b.user_messages().len(), 2);
+        assert_eq!(b.delivered.len(), 2);
+
+        // Remembered only for as long as it's still going round
+        clock.advance(b.quarantine_ttl());
+        b.expire(clock.now());
+        assert!(b.delivered.is_empty());
     }
 
     #[test]
-    fn pings_are_acked() {
-        todo!()
+    fn ignore_old_news() {
+        let mut s = server(0);
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 5.into(),
+            kind: RumorKind::alive(addr(1)),
+        });
+        s.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 3.into(),
+            kind: RumorKind::Failed,
+        });
+        let peer = s.membership.get(&PeerId(1)).unwrap();
+        assert_eq!(peer.state, PeerState::Alive);
+        assert_eq!(peer.incarnation, 5.into());
+
+        // Stale news about ourselves doesn't need refuting
+        s.process_rumor(Rumor {
+            peer_id: 0.into(),
+            incarnation: 0.into(),
+            kind: RumorKind::Suspect { from: 2.into() },
+        });
+        assert_eq!(s.incarnation, 1.into());
+    }
+
+    /// Records each incarnation we're told we're at
+    #[derive(Clone, Default)]
+    struct Incarnations(Rc<RefCell<Vec<Incarnation>>>);
+
+    impl MembershipDelegate for Incarnations {
+        fn on_change(&mut self, _peer: Peer, _old: Option<PeerState>) {}
+
+        fn on_incarnation(&mut self, incarnation: Incarnation) {
+            self.0.borrow_mut().push(incarnation);
+        }
     }
 
     #[test]
-    fn pingreqs_beget_pings() {
-        todo!()
+    fn restarts_resume_their_incarnation() {
+        let persisted = Incarnations::default();
+        let mut s = server(0)
+            .with_delegate(persisted.clone())
+            .with_incarnation(7.into());
+        assert_eq!(s.incarnation(), 8.into());
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 0.into(),
+            incarnation: 8.into(),
+            kind: RumorKind::alive(addr(0)),
+        }));
+        // Suspicions from before the restart are already outranked, later ones refuted
+        s.process_rumor(Rumor {
+            peer_id: 0.into(),
+            incarnation: 7.into(),
+            kind: RumorKind::Suspect { from: 1.into() },
+        });
+        assert_eq!(s.incarnation(), 8.into());
+        s.process_rumor(Rumor {
+            peer_id: 0.into(),
+            incarnation: 8.into(),
+            kind: RumorKind::Suspect { from: 1.into() },
+        });
+        assert_eq!(*persisted.0.borrow(), vec![8.into(), 9.into()]);
     }
 
     #[test]
-    fn pingreq_acks_are_forwarded() {
-        todo!()
+    fn snapshots_restore_membership() {
+        let (mut s, clock) = server_with_clock(0);
+        meet(&mut s, 1);
+        meet(&mut s, 2);
+        meet(&mut s, 3);
+        fail(&mut s, 3);
+        s.suspect(2.into(), 1.into(), 1.into());
+        s.incarnation = 4.into();
+        let snapshot = s.snapshot();
+        assert_eq!(snapshot.peers.len(), 3);
+
+        let mut s = server(0).with_clock(clock.clone());
+        s.restore(snapshot);
+        assert_eq!(s.incarnation, 5.into());
+        assert!(rumors(&mut s).contains(&Rumor {
+            peer_id: 0.into(),
+            incarnation: 5.into(),
+            kind: RumorKind::alive(addr(0)),
+        }));
+        for id in [1, 2] {
+            assert_eq!(s.peer(id.into()).unwrap().state, PeerState::Alive);
+        }
+        assert!(!s.contains(3.into()));
+        let mut rotation = s.memberlist.clone();
+        rotation.sort();
+        assert_eq!(rotation, vec![1.into(), 2.into()]);
+        assert_eq!(s.probe(clock.now()).len(), 2);
     }
 
+    #[cfg(feature = "bincode")]
     #[test]
-    fn late_acks_are_suspect() {
-        todo!()
+    fn snapshots_roundtrip_bytes() {
+        let mut s = server(0);
+        meet(&mut s, 1);
+        let snapshot = s.snapshot();
+        let bytes = snapshot.to_bytes().unwrap();
+        assert_eq!(MembershipSnapshot::from_bytes(&bytes).unwrap(), snapshot);
     }
 
+    #[cfg(feature = "bincode")]
     #[test]
-    fn timely_acks_clear_suspicion() {
-        todo!()
+    fn messages_roundtrip_bytes() {
+        let v6: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        let peers = vec![
+            Peer::new(1.into(), addr(1), 1.into(), PeerState::Alive),
+            Peer::new(2.into(), v6, 2.into(), PeerState::Suspect),
+            Peer::new(3.into(), addr(3), 3.into(), PeerState::Failed),
+        ];
+        let kinds = [
+            MsgKind::Ping,
+            MsgKind::Ack(1.into(), 2.into()),
+            MsgKind::PingReq {
+                target_id: 2.into(),
+                target: v6,
+            },
+            MsgKind::Push(peers.clone()),
+            MsgKind::PullDigest(peers.iter().map(PeerDigest::from).collect()),
+            MsgKind::Pull(peers),
+        ];
+        for kind in kinds {
+            let mut msg = message(0, 1, 42, kind);
+            msg.dest_addr = v6;
+            let bytes = msg.to_bytes().unwrap();
+            assert_eq!(Message::from_bytes(&bytes).unwrap(), msg);
+        }
     }
 
+    #[cfg(feature = "bincode")]
     #[test]
-    fn recognize_failed_peer() {
-        todo!()
+    fn link_local_addresses_survive_push_pull() {
+        use std::net::{Ipv6Addr, SocketAddrV6};
+
+        let link_local = SocketAddr::V6(SocketAddrV6::new(
+            "fe80::1".parse().unwrap(),
+            8080,
+            0x12345,
+            7,
+        ));
+        let peers = vec![Peer::new(1.into(), link_local, 1.into(), PeerState::Alive)];
+        for kind in [MsgKind::Push(peers.clone()), MsgKind::Pull(peers)] {
+            let mut msg = message(1, 0, 42, kind);
+            msg.src_addr = link_local;
+            let decoded = Message::from_bytes(&msg.to_bytes().unwrap()).unwrap();
+            assert_eq!(decoded, msg);
+            let mut s = server(0);
+            s.process(decoded).unwrap();
+            match s.peer(1.into()).unwrap().addr() {
+                SocketAddr::V6(addr) => {
+                    assert_eq!(
+                        addr.ip().octets(),
+                        "fe80::1".parse::<Ipv6Addr>().unwrap().octets()
+                    );
+                    assert_eq!(addr.port(), 8080);
+                    assert_eq!(addr.flowinfo(), 0x12345);
+                    assert_eq!(addr.scope_id(), 7);
+                }
+                addr => panic!("unexpected {}", addr),
+            }
+        }
+        let rumor = Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::alive(link_local),
+        };
+        let bytes = bincode::serialize(&rumor).unwrap();
+        assert_eq!(bincode::deserialize::<Rumor>(&bytes).unwrap(), rumor);
     }
 
     #[test]
-    fn ignore_old_news() {
-        todo!()
+    fn peer_filters_veto_and_rewrite_peers() {
+        let nat = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let mut s = server(0).with_peer_filter(move |peer: &Peer| match u64::from(peer.id()) {
+            1 => None,
+            2 => Some(peer.clone().with_addr(nat)),
+            _ => Some(peer.clone()),
+        });
+        let alive = |id: u64, incarnation: u64| Rumor {
+            peer_id: id.into(),
+            incarnation: incarnation.into(),
+            kind: RumorKind::alive(addr(id)),
+        };
+
+        s.process_rumor(alive(1, 1));
+        s.process_rumor(alive(2, 1));
+        assert!(s.peer(1.into()).is_none());
+        assert_eq!(s.peer(2.into()).unwrap().addr(), nat);
+        assert!(rumors(&mut s).iter().all(|rumor| match rumor.kind {
+            RumorKind::Alive { addr, .. } => rumor.peer_id != 1.into() && addr != self::addr(2),
+            _ => true,
+        }));
+
+        // A later rumor can't undo the rewrite
+        s.process_rumor(alive(2, 2));
+        assert_eq!(s.peer(2.into()).unwrap().addr(), nat);
+
+        let peers = vec![
+            Peer::new(1.into(), addr(1), 3.into(), PeerState::Alive),
+            Peer::new(3.into(), addr(3), 1.into(), PeerState::Alive),
+        ];
+        s.process(message(3, 0, 42, MsgKind::Push(peers))).unwrap();
+        assert!(s.peer(1.into()).is_none());
+        assert_eq!(s.peer(3.into()).unwrap().addr(), addr(3));
+
+        s.process(message(1, 0, 43, MsgKind::Ping)).unwrap();
+        assert!(s.peer(1.into()).is_none());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn size_estimates_match_the_wire() {
+        let v6: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        let mut with_meta = Peer::new(2.into(), v6, 2.into(), PeerState::Suspect);
+        with_meta.meta = b"rack=7".to_vec();
+        let peers = vec![
+            Peer::new(1.into(), addr(1), 1.into(), PeerState::Alive),
+            with_meta,
+        ];
+        let rumors = [
+            Rumor {
+                peer_id: 1.into(),
+                incarnation: 1.into(),
+                kind: RumorKind::alive(addr(1)),
+            },
+            Rumor {
+                peer_id: 2.into(),
+                incarnation: 2.into(),
+                kind: RumorKind::Alive {
+                    addr: v6,
+                    meta: b"rack=7".to_vec(),
+                },
+            },
+            Rumor {
+                peer_id: 3.into(),
+                incarnation: 3.into(),
+                kind: RumorKind::Suspect { from: 1.into() },
+            },
+            Rumor {
+                peer_id: 4.into(),
+                incarnation: 4.into(),
+                kind: RumorKind::Depart,
+            },
+            Rumor {
+                peer_id: 5.into(),
+                incarnation: 5.into(),
+                kind: RumorKind::User {
+                    seq_no: 5,
+                    tag: 9,
+                    data: vec![0; 100],
+                },
+            },
+        ];
+        for rumor in &rumors {
+            assert_eq!(
+                rumor.estimated_size(),
+                rumor.serialize().len(),
+                "{:?}",
+                rumor
+            );
+        }
+        let kinds = [
+            MsgKind::Ping,
+            MsgKind::Ack(1.into(), 2.into()),
+            MsgKind::PingReq {
+                target_id: 2.into(),
+                target: v6,
+            },
+            MsgKind::Nack {
+                target_id: 2.into(),
+            },
+            MsgKind::Push(peers.clone()),
+            MsgKind::PullDigest(peers.iter().map(PeerDigest::from).collect()),
+            MsgKind::Pull(peers),
+            MsgKind::Gossip(rumors.iter().flat_map(Rumor::serialize).collect()),
+        ];
+        for kind in kinds {
+            for dest_addr in [addr(1), v6] {
+                let mut msg = message(0, 1, 42, kind.clone());
+                msg.dest_addr = dest_addr;
+                msg.mac = vec![1; 32];
+                assert_eq!(
+                    msg.estimated_size(),
+                    msg.to_bytes().unwrap().len(),
+                    "{:?}",
+                    msg
+                );
+            }
+        }
     }
 }
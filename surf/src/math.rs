@@ -0,0 +1,52 @@
+//! The few float functions we need, from `std` where we have it and `libm` otherwise
+
+#[cfg(feature = "std")]
+pub(crate) fn ln(x: f64) -> f64 {
+    x.ln()
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn ln(x: f64) -> f64 {
+    libm::log(x)
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn log10(x: f64) -> f64 {
+    x.log10()
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn log10(x: f64) -> f64 {
+    libm::log10(x)
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn exp(x: f64) -> f64 {
+    libm::exp(x)
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn sqrt(x: f64) -> f64 {
+    x.sqrt()
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn sqrt(x: f64) -> f64 {
+    libm::sqrt(x)
+}
+
+/// `ceil(log10(n))`
+#[cfg(feature = "std")]
+pub(crate) fn ceil_log10(n: usize) -> u32 {
+    (n as f32).log10().ceil() as u32
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn ceil_log10(n: usize) -> u32 {
+    libm::ceilf(libm::log10f(n as f32)) as u32
+}
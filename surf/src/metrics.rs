@@ -0,0 +1,110 @@
+use core::time::Duration;
+
+use crate::MsgKind;
+
+/// Observes a [`Server`](crate::Server) for monitoring. Every callback does nothing by
+/// default, so implementations only need the ones they export.
+pub trait Metrics {
+    /// A message of `kind` went out through the transport
+    fn incr_messages_sent(&self, _kind: &MsgKind) {}
+
+    /// A message with an incompatible protocol `version` was dropped
+    fn incr_incompatible_messages(&self, _version: u16) {}
+
+    /// A message without a valid HMAC under any of our secret keys was dropped
+    fn incr_unauthenticated_messages(&self) {}
+
+    /// Bytes that none of our encryption keys could decrypt were dropped
+    fn incr_undecryptable_messages(&self) {}
+
+    /// Bytes flagged as compressed that didn't decompress were dropped
+    fn incr_undecompressable_messages(&self) {}
+
+    /// A message repeating a sequence number its sender had already used, or too old to
+    /// tell, was dropped
+    fn incr_replayed_messages(&self) {}
+
+    /// A message was dropped because the transport's buffer was full, see
+    /// [`VecTransport::bounded`](crate::VecTransport::bounded)
+    fn incr_dropped_messages(&self) {}
+
+    /// One of our own probes was acked `rtt` after the ping went out
+    fn observe_probe_rtt(&self, _rtt: Duration) {}
+
+    /// Peers we believe are alive, counting ourselves
+    fn set_alive_count(&self, _count: usize) {}
+
+    /// Peers currently suspected
+    fn set_suspect_count(&self, _count: usize) {}
+
+    /// Broadcasts waiting to be gossiped
+    fn set_broadcast_queue_len(&self, _len: usize) {}
+}
+
+/// Records nothing
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// Running totals of a server's traffic and the state of its queues, from
+/// [`Server::stats`](crate::Server::stats). Lighter than implementing [`Metrics`] when all
+/// that's wanted is something to poll.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Stats {
+    /// Messages that went out through the transport
+    pub sent: MessageCounts,
+    /// Messages [`Server::process`](crate::Server::process) accepted, leaving out those
+    /// dropped as misdirected, unauthenticated, foreign, incompatible or replayed
+    pub received: MessageCounts,
+    /// Rumors piggybacked on what we sent, counting each time a rumor went out
+    pub rumors_sent: u64,
+    /// Rumors piggybacked on what we received
+    pub rumors_received: u64,
+    /// Broadcasts waiting to be gossiped
+    pub broadcast_queue_len: usize,
+    /// Pings awaiting an ack, ours and those we're sending for others
+    pub pending_pings: usize,
+}
+
+/// How many messages of each kind, see [`Stats`]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct MessageCounts {
+    pub pings: u64,
+    pub acks: u64,
+    pub ping_reqs: u64,
+    pub nacks: u64,
+    pub pushes: u64,
+    /// Both full Pulls and digests
+    pub pulls: u64,
+    pub gossip: u64,
+}
+
+impl MessageCounts {
+    /// Count a message of `kind`
+    pub(crate) fn incr(&mut self, kind: &MsgKind) {
+        let count = match kind {
+            MsgKind::Ping => &mut self.pings,
+            MsgKind::Ack(..) => &mut self.acks,
+            MsgKind::PingReq { .. } => &mut self.ping_reqs,
+            MsgKind::Nack { .. } => &mut self.nacks,
+            MsgKind::Push(_) => &mut self.pushes,
+            MsgKind::Pull(_) | MsgKind::PullDigest(_) => &mut self.pulls,
+            MsgKind::Gossip(_) => &mut self.gossip,
+        };
+        *count += 1;
+    }
+
+    /// Messages of every kind
+    pub fn total(&self) -> u64 {
+        self.pings
+            + self.acks
+            + self.ping_reqs
+            + self.nacks
+            + self.pushes
+            + self.pulls
+            + self.gossip
+    }
+}
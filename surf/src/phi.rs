@@ -0,0 +1,124 @@
+//! The phi-accrual failure detector (Hayashibara et al.), which rates how suspicious a peer's
+//! silence is against how regularly it has answered before, rather than against a fixed
+//! timeout.
+
+use alloc::collections::VecDeque;
+use core::time::Duration;
+
+use crate::{math, Instant};
+
+/// How many of the latest intervals between acks are kept per peer
+const WINDOW: usize = 100;
+
+/// How many intervals we need before phi means anything
+const MIN_SAMPLES: usize = 3;
+
+/// When a peer last answered us and how long it took between recent answers
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ArrivalWindow {
+    last: Option<Instant>,
+    intervals: VecDeque<Duration>,
+}
+
+impl ArrivalWindow {
+    /// The peer answered at `now`
+    pub(crate) fn heartbeat(&mut self, now: Instant) {
+        if let Some(last) = self.last {
+            if self.intervals.len() == WINDOW {
+                self.intervals.pop_front();
+            }
+            self.intervals
+                .push_back(now.saturating_duration_since(last));
+        }
+        self.last = Some(now);
+    }
+
+    /// Start the silence over from `now` without counting it as an answer, so a peer we just
+    /// suspected has a fresh interval to answer in before we suspect it again
+    pub(crate) fn restart(&mut self, now: Instant) {
+        self.last = Some(now);
+    }
+
+    /// Whether we've seen enough answers to judge
+    pub(crate) fn ready(&self) -> bool {
+        self.intervals.len() >= MIN_SAMPLES
+    }
+
+    /// How suspicious the silence since the last answer is at `now`: the chance that an
+    /// answer is still on its way is `10^-phi`. Intervals are assumed normally distributed
+    /// with a standard deviation of at least `min_std_dev`, so a perfectly regular peer
+    /// isn't suspected the instant it's late.
+    pub(crate) fn phi(&self, now: Instant, min_std_dev: Duration) -> f64 {
+        let last = match self.last {
+            Some(last) if !self.intervals.is_empty() => last,
+            _ => return 0.0,
+        };
+        let n = self.intervals.len() as f64;
+        let mean = self
+            .intervals
+            .iter()
+            .map(Duration::as_secs_f64)
+            .sum::<f64>()
+            / n;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| {
+                let deviation = i.as_secs_f64() - mean;
+                deviation * deviation
+            })
+            .sum::<f64>()
+            / n;
+        let std_dev = math::sqrt(variance).max(min_std_dev.as_secs_f64());
+        let elapsed = now.saturating_duration_since(last).as_secs_f64();
+        // A logistic approximation of the normal distribution's tail
+        let y = (elapsed - mean) / std_dev;
+        let e = math::exp(-y * (1.5976 + 0.070566 * y * y));
+        if elapsed > mean {
+            -math::log10(e / (1.0 + e))
+        } else {
+            -math::log10(1.0 - 1.0 / (1.0 + e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phi_grows_with_silence() {
+        let start = Instant::now();
+        let mut window = ArrivalWindow::default();
+        let period = Duration::from_millis(100);
+        for i in 0..10 {
+            window.heartbeat(start + period * i);
+        }
+        assert!(window.ready());
+        let last = start + period * 9;
+        let min_std_dev = Duration::from_millis(10);
+        let phi = |after| window.phi(last + after, min_std_dev);
+        assert!(phi(Duration::from_millis(50)) < 1.0);
+        assert!(phi(Duration::from_millis(120)) < 8.0);
+        assert!(phi(Duration::from_millis(200)) > 8.0);
+        assert!(phi(Duration::from_millis(120)) < phi(Duration::from_millis(130)));
+    }
+
+    #[test]
+    fn erratic_peers_get_more_slack() {
+        let start = Instant::now();
+        let mut steady = ArrivalWindow::default();
+        let mut erratic = ArrivalWindow::default();
+        let mut at = start;
+        for i in 0..20 {
+            steady.heartbeat(start + Duration::from_millis(100) * i);
+            at += Duration::from_millis(if i % 2 == 0 { 20 } else { 180 });
+            erratic.heartbeat(at);
+        }
+        let min_std_dev = Duration::from_millis(10);
+        let silence = Duration::from_millis(250);
+        let steady_phi = steady.phi(start + Duration::from_millis(1900) + silence, min_std_dev);
+        let erratic_phi = erratic.phi(at + silence, min_std_dev);
+        assert!(erratic_phi < steady_phi);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::seq_distance;
+
+/// How many sequence numbers behind the newest one we can still tell apart
+const WINDOW: u64 = u64::BITS as u64;
+
+/// Which recent sequence numbers a peer has sent us, so a captured message can't be replayed.
+/// Sequence numbers wrap, so "newer" means less than half the number space ahead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ReplayWindow {
+    newest: u64,
+    /// Bit `n` is set once we've seen `newest - n`
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub(crate) fn new(seq_no: u64) -> Self {
+        ReplayWindow {
+            newest: seq_no,
+            seen: 1,
+        }
+    }
+
+    /// Record `seq_no`, unless we've already seen it or it's too old to tell
+    pub(crate) fn accept(&mut self, seq_no: u64) -> bool {
+        let ahead = seq_distance(self.newest, seq_no);
+        if ahead > 0 {
+            self.seen = match ahead.unsigned_abs() {
+                shift if shift < WINDOW => self.seen << shift | 1,
+                _ => 1,
+            };
+            self.newest = seq_no;
+            return true;
+        }
+        let behind = ahead.unsigned_abs();
+        if behind == 0 || behind >= WINDOW || self.seen & 1 << behind != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_and_stale_seq_nos_are_rejected() {
+        let mut window = ReplayWindow::new(100);
+        assert!(!window.accept(100));
+        assert!(window.accept(103));
+        // Reordered but new
+        assert!(window.accept(101));
+        assert!(!window.accept(101));
+        assert!(window.accept(103 + WINDOW - 1));
+        assert!(!window.accept(102));
+        assert!(window.accept(103 + WINDOW));
+        assert!(!window.accept(103));
+    }
+
+    #[test]
+    fn windows_survive_wraparound() {
+        let mut window = ReplayWindow::new(u64::MAX - 1);
+        assert!(window.accept(1));
+        assert!(window.accept(u64::MAX));
+        assert!(!window.accept(u64::MAX - 1));
+        assert!(window.accept(0));
+        assert!(!window.accept(0));
+        // Half the number space back is far in the past, not the future
+        assert!(!window.accept(1u64.wrapping_add(u64::MAX / 2 + 1)));
+    }
+}
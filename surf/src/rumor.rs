@@ -1,7 +1,8 @@
 use crate::{Incarnation, PeerId};
-use std::cmp::Ordering;
-use std::mem;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::mem;
+use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 #[derive(Debug, thiserror::Error, PartialEq)]
 pub enum DeserializationError {
@@ -13,39 +14,109 @@ pub enum DeserializationError {
     InvalidRumor(u8),
     #[error("unknown ip version {0}")]
     InvalidIp(u8),
+    #[error("user data of {0} bytes exceeds the {MAX_USER_DATA} byte limit")]
+    UserDataTooLarge(usize),
 }
 
+/// Largest payload a user rumor may carry
+pub const MAX_USER_DATA: usize = 512;
+/// Largest metadata a peer may attach to its Alive rumors
+pub const MAX_META: usize = u8::MAX as usize;
+
 /// Node states
-#[derive(PartialEq, Debug, Clone, Copy, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(PartialEq, Debug, Clone, Eq)]
 pub enum RumorKind {
-    /// Alive messages also deliver details for new peers
-    Alive(SocketAddr),
-    Suspect,
+    /// Alive messages also deliver details for new peers: where they are and whatever
+    /// metadata they've attached
+    Alive {
+        #[cfg_attr(feature = "serde", serde(with = "crate::socket_addr"))]
+        addr: SocketAddr,
+        meta: Vec<u8>,
+    },
+    /// Carries who suspects the peer, so independent suspicions can be told apart
+    Suspect {
+        from: PeerId,
+    },
     Failed,
-    // How to handle custom user commands?
-    // User(u8, [u8; 512]),
+    /// The peer left the cluster gracefully
+    Depart,
+    /// Application data gossiped alongside membership. These don't describe the peer's state;
+    /// the rumor's peer is whoever broadcast it.
+    User {
+        /// Sequence number the sender stamped on this broadcast, so the same data sent twice
+        /// is delivered twice
+        seq_no: u64,
+        tag: u8,
+        data: Vec<u8>,
+    },
 }
 
 impl RumorKind {
+    /// An Alive rumor without metadata
+    pub fn alive(addr: SocketAddr) -> Self {
+        RumorKind::Alive {
+            addr,
+            meta: Vec::new(),
+        }
+    }
+
+    /// How many bytes [`RumorKind::serialize_to`] writes, without writing them
+    pub fn estimated_size(&self) -> usize {
+        let value = match self {
+            RumorKind::Suspect { .. } => mem::size_of::<PeerId>(),
+            RumorKind::Failed | RumorKind::Depart => 0,
+            RumorKind::Alive { addr, meta } => {
+                let addr = match addr {
+                    // ip, port
+                    SocketAddr::V4(_) => 4 + 2,
+                    // ip, port, flowinfo, scope id
+                    SocketAddr::V6(_) => 16 + 2 + 4 + 4,
+                };
+                addr + 1 + meta.len()
+            }
+            // sequence number, tag, length, data
+            RumorKind::User { data, .. } => 8 + 1 + 2 + data.len(),
+        };
+        1 + value
+    }
+
     pub fn serialize_to(&self, buf: &mut Vec<u8>) {
         match self {
-            RumorKind::Suspect => {
+            RumorKind::Suspect { from } => {
                 buf.extend_from_slice(&1u8.to_le_bytes());
+                from.serialize_to(buf);
             }
             RumorKind::Failed => {
                 buf.extend_from_slice(&2u8.to_le_bytes());
             }
-            RumorKind::Alive(SocketAddr::V4(sa4)) => {
-                buf.extend_from_slice(&4u8.to_le_bytes());
-                buf.extend_from_slice(&sa4.ip().octets());
-                buf.extend_from_slice(&sa4.port().to_le_bytes());
+            RumorKind::Depart => {
+                buf.extend_from_slice(&3u8.to_le_bytes());
+            }
+            RumorKind::Alive { addr, meta } => {
+                match addr {
+                    SocketAddr::V4(sa4) => {
+                        buf.extend_from_slice(&4u8.to_le_bytes());
+                        buf.extend_from_slice(&sa4.ip().octets());
+                        buf.extend_from_slice(&sa4.port().to_le_bytes());
+                    }
+                    SocketAddr::V6(sa6) => {
+                        buf.extend_from_slice(&6u8.to_le_bytes());
+                        buf.extend_from_slice(&sa6.ip().octets());
+                        buf.extend_from_slice(&sa6.port().to_le_bytes());
+                        buf.extend_from_slice(&sa6.flowinfo().to_le_bytes());
+                        buf.extend_from_slice(&sa6.scope_id().to_le_bytes());
+                    }
+                }
+                buf.push(meta.len() as u8);
+                buf.extend_from_slice(meta);
             }
-            RumorKind::Alive(SocketAddr::V6(sa6)) => {
-                buf.extend_from_slice(&6u8.to_le_bytes());
-                buf.extend_from_slice(&sa6.ip().octets());
-                buf.extend_from_slice(&sa6.port().to_le_bytes());
-                buf.extend_from_slice(&sa6.flowinfo().to_le_bytes());
-                buf.extend_from_slice(&sa6.scope_id().to_le_bytes());
+            RumorKind::User { seq_no, tag, data } => {
+                buf.extend_from_slice(&5u8.to_le_bytes());
+                buf.extend_from_slice(&seq_no.to_le_bytes());
+                buf.extend_from_slice(&tag.to_le_bytes());
+                buf.extend_from_slice(&(data.len() as u16).to_le_bytes());
+                buf.extend_from_slice(data);
             }
         }
     }
@@ -55,8 +126,18 @@ impl RumorKind {
     pub fn deserialize(bytes: &[u8]) -> Result<(RumorKind, &[u8]), DeserializationError> {
         // FIXME: return `rest` here
         match bytes[0] {
-            1 => Ok((RumorKind::Suspect, &bytes[1..])),
+            1 => {
+                // tag + suspector
+                const LEN: usize = 1 + mem::size_of::<PeerId>();
+                if bytes.len() < LEN {
+                    return Err(DeserializationError::TooSmall(LEN - bytes.len()));
+                }
+                let (from_bytes, rest) = bytes[1..].split_at(mem::size_of::<PeerId>());
+                let from = PeerId::deserialize(from_bytes.try_into().unwrap());
+                Ok((RumorKind::Suspect { from }, rest))
+            }
             2 => Ok((RumorKind::Failed, &bytes[1..])),
+            3 => Ok((RumorKind::Depart, &bytes[1..])),
             4 => {
                 // Alive v4
                 if bytes.len() < 7 {
@@ -69,8 +150,12 @@ impl RumorKind {
                 let ip = Ipv4Addr::from(octets);
                 let (port_bytes, rest) = rest.split_at(2);
                 let port = u16::from_le_bytes(port_bytes.try_into().unwrap());
+                let (meta, rest) = deserialize_meta(rest)?;
                 Ok((
-                    RumorKind::Alive(SocketAddr::V4(SocketAddrV4::new(ip, port))),
+                    RumorKind::Alive {
+                        addr: SocketAddr::V4(SocketAddrV4::new(ip, port)),
+                        meta,
+                    },
                     rest,
                 ))
             }
@@ -93,8 +178,38 @@ impl RumorKind {
 
                 let (sb, rest) = rest.split_at(4);
                 let si = u32::from_le_bytes(sb.try_into().unwrap());
+                let (meta, rest) = deserialize_meta(rest)?;
                 Ok((
-                    RumorKind::Alive(SocketAddr::V6(SocketAddrV6::new(ip, port, fi, si))),
+                    RumorKind::Alive {
+                        addr: SocketAddr::V6(SocketAddrV6::new(ip, port, fi, si)),
+                        meta,
+                    },
+                    rest,
+                ))
+            }
+            5 => {
+                // tag + sequence number + user tag + u16 length
+                const LEN: usize = 1 + 8 + 1 + 2;
+                if bytes.len() < LEN {
+                    return Err(DeserializationError::TooSmall(LEN - bytes.len()));
+                }
+                let seq_no = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+                let tag = bytes[9];
+                let (len_bytes, rest) = bytes[10..].split_at(2);
+                let len = u16::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+                if len > MAX_USER_DATA {
+                    return Err(DeserializationError::UserDataTooLarge(len));
+                }
+                if rest.len() < len {
+                    return Err(DeserializationError::TooSmall(len - rest.len()));
+                }
+                let (data, rest) = rest.split_at(len);
+                Ok((
+                    RumorKind::User {
+                        seq_no,
+                        tag,
+                        data: data.to_vec(),
+                    },
                     rest,
                 ))
             }
@@ -104,10 +219,18 @@ impl RumorKind {
 
     pub fn tag(&self) -> u8 {
         match self {
-            RumorKind::Suspect => 1,
+            RumorKind::Suspect { .. } => 1,
             RumorKind::Failed => 2,
-            RumorKind::Alive(SocketAddr::V4(_)) => 4,
-            RumorKind::Alive(SocketAddr::V6(_)) => 6,
+            RumorKind::Depart => 3,
+            RumorKind::Alive {
+                addr: SocketAddr::V4(_),
+                ..
+            } => 4,
+            RumorKind::Alive {
+                addr: SocketAddr::V6(_),
+                ..
+            } => 6,
+            RumorKind::User { .. } => 5,
         }
     }
 }
@@ -119,15 +242,42 @@ impl PartialOrd for RumorKind {
             return Some(Ordering::Equal);
         }
         match (self, other) {
+            (Depart, _) => Some(Ordering::Greater),
+            (_, Depart) => Some(Ordering::Less),
             (Failed, _) => Some(Ordering::Greater),
             (_, Failed) => Some(Ordering::Less),
+            (Suspect { .. }, Alive { .. }) => Some(Ordering::Greater),
+            (Alive { .. }, Suspect { .. }) => Some(Ordering::Less),
             _ => None,
         }
     }
 }
 
+/// Read an Alive rumor's length-prefixed metadata
+fn deserialize_meta(bytes: &[u8]) -> Result<(Vec<u8>, &[u8]), DeserializationError> {
+    let (len, rest) = match bytes.split_first() {
+        Some((len, rest)) => (*len as usize, rest),
+        None => return Err(DeserializationError::TooSmall(1)),
+    };
+    if rest.len() < len {
+        return Err(DeserializationError::TooSmall(len - rest.len()));
+    }
+    let (meta, rest) = rest.split_at(len);
+    Ok((meta.to_vec(), rest))
+}
+
+/// See [`Rumor::subject`]
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum Subject {
+    /// Membership news about a peer
+    Peer(PeerId),
+    /// A user broadcast, identified by who sent it and the sequence number they stamped on it
+    User(PeerId, u64),
+}
+
 /// Rumors disseminated on top of normal gossip
-#[derive(PartialEq, Debug, Copy, Clone, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(PartialEq, Debug, Clone, Eq)]
 pub struct Rumor {
     /// ID of the peer this rumor is about
     pub peer_id: PeerId,
@@ -161,10 +311,24 @@ impl Rumor {
         ))
     }
 
+    /// What this rumor is news about. Newer news about the same subject supersedes older.
+    pub fn subject(&self) -> Subject {
+        match &self.kind {
+            RumorKind::User { seq_no, .. } => Subject::User(self.peer_id, *seq_no),
+            _ => Subject::Peer(self.peer_id),
+        }
+    }
+
+    /// How many bytes [`Rumor::serialize`] produces, without serializing, so callers can plan
+    /// what fits in a datagram
+    pub fn estimated_size(&self) -> usize {
+        mem::size_of::<PeerId>() + mem::size_of::<Incarnation>() + self.kind.estimated_size()
+    }
+
     /// rumors are serialized as:
     /// peer_id, incarnation, rumor_kind_tag, rumor_kind_value
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(SMALLEST_RUMOR);
+        let mut buf = Vec::with_capacity(self.estimated_size());
         self.peer_id.serialize_to(&mut buf);
         self.incarnation.serialize_to(&mut buf);
         self.kind.serialize_to(&mut buf);
@@ -173,11 +337,28 @@ impl Rumor {
     }
 }
 
+impl Rumor {
+    /// Whether this news replaces `current`, news about the same peer, by outranking it
+    pub fn supersedes(&self, current: &Rumor) -> bool {
+        self.partial_cmp(current) == Some(Ordering::Greater)
+    }
+}
+
 impl PartialOrd for Rumor {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         if self.peer_id != other.peer_id {
             return None;
         }
+        // Departures outrank everything at their incarnation and suspicions and failures at
+        // any, so only a peer that rejoins at a later one undoes them
+        match (&self.kind, &other.kind) {
+            (RumorKind::Depart, RumorKind::Depart)
+            | (RumorKind::Depart, RumorKind::Alive { .. })
+            | (RumorKind::Alive { .. }, RumorKind::Depart) => {}
+            (RumorKind::Depart, _) => return Some(Ordering::Greater),
+            (_, RumorKind::Depart) => return Some(Ordering::Less),
+            _ => {}
+        }
         match self.incarnation.cmp(&other.incarnation) {
             Ordering::Equal => self.kind.partial_cmp(&other.kind),
             ord => Some(ord),
@@ -188,6 +369,7 @@ impl PartialOrd for Rumor {
 #[cfg(test)]
 mod rumor_tests {
     use super::*;
+    use alloc::boxed::Box;
     use pretty_hex::*;
     type TestResult<T = (), E = Box<dyn std::error::Error>> = Result<T, E>;
 
@@ -199,12 +381,12 @@ mod rumor_tests {
     fn test_only_cmp_same_peer() {
         let alive = Rumor {
             peer_id: 1.into(),
-            kind: RumorKind::Alive(sockaddr()),
+            kind: RumorKind::alive(sockaddr()),
             incarnation: 1.into(),
         };
         let alive2 = Rumor {
             peer_id: 2.into(),
-            kind: RumorKind::Alive(sockaddr()),
+            kind: RumorKind::alive(sockaddr()),
             incarnation: 33.into(),
         };
         assert_eq!(alive.partial_cmp(&alive2), None);
@@ -214,18 +396,18 @@ mod rumor_tests {
     fn test_rumor_precedence_favors_incarnation_num() {
         let alive1 = Rumor {
             peer_id: 1.into(),
-            kind: RumorKind::Alive(sockaddr()),
+            kind: RumorKind::alive(sockaddr()),
             incarnation: 1.into(),
         };
         let sus2 = Rumor {
             peer_id: 1.into(),
-            kind: RumorKind::Suspect,
+            kind: RumorKind::Suspect { from: 7.into() },
             incarnation: 2.into(),
         };
         assert_eq!(alive1.partial_cmp(&sus2), Some(Ordering::Less));
         let alive3 = Rumor {
             peer_id: 1.into(),
-            kind: RumorKind::Alive(sockaddr()),
+            kind: RumorKind::alive(sockaddr()),
             incarnation: 3.into(),
         };
         assert_eq!(alive3.partial_cmp(&sus2), Some(Ordering::Greater));
@@ -236,6 +418,28 @@ mod rumor_tests {
         };
         assert_eq!(failed2.partial_cmp(&alive3), Some(Ordering::Less));
         assert_eq!(failed2.partial_cmp(&sus2), Some(Ordering::Greater));
+        let depart1 = Rumor {
+            peer_id: 1.into(),
+            kind: RumorKind::Depart,
+            incarnation: 1.into(),
+        };
+        assert_eq!(depart1.partial_cmp(&alive3), Some(Ordering::Less));
+        assert_eq!(failed2.partial_cmp(&depart1), Some(Ordering::Less));
+        // Stale departures don't undo a rejoin, and only rejoining at a later incarnation
+        // undoes a departure
+        assert!(!depart1.supersedes(&alive3));
+        assert!(alive3.supersedes(&depart1));
+        assert!(!failed2.supersedes(&depart1));
+        assert!(Rumor {
+            kind: RumorKind::Depart,
+            ..alive3.clone()
+        }
+        .supersedes(&alive3));
+        assert!(!Rumor {
+            incarnation: 1.into(),
+            ..alive3.clone()
+        }
+        .supersedes(&depart1));
     }
 
     #[test]
@@ -243,29 +447,46 @@ mod rumor_tests {
         let rumors = [
             Rumor {
                 peer_id: 0.into(),
-                kind: RumorKind::Alive(sockaddr()),
+                kind: RumorKind::alive(sockaddr()),
                 incarnation: 1.into(),
             },
             Rumor {
                 peer_id: 1.into(),
-                kind: RumorKind::Alive(SocketAddr::V6(SocketAddrV6::new(
-                    Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
-                    8080,
-                    13,
-                    89,
-                ))),
+                kind: RumorKind::Alive {
+                    addr: SocketAddr::V6(SocketAddrV6::new(
+                        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+                        8080,
+                        13,
+                        89,
+                    )),
+                    meta: b"zone=us-east-1a".to_vec(),
+                },
                 incarnation: 1.into(),
             },
             Rumor {
                 peer_id: 99.into(),
-                kind: RumorKind::Suspect,
+                kind: RumorKind::Suspect { from: 7.into() },
                 incarnation: 12.into(),
             },
             Rumor {
                 peer_id: 2.into(),
-                kind: RumorKind::Suspect,
+                kind: RumorKind::Suspect { from: 7.into() },
                 incarnation: 3.into(),
             },
+            Rumor {
+                peer_id: 3.into(),
+                kind: RumorKind::Depart,
+                incarnation: 7.into(),
+            },
+            Rumor {
+                peer_id: 4.into(),
+                kind: RumorKind::User {
+                    seq_no: u64::MAX - 1,
+                    tag: 9,
+                    data: b"hello".to_vec(),
+                },
+                incarnation: 2.into(),
+            },
         ];
         for rumor in rumors {
             let (r, _) = Rumor::deserialize(&rumor.serialize())?;
@@ -274,28 +495,47 @@ mod rumor_tests {
         Ok(())
     }
 
+    #[test]
+    fn reject_oversized_user_data() {
+        let rumor = Rumor {
+            peer_id: 4.into(),
+            kind: RumorKind::User {
+                seq_no: 1,
+                tag: 9,
+                data: vec![0; MAX_USER_DATA + 1],
+            },
+            incarnation: 2.into(),
+        };
+        assert_eq!(
+            Rumor::deserialize(&rumor.serialize()),
+            Err(DeserializationError::UserDataTooLarge(MAX_USER_DATA + 1))
+        );
+    }
+
     #[test]
     fn deserialize() -> TestResult {
-        let mut buf = [0u8; 15];
-        // [0, 4) are 0 for peer_id 0
-        // [4, 8) are incarnation 1
-        buf[4] = 1;
+        let mut buf = [0u8; 24];
+        // [0, 8) are 0 for peer_id 0
+        // [8, 16) are incarnation 1
+        buf[8] = 1;
         // u8 rumorkind tag. 4 for Alive IPv4
-        buf[8] = 4;
+        buf[16] = 4;
         // 4 bytes for the octets
-        buf[9] = 127;
-        buf[10] = 0;
-        buf[11] = 0;
-        buf[12] = 1;
+        buf[17] = 127;
+        buf[18] = 0;
+        buf[19] = 0;
+        buf[20] = 1;
         // 2 bytes for the port
-        buf[13..15].copy_from_slice(&(8080u16).to_le_bytes());
+        buf[21..23].copy_from_slice(&(8080u16).to_le_bytes());
+        // u8 metadata length, then no metadata
+        buf[23] = 0;
         match Rumor::deserialize(&buf) {
             Ok((deser, _)) => {
                 assert_eq!(
                     Rumor {
                         peer_id: 0.into(),
                         incarnation: 1.into(),
-                        kind: RumorKind::Alive(sockaddr()),
+                        kind: RumorKind::alive(sockaddr()),
                     },
                     deser,
                     "Incorrectly parsed\n{:?}",
@@ -312,23 +552,25 @@ mod rumor_tests {
 
     #[test]
     fn deserialize_many() -> TestResult {
-        let mut buf = [0u8; 26];
+        let mut buf = [0u8; 51];
         // two rumors
         buf[0] = 2;
         // peer 0
         buf[2] = 0;
-        buf[6] = 1;
-        buf[10] = 4;
-        buf[11] = 127;
-        buf[12] = 0;
-        buf[13] = 0;
-        buf[14] = 1;
+        buf[10] = 1;
+        buf[18] = 4;
+        buf[19] = 127;
+        buf[20] = 0;
+        buf[21] = 0;
+        buf[22] = 1;
         // 2 bytes for the port
-        buf[15..17].copy_from_slice(&(8080u16).to_le_bytes());
-        // second rumor
-        buf[17] = 1;
-        buf[21] = 3;
-        buf[25] = 1; // tag 1 is suspect
+        buf[23..25].copy_from_slice(&(8080u16).to_le_bytes());
+        buf[25] = 0; // no metadata
+                     // second rumor
+        buf[26] = 1;
+        buf[34] = 3;
+        buf[42] = 1; // tag 1 is suspect
+        buf[43] = 7; // by peer 7
 
         let rest = Rumor::deserialize(&buf[2..])
             .map(|(deser, rest)| {
@@ -336,25 +578,24 @@ mod rumor_tests {
                     Rumor {
                         peer_id: 0.into(),
                         incarnation: 1.into(),
-                        kind: RumorKind::Alive(sockaddr()),
+                        kind: RumorKind::alive(sockaddr()),
                     },
                     deser,
                     "first rumor is incorrect"
                 );
                 rest
             })
-            .map_err(|e| {
+            .inspect_err(|_| {
                 eprintln!("Failed to parse rumor from\n{:?}", pretty_hex(&buf));
-                e
             })?;
 
-        match Rumor::deserialize(&rest) {
+        match Rumor::deserialize(rest) {
             Ok((deser, _)) => {
                 assert_eq!(
                     Rumor {
                         peer_id: 1.into(),
                         incarnation: 3.into(),
-                        kind: RumorKind::Suspect,
+                        kind: RumorKind::Suspect { from: 7.into() },
                     },
                     deser,
                     "second rumor is incorrect"
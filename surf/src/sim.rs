@@ -0,0 +1,430 @@
+//! An in-memory network for exercising many [`Server`]s at once, for convergence and failure
+//! testing without sockets or wall-clock time.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::mem::take;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use rand::prelude::*;
+
+use crate::{Clock, ConfigError, Event, Message, PeerId, Server, ServerBuilder};
+
+/// Room for the rumors piggybacked on each simulated message
+const SIM_GOSSIP_SIZE: usize = 512;
+
+/// Steps between anti-entropy rounds unless set otherwise
+const DEFAULT_PUSH_PULL_INTERVAL: usize = 20;
+
+/// Logical time shared by every server in a [`Simulator`], advanced only by
+/// [`Simulator::step`]
+#[derive(Debug, Clone)]
+pub struct SimClock(Rc<Cell<Instant>>);
+
+impl SimClock {
+    fn new() -> Self {
+        SimClock(Rc::new(Cell::new(Instant::now())))
+    }
+
+    fn advance(&self, by: Duration) {
+        self.0.set(self.0.get() + by);
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        self.0.get()
+    }
+}
+
+/// A message on the simulated wire, with the rumors its sender piggybacked on it
+struct InFlight {
+    msg: Message,
+    gossip: Vec<u8>,
+}
+
+/// Owns a set of servers and routes their outgoing messages to each other by `dest_id`, with
+/// configurable loss, delay and partitions. Every step advances a logical clock, ticks each
+/// server, then delivers whatever arrived.
+///
+/// Loss, delay and each server's RNG are all drawn from the simulator's seed.
+pub struct Simulator {
+    servers: BTreeMap<PeerId, Server>,
+    clock: SimClock,
+    step: Duration,
+    rng: StdRng,
+    /// Messages to deliver on the next step
+    in_flight: Vec<InFlight>,
+    /// Chance that any message is dropped
+    loss: f64,
+    /// Chance that a message is held back another step, so delays compound
+    delay: f64,
+    /// One side of a partition; messages between it and everyone else are dropped
+    partition: HashSet<PeerId>,
+    /// How many steps between each server's anti-entropy rounds, if any
+    push_pull_interval: usize,
+    steps: usize,
+}
+
+impl Simulator {
+    /// An empty, lossless network whose clock advances by `step` each [`Simulator::step`]
+    pub fn new(step: Duration, seed: u64) -> Self {
+        Simulator {
+            servers: BTreeMap::new(),
+            clock: SimClock::new(),
+            step,
+            rng: StdRng::seed_from_u64(seed),
+            in_flight: Vec::new(),
+            loss: 0.0,
+            delay: 0.0,
+            partition: HashSet::new(),
+            push_pull_interval: DEFAULT_PUSH_PULL_INTERVAL,
+            steps: 0,
+        }
+    }
+
+    /// Drop each message with probability `p`
+    pub fn set_loss(&mut self, p: f64) {
+        self.loss = p;
+    }
+
+    /// Hold each message back for another step with probability `p`
+    pub fn set_delay(&mut self, p: f64) {
+        self.delay = p;
+    }
+
+    /// Have every server start an anti-entropy round with [`Server::push_pull`] each
+    /// `steps` steps, or never if zero. Without these, a rumor that ran out of retransmits
+    /// before reaching everyone is never repaired.
+    pub fn set_push_pull_interval(&mut self, steps: usize) {
+        self.push_pull_interval = steps;
+    }
+
+    /// Cut `side` off from every other server until [`Simulator::heal`]
+    pub fn partition(&mut self, side: &[PeerId]) {
+        self.partition = side.iter().copied().collect();
+    }
+
+    /// Undo [`Simulator::partition`]
+    pub fn heal(&mut self) {
+        self.partition.clear();
+    }
+
+    /// Build a server on the simulated network. Its clock and RNG are replaced with the
+    /// simulator's.
+    pub fn add(&mut self, builder: ServerBuilder) -> Result<PeerId, ConfigError> {
+        let rng = StdRng::seed_from_u64(self.rng.gen());
+        let server = builder.clock(self.clock.clone()).rng(rng).build()?;
+        let id = server.id;
+        self.servers.insert(id, server);
+        Ok(id)
+    }
+
+    /// Stop `id` as if it crashed. Messages to it are dropped from then on.
+    pub fn crash(&mut self, id: PeerId) -> Option<Server> {
+        self.servers.remove(&id)
+    }
+
+    pub fn server(&self, id: PeerId) -> Option<&Server> {
+        self.servers.get(&id)
+    }
+
+    pub fn server_mut(&mut self, id: PeerId) -> Option<&mut Server> {
+        self.servers.get_mut(&id)
+    }
+
+    /// The servers still running, in id order
+    pub fn servers(&self) -> impl Iterator<Item = &Server> {
+        self.servers.values()
+    }
+
+    /// Have `id` join the cluster through `seed`, retrying until the seed answers
+    pub fn join(&mut self, id: PeerId, seed: PeerId) {
+        let seed_addr = match self.servers.get(&seed) {
+            Some(s) => s.addr,
+            None => return,
+        };
+        if let Some(server) = self.servers.get_mut(&id) {
+            server.join_seeds(&[(seed, seed_addr)]);
+        }
+    }
+
+    /// Put `msg` on the wire as though its sender had sent it
+    pub fn send(&mut self, msg: Message) {
+        self.in_flight.push(InFlight {
+            msg,
+            gossip: Vec::new(),
+        });
+    }
+
+    /// The simulated time
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Advance the clock by one step, tick every server whose next probe is due and time out
+    /// the rest, then deliver what's in flight. Responses go out on the next step. Returns
+    /// what each server reported.
+    pub fn step(&mut self) -> Vec<(PeerId, Event)> {
+        self.clock.advance(self.step);
+        self.steps += 1;
+        let now = self.clock.now();
+        let push_pull =
+            self.push_pull_interval > 0 && self.steps.is_multiple_of(self.push_pull_interval);
+        let mut events = Vec::new();
+        let mut sent = Vec::new();
+        for (id, server) in self.servers.iter_mut() {
+            let reported = if now >= server.next_probe() {
+                server.tick(now)
+            } else {
+                server.expire(now)
+            };
+            events.extend(reported.into_iter().map(|e| (*id, e)));
+            if push_pull {
+                server.push_pull();
+            }
+            sent.extend(drain(server));
+        }
+        for InFlight { msg, gossip } in take(&mut self.in_flight) {
+            if self.rng.gen_bool(self.delay) {
+                trace!("{:03} -- {:?} -? {:03}", msg.src_id, msg.kind, msg.dest_id);
+                sent.push(InFlight { msg, gossip });
+                continue;
+            }
+            if self.rng.gen_bool(self.loss)
+                || self.partition.contains(&msg.src_id) != self.partition.contains(&msg.dest_id)
+            {
+                trace!("{:03} -- {:?} -X {:03}", msg.src_id, msg.kind, msg.dest_id);
+                continue;
+            }
+            trace!("{:03} -- {:?} -> {:03}", msg.src_id, msg.kind, msg.dest_id);
+            let msg = wire(msg);
+            let server = match self.servers.get_mut(&msg.dest_id) {
+                Some(server) => server,
+                None => continue,
+            };
+            let id = server.id;
+            match server.process_with_gossip(msg, &gossip) {
+                Ok(processed) => events.extend(processed.into_iter().map(|e| (id, e))),
+                Err(e) => {
+                    warn!("{:03} dropped a message: {}", id, e);
+                    continue;
+                }
+            }
+            sent.extend(drain(server));
+        }
+        self.in_flight = sent;
+        events
+    }
+
+    /// Step `steps` times
+    pub fn run(&mut self, steps: usize) -> Vec<(PeerId, Event)> {
+        (0..steps).flat_map(|_| self.step()).collect()
+    }
+
+    /// Whether every running server counts exactly the running servers as members, alive or
+    /// suspected
+    pub fn converged(&self) -> bool {
+        self.servers.values().all(|server| {
+            let members = server
+                .members()
+                .filter(|p| p.state.is_probed())
+                .map(|p| p.id);
+            members.clone().all(|id| self.servers.contains_key(&id))
+                && members.count() == self.servers.len()
+        })
+    }
+}
+
+/// Everything `server` sent, each with what it had to gossip
+fn drain(server: &mut Server) -> Vec<InFlight> {
+    server
+        .outbox()
+        .into_iter()
+        .map(|mut msg| {
+            let mut gossip = vec![0; SIM_GOSSIP_SIZE];
+            let len = server.piggyback(&mut msg, &mut gossip);
+            gossip.truncate(len);
+            InFlight { msg, gossip }
+        })
+        .collect()
+}
+
+/// Round-trip through the wire format like a real network would, when there is one
+#[cfg(feature = "bincode")]
+fn wire(msg: Message) -> Message {
+    Message::from_bytes(&msg.to_bytes().expect("should encode")).expect("should decode")
+}
+
+#[cfg(not(feature = "bincode"))]
+fn wire(msg: Message) -> Message {
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::PeerState;
+
+    fn builder(id: u64) -> ServerBuilder {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9000 + id as u16));
+        Server::builder(id.into(), addr)
+            .ping_interval(Duration::from_millis(10))
+            .protocol_period(Duration::from_millis(50))
+            .suspicion_period(Duration::from_millis(150))
+    }
+
+    fn cluster(n: u64, seed: u64) -> Simulator {
+        let mut sim = Simulator::new(Duration::from_millis(5), seed);
+        for id in 0..n {
+            sim.add(builder(id)).unwrap();
+        }
+        for id in 1..n {
+            sim.join(id.into(), 0.into());
+        }
+        sim
+    }
+
+    /// Step until everyone has heard of `id` and it's learned what `seed` knows, so that
+    /// crashing whoever it did meet can't leave `id` stranded. Whether it did in time.
+    fn joined(sim: &mut Simulator, id: PeerId, seed: PeerId) -> bool {
+        for _ in 0..10_000 {
+            let (joiner, seed) = match (sim.server(id), sim.server(seed)) {
+                (Some(joiner), Some(seed)) => (joiner, seed),
+                _ => return false,
+            };
+            let welcomed = sim
+                .servers()
+                .filter(|s| s.id != id)
+                .all(|s| s.peer(id).is_some_and(|p| p.state().is_probed()));
+            // The joiner may already have given up on some of them
+            let learned = seed
+                .members()
+                .filter(|p| p.state.is_probed() && p.id != id && p.id != seed.id)
+                .all(|p| joiner.peer(p.id).is_some());
+            if welcomed && learned {
+                return true;
+            }
+            sim.step();
+        }
+        false
+    }
+
+    /// Something that happens to a simulated cluster
+    #[derive(Debug, Clone)]
+    enum Churn {
+        /// A new server joins through a running one
+        Join,
+        /// The running server at this index, modulo how many there are, crashes
+        Crash(usize),
+        /// The network starts dropping this fraction of messages
+        Loss(f64),
+        /// Time passes
+        Run(usize),
+    }
+
+    fn churn() -> impl Strategy<Value = Churn> {
+        prop_oneof![
+            Just(Churn::Join),
+            any::<usize>().prop_map(Churn::Crash),
+            // Much heavier loss can leave peers each thinking the other failed, which nothing
+            // here heals
+            (0.0..0.1).prop_map(Churn::Loss),
+            (1..60usize).prop_map(Churn::Run),
+        ]
+    }
+
+    #[test]
+    fn lossy_clusters_converge() {
+        let mut sim = cluster(8, 1);
+        sim.set_loss(0.1);
+        sim.run(400);
+        // Loss still fails the odd peer now and then, so give it a chance to refute
+        assert!((0..200).any(|_| {
+            sim.step();
+            sim.converged()
+        }));
+    }
+
+    #[test]
+    fn servers_probe_once_a_period() {
+        let mut sim = cluster(4, 3);
+        // 10 protocol periods of 10 steps each
+        sim.run(100);
+        assert!(sim.converged());
+        assert!(sim.servers().all(|s| s.stats().sent.pings <= 11));
+    }
+
+    #[test]
+    fn crashes_are_detected() {
+        let mut sim = cluster(6, 2);
+        sim.run(100);
+        assert!(sim.converged());
+        sim.crash(3.into());
+        assert!(!sim.converged());
+        sim.run(400);
+        assert!(sim.converged());
+        assert!(sim
+            .servers()
+            .all(|s| s.peer(3.into()).map(|p| p.state()) == Some(PeerState::Failed)));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn clusters_converge_once_churn_stops(
+            seed in any::<u64>(),
+            history in prop::collection::vec(churn(), 1..24),
+        ) {
+            let mut sim = cluster(3, seed);
+            for id in 1..3 {
+                prop_assert!(joined(&mut sim, id.into(), 0.into()));
+            }
+            // Every join is a new server; restarts under an old id aren't modeled
+            let mut next_id = 3;
+            for event in history {
+                match event {
+                    Churn::Join => {
+                        let seed = sim.servers().next().unwrap().id;
+                        let id = sim.add(builder(next_id)).unwrap();
+                        next_id += 1;
+                        sim.join(id, seed);
+                        prop_assert!(joined(&mut sim, id, seed));
+                    }
+                    Churn::Crash(i) => {
+                        let running: Vec<_> = sim.servers().map(|s| s.id).collect();
+                        // Someone has to be left to converge
+                        if running.len() > 1 {
+                            sim.crash(running[i % running.len()]);
+                        }
+                    }
+                    Churn::Loss(p) => sim.set_loss(p),
+                    Churn::Run(steps) => {
+                        sim.run(steps);
+                    }
+                }
+            }
+            sim.set_loss(0.0);
+            sim.run(1000);
+            prop_assert!(sim.converged());
+        }
+    }
+
+    #[test]
+    fn partitions_split_the_cluster() {
+        let mut sim = cluster(6, 3);
+        sim.run(100);
+        sim.partition(&[0.into(), 1.into(), 2.into()]);
+        sim.run(400);
+        assert!(!sim.converged());
+        let s = sim.server(0.into()).unwrap();
+        assert_eq!(s.peer(1.into()).unwrap().state(), PeerState::Alive);
+        assert_ne!(s.peer(4.into()).unwrap().state(), PeerState::Alive);
+    }
+}
@@ -0,0 +1,62 @@
+//! Serializes socket addresses for the wire without losing anything. Serde's own impls drop
+//! an IPv6 address's flow info and scope id in compact formats, which leaves link-local peers
+//! unreachable once their address has been through a Push. Use with
+//! `#[serde(with = "crate::socket_addr")]`.
+
+use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// What compact formats see: every field of either kind of address
+#[derive(Serialize, Deserialize)]
+enum Repr {
+    V4([u8; 4], u16),
+    V6 {
+        ip: [u8; 16],
+        port: u16,
+        flowinfo: u32,
+        scope_id: u32,
+    },
+}
+
+pub(crate) fn serialize<S: Serializer>(
+    addr: &SocketAddr,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    // Text formats already keep the scope id, as `[ip%scope]:port`
+    if serializer.is_human_readable() {
+        return addr.serialize(serializer);
+    }
+    let repr = match addr {
+        SocketAddr::V4(sa4) => Repr::V4(sa4.ip().octets(), sa4.port()),
+        SocketAddr::V6(sa6) => Repr::V6 {
+            ip: sa6.ip().octets(),
+            port: sa6.port(),
+            flowinfo: sa6.flowinfo(),
+            scope_id: sa6.scope_id(),
+        },
+    };
+    repr.serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<SocketAddr, D::Error> {
+    if deserializer.is_human_readable() {
+        return SocketAddr::deserialize(deserializer);
+    }
+    Ok(match Repr::deserialize(deserializer)? {
+        Repr::V4(ip, port) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port)),
+        Repr::V6 {
+            ip,
+            port,
+            flowinfo,
+            scope_id,
+        } => SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(ip),
+            port,
+            flowinfo,
+            scope_id,
+        )),
+    })
+}
@@ -0,0 +1,158 @@
+use alloc::vec::Vec;
+use core::mem::take;
+
+use crate::{Message, MsgKind, Priority, Rumor};
+
+/// Delivers a [`Server`](crate::Server)'s outgoing messages
+pub trait Transport {
+    fn send(&mut self, msg: &Message);
+
+    /// Drop anything sent but not yet delivered, when [`Server::reset`](crate::Server::reset)
+    /// starts over. Nothing to do for transports that deliver straight away.
+    fn clear(&mut self) {}
+
+    /// How many messages were dropped for lack of room since the last call
+    fn dropped(&mut self) -> usize {
+        0
+    }
+}
+
+/// Buffers outgoing messages until the caller drains them
+#[derive(Debug, Default)]
+pub struct VecTransport {
+    outbox: Vec<Message>,
+    /// Most messages buffered at once, if bounded
+    capacity: Option<usize>,
+    dropped: usize,
+}
+
+impl VecTransport {
+    pub fn new() -> Self {
+        VecTransport::default()
+    }
+
+    /// Buffer at most `capacity` messages, for callers that may fall behind draining. Once
+    /// full, the oldest of the messages we can best do without is dropped: anti-entropy,
+    /// which is retried next round, then gossip with nothing but Alive and user news, then
+    /// probes and other gossip. Acks and nacks, which someone is waiting on to judge a peer,
+    /// go last.
+    pub fn bounded(capacity: usize) -> Self {
+        VecTransport {
+            capacity: Some(capacity),
+            ..VecTransport::default()
+        }
+    }
+
+    /// Take every message sent since the last drain
+    pub fn drain(&mut self) -> Vec<Message> {
+        take(&mut self.outbox)
+    }
+}
+
+impl Transport for VecTransport {
+    fn send(&mut self, msg: &Message) {
+        self.outbox.push(msg.clone());
+        let capacity = match self.capacity {
+            Some(capacity) if self.outbox.len() > capacity => capacity,
+            _ => return,
+        };
+        while self.outbox.len() > capacity {
+            let (idx, _) = self
+                .outbox
+                .iter()
+                .enumerate()
+                .min_by_key(|(idx, msg)| (retention(msg), *idx))
+                .expect("the outbox is over capacity");
+            let msg = self.outbox.remove(idx);
+            debug!("outbox full, dropping {:?} to {}", msg.kind, msg.dest_id);
+            self.dropped += 1;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.outbox.clear();
+    }
+
+    fn dropped(&mut self) -> usize {
+        take(&mut self.dropped)
+    }
+}
+
+/// How much we'd rather keep `msg` in a full outbox; the lowest goes first
+fn retention(msg: &Message) -> u8 {
+    match &msg.kind {
+        MsgKind::Push(_) | MsgKind::Pull(_) | MsgKind::PullDigest(_) => 0,
+        MsgKind::Gossip(gossip) if !carries_membership_news(gossip) => 1,
+        MsgKind::Ping | MsgKind::PingReq { .. } | MsgKind::Gossip(_) => 2,
+        MsgKind::Ack(..) | MsgKind::Nack { .. } => 3,
+    }
+}
+
+/// Whether gossip in the format [`Server::gossip`](crate::Server::gossip) writes has any
+/// suspicions, failures or departures in it. Anything we can't read is assumed to.
+fn carries_membership_news(gossip: &[u8]) -> bool {
+    if gossip.len() < 2 {
+        return false;
+    }
+    let count = u16::from_le_bytes([gossip[0], gossip[1]]);
+    let mut rest = &gossip[2..];
+    for _ in 0..count {
+        match Rumor::deserialize(rest) {
+            Ok((rumor, r)) if Priority::from(&rumor.kind) < Priority::Membership => rest = r,
+            _ => return true,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RumorKind, PROTOCOL_VERSION};
+
+    fn message(seq_no: u64, kind: MsgKind) -> Message {
+        let addr = "127.0.0.1:8000".parse().unwrap();
+        Message {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_label: 0,
+            dest_id: 1.into(),
+            dest_addr: addr,
+            src_id: 0.into(),
+            src_addr: addr,
+            seq_no,
+            kind,
+            mac: Vec::new(),
+        }
+    }
+
+    fn gossip(kind: RumorKind) -> MsgKind {
+        let mut buf = vec![1, 0];
+        buf.extend(
+            Rumor {
+                peer_id: 2.into(),
+                incarnation: 1.into(),
+                kind,
+            }
+            .serialize(),
+        );
+        MsgKind::Gossip(buf)
+    }
+
+    #[test]
+    fn full_outboxes_keep_what_matters() {
+        let mut transport = VecTransport::bounded(3);
+        let alive = gossip(RumorKind::alive("127.0.0.1:8002".parse().unwrap()));
+        transport.send(&message(0, MsgKind::Ack(1.into(), 1.into())));
+        transport.send(&message(1, gossip(RumorKind::Failed)));
+        transport.send(&message(2, alive.clone()));
+        assert_eq!(transport.dropped(), 0);
+        transport.send(&message(3, MsgKind::Push(Vec::new())));
+        transport.send(&message(4, MsgKind::Ping));
+        transport.send(&message(5, alive));
+        assert_eq!(transport.dropped(), 3);
+        assert_eq!(transport.dropped(), 0);
+        let kept: Vec<_> = transport.drain().iter().map(|m| m.seq_no).collect();
+        // Dropped the Push, then both gossips of Alive news, oldest first
+        assert_eq!(kept, vec![0, 1, 4]);
+    }
+}
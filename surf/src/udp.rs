@@ -0,0 +1,477 @@
+//! Runs a [`Server`] over UDP, so applications don't each have to write the socket glue.
+//! Messages that [prefer a reliable transport](crate::MsgKind::prefers_reliable) go over a
+//! short-lived TCP connection to the same address instead. With the `encryption` feature,
+//! everything is sealed by `Server::seal` under the server's keys. With the `compression`
+//! feature, large payloads are compressed by `Server::compress` first.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use crate::{Event, Message, Server};
+
+/// Datagrams are kept under a typical Ethernet MTU, filling what's left after the message
+/// with gossip. Messages that don't fit on their own go out anyway and may be fragmented.
+const MAX_DATAGRAM: usize = 1400;
+
+/// Largest datagram UDP can carry, which is as much as we'll try to read
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// Room left for sealing each datagram, whether or not the server has a key
+#[cfg(feature = "encryption")]
+const SEAL_OVERHEAD: usize = crate::ENCRYPTION_OVERHEAD;
+#[cfg(not(feature = "encryption"))]
+const SEAL_OVERHEAD: usize = 0;
+
+/// Room left for the compression flag in front of each datagram
+#[cfg(feature = "compression")]
+const COMPRESSION_OVERHEAD: usize = crate::COMPRESSION_OVERHEAD;
+#[cfg(not(feature = "compression"))]
+const COMPRESSION_OVERHEAD: usize = 0;
+
+/// Largest message we'll accept over TCP, so a bad length can't make us allocate wildly
+const MAX_STREAM_MESSAGE: usize = 16 << 20;
+
+/// Longest we wait on a datagram before checking for TCP connections again
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Owns a [`Server`] and the socket it talks through. Each message goes out as one datagram:
+/// its encoded length as a little-endian u16, the message from [`Message::to_bytes`], then
+/// rumors from [`Server::piggyback`] to fill the rest.
+///
+/// Push and Pull messages carry whole membership lists, which can outgrow a datagram. Those
+/// are sent over a fresh TCP connection as a little-endian u32 length then the message,
+/// without gossip, and received on a listener bound to the same address.
+pub struct UdpRunner {
+    server: Server,
+    socket: UdpSocket,
+    listener: TcpListener,
+    buf: Vec<u8>,
+}
+
+impl UdpRunner {
+    /// Bind a UDP socket and a TCP listener to the server's address
+    pub fn bind(server: Server) -> io::Result<Self> {
+        let socket = UdpSocket::bind(server.addr)?;
+        let listener = TcpListener::bind(server.addr)?;
+        UdpRunner::new(server, socket, listener)
+    }
+
+    /// Talk through an already bound socket and listener, which should both be listening on
+    /// the server's address
+    pub fn new(server: Server, socket: UdpSocket, listener: TcpListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(UdpRunner {
+            server,
+            socket,
+            listener,
+            buf: vec![0; MAX_UDP_PAYLOAD],
+        })
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Messages the server sends through this are flushed on the next [`UdpRunner::poll`]
+    pub fn server_mut(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    /// Tick the server if its next probe is due, time out whatever else is due, or else handle
+    /// any waiting TCP connections and the next datagram to arrive shortly. Whatever the
+    /// server sent in response goes out before returning what happened.
+    pub fn poll(&mut self) -> io::Result<Vec<Event>> {
+        // Read before the clock, as they're the current time when we've yet to probe
+        let next_tick = self.server.next_probe();
+        let deadline = self.server.next_deadline();
+        let now = self.server.clock.now();
+        let events = if now >= next_tick {
+            self.server.tick(now)
+        } else if now >= deadline {
+            self.server.expire(now)
+        } else {
+            let mut events = self.accept()?;
+            self.socket
+                .set_read_timeout(Some((deadline - now).min(ACCEPT_INTERVAL)))?;
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((len, from)) => events.extend(self.receive(len, from)),
+                Err(e) if is_timeout(&e) => {}
+                Err(e) => return Err(e),
+            }
+            events
+        };
+        self.flush();
+        Ok(events)
+    }
+
+    /// Poll forever, passing each event to `on_event`
+    pub fn run(&mut self, mut on_event: impl FnMut(Event)) -> io::Result<()> {
+        loop {
+            for event in self.poll()? {
+                on_event(event);
+            }
+        }
+    }
+
+    /// Handle the datagram of `len` bytes in our buffer
+    fn receive(&mut self, len: usize, from: SocketAddr) -> Vec<Event> {
+        let datagram =
+            match open(&self.server, &self.buf[..len]).and_then(|b| decompress(&self.server, &b)) {
+                Some(datagram) => datagram,
+                None => return Vec::new(),
+            };
+        let (msg, gossip) = match decode(&datagram) {
+            Some(decoded) => decoded,
+            None => {
+                warn!(
+                    "{:03} dropping malformed datagram from {}",
+                    self.server.id, from
+                );
+                return Vec::new();
+            }
+        };
+        match self.server.process_with_gossip(msg, gossip) {
+            Ok(events) => events,
+            Err(e) => {
+                warn!(
+                    "{:03} dropping message from {}: {}",
+                    self.server.id, from, e
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    /// Read a message from every TCP connection waiting to be accepted
+    fn accept(&mut self) -> io::Result<Vec<Event>> {
+        let mut events = Vec::new();
+        loop {
+            let (stream, from) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if is_timeout(&e) => return Ok(events),
+                Err(e) => return Err(e),
+            };
+            let bytes = match read_stream(stream, self.server.protocol_period) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!(
+                        "{:03} dropping malformed stream from {}: {}",
+                        self.server.id, from, e
+                    );
+                    continue;
+                }
+            };
+            let msg = match open(&self.server, &bytes)
+                .and_then(|b| decompress(&self.server, &b))
+                .map(|b| Message::from_bytes(&b))
+            {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    warn!(
+                        "{:03} dropping malformed message from {}: {}",
+                        self.server.id, from, e
+                    );
+                    continue;
+                }
+                None => continue,
+            };
+            match self.server.process(msg) {
+                Ok(processed) => events.extend(processed),
+                Err(e) => warn!(
+                    "{:03} dropping message from {}: {}",
+                    self.server.id, from, e
+                ),
+            }
+        }
+    }
+
+    /// Send everything in the server's outbox, with as much gossip as fits
+    fn flush(&mut self) {
+        for mut msg in self.server.outbox() {
+            let mut bytes = match msg.to_bytes() {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!(
+                        "{:03} couldn't encode {:?}: {}",
+                        self.server.id, msg.kind, e
+                    );
+                    continue;
+                }
+            };
+            if msg.kind.prefers_reliable() {
+                let bytes = compress(&self.server, &bytes);
+                let bytes = seal(&mut self.server, &bytes);
+                let sent = write_stream(msg.dest_addr, &bytes, self.server.protocol_period);
+                if let Err(e) = sent {
+                    warn!(
+                        "{:03} couldn't stream to {:03} at {}: {}",
+                        self.server.id, msg.dest_id, msg.dest_addr, e
+                    );
+                }
+                continue;
+            }
+            let len = match u16::try_from(bytes.len()) {
+                Ok(len) => len,
+                Err(_) => {
+                    warn!(
+                        "{:03} dropping {} byte message to {:03}",
+                        self.server.id,
+                        bytes.len(),
+                        msg.dest_id
+                    );
+                    continue;
+                }
+            };
+            let room =
+                MAX_DATAGRAM.saturating_sub(2 + bytes.len() + COMPRESSION_OVERHEAD + SEAL_OVERHEAD);
+            let mut gossip = vec![0; room];
+            if room > 0 {
+                let used = self.server.piggyback(&mut msg, &mut gossip);
+                gossip.truncate(used);
+                // Signed again to cover the gossip, but the HMAC is no longer than before
+                bytes = match msg.to_bytes() {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        warn!(
+                            "{:03} couldn't encode {:?}: {}",
+                            self.server.id, msg.kind, e
+                        );
+                        continue;
+                    }
+                };
+            }
+            let mut datagram = Vec::with_capacity(2 + bytes.len() + gossip.len());
+            datagram.extend_from_slice(&len.to_le_bytes());
+            datagram.extend_from_slice(&bytes);
+            datagram.extend_from_slice(&gossip);
+            let datagram = compress(&self.server, &datagram);
+            let datagram = seal(&mut self.server, &datagram);
+            if let Err(e) = self.socket.send_to(&datagram, msg.dest_addr) {
+                warn!(
+                    "{:03} couldn't send to {:03} at {}: {}",
+                    self.server.id, msg.dest_id, msg.dest_addr, e
+                );
+            }
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Send one length-prefixed message over a new connection, giving up after `timeout`
+fn write_stream(addr: SocketAddr, bytes: &[u8], timeout: Duration) -> io::Result<()> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(bytes)
+}
+
+/// Read the one length-prefixed message sent over `stream`, giving up after `timeout`
+fn read_stream(mut stream: TcpStream, timeout: Duration) -> io::Result<Vec<u8>> {
+    // Accepted streams inherit the listener's non-blocking mode on some platforms
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_STREAM_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message too large",
+        ));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(feature = "encryption")]
+fn seal(server: &mut Server, bytes: &[u8]) -> Vec<u8> {
+    server.seal(bytes)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn seal(_server: &mut Server, bytes: &[u8]) -> Vec<u8> {
+    bytes.to_vec()
+}
+
+/// Decrypt what arrived, or `None` if it was dropped
+#[cfg(feature = "encryption")]
+fn open(server: &Server, bytes: &[u8]) -> Option<Vec<u8>> {
+    server.open(bytes)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open(_server: &Server, bytes: &[u8]) -> Option<Vec<u8>> {
+    Some(bytes.to_vec())
+}
+
+#[cfg(feature = "compression")]
+fn compress(server: &Server, bytes: &[u8]) -> Vec<u8> {
+    server.compress(bytes)
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_server: &Server, bytes: &[u8]) -> Vec<u8> {
+    bytes.to_vec()
+}
+
+/// Decompress what was opened, or `None` if it was dropped
+#[cfg(feature = "compression")]
+fn decompress(server: &Server, bytes: &[u8]) -> Option<Vec<u8>> {
+    server.decompress(bytes)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_server: &Server, bytes: &[u8]) -> Option<Vec<u8>> {
+    Some(bytes.to_vec())
+}
+
+/// Split a datagram into its message and the gossip after it
+fn decode(datagram: &[u8]) -> Option<(Message, &[u8])> {
+    if datagram.len() < 2 {
+        return None;
+    }
+    let (len, rest) = datagram.split_at(2);
+    let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (msg, gossip) = rest.split_at(len);
+    Some((Message::from_bytes(msg).ok()?, gossip))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{MsgKind, Peer, PeerState, Rumor, RumorKind, PROTOCOL_VERSION};
+
+    fn runner(id: u64) -> UdpRunner {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+        let server = Server::builder(id.into(), addr)
+            .ping_interval(Duration::from_millis(10))
+            .protocol_period(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        UdpRunner::new(server, socket, listener).unwrap()
+    }
+
+    #[test]
+    fn peers_meet_over_udp() {
+        let mut a = runner(0);
+        let mut b = runner(1);
+        let seed = (a.server().id, a.server().addr);
+        b.server_mut().join_seeds(&[seed]);
+        for _ in 0..20 {
+            a.poll().unwrap();
+            b.poll().unwrap();
+        }
+        for (runner, other) in [(&a, 1), (&b, 0)] {
+            assert_eq!(
+                runner.server().peer(other.into()).map(|p| p.state()),
+                Some(PeerState::Alive)
+            );
+        }
+    }
+
+    #[test]
+    fn timeouts_are_handled_between_probes() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(addr).unwrap();
+        let mut server = Server::builder(0.into(), addr)
+            .ping_interval(Duration::from_millis(10))
+            .protocol_period(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        server.process_rumor(Rumor {
+            peer_id: 1.into(),
+            incarnation: 1.into(),
+            kind: RumorKind::alive(silent.local_addr().unwrap()),
+        });
+        let mut a = UdpRunner::new(server, socket, listener).unwrap();
+        // With nobody to ask for help, the probe nobody answers suspects them once the ping
+        // interval is up, long before the next probe
+        let start = Instant::now();
+        while a.server().peer(1.into()).unwrap().state() != PeerState::Suspect {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            a.poll().unwrap();
+        }
+    }
+
+    #[test]
+    fn garbage_is_dropped() {
+        let mut a = runner(0);
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(&[9, 9, 9], a.server().addr).unwrap();
+        a.poll().unwrap();
+        assert!(a.poll().unwrap().is_empty());
+        assert_eq!(a.server().num_members(), 1);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_peers_meet() {
+        let mut a = runner(0);
+        let mut b = runner(1);
+        let mut c = runner(2);
+        a.server_mut().set_encryption_keys(vec![[1; 32]]);
+        // Already accepting the next key, ahead of a rotation
+        b.server_mut().set_encryption_keys(vec![[1; 32], [2; 32]]);
+        let seed = (a.server().id, a.server().addr);
+        b.server_mut().join_seeds(&[seed]);
+        c.server_mut().join_seeds(&[seed]);
+        for _ in 0..20 {
+            a.poll().unwrap();
+            b.poll().unwrap();
+            c.poll().unwrap();
+        }
+        assert!(a.server().contains(1.into()));
+        assert!(b.server().contains(0.into()));
+        assert!(!a.server().contains(2.into()));
+    }
+
+    #[test]
+    fn large_pushes_go_over_tcp() {
+        let mut a = runner(0);
+        let b = runner(1);
+        let peers: Vec<_> = (2..3000)
+            .map(|id| {
+                let addr = SocketAddr::from(([127, 0, 0, 1], id as u16));
+                Peer::new(id.into(), addr, 1.into(), PeerState::Alive)
+            })
+            .collect();
+        let msg = Message {
+            protocol_version: PROTOCOL_VERSION,
+            cluster_label: 0,
+            dest_id: a.server().id,
+            dest_addr: a.server().addr,
+            src_id: b.server().id,
+            src_addr: b.server().addr,
+            seq_no: 0,
+            kind: MsgKind::Push(peers),
+            mac: Vec::new(),
+        };
+        let bytes = msg.to_bytes().unwrap();
+        assert!(bytes.len() > MAX_UDP_PAYLOAD);
+        let bytes = compress(b.server(), &bytes);
+        write_stream(msg.dest_addr, &bytes, Duration::from_secs(1)).unwrap();
+        for _ in 0..10 {
+            a.poll().unwrap();
+        }
+        assert_eq!(a.server().num_members(), 3000);
+    }
+}